@@ -44,6 +44,11 @@ fn build_sigmastudio_code() {
 }
 
 fn main() {
+    // The host build only compiles the library for the tests
+    if env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("espidf") {
+        return;
+    }
+
     embuild::espidf::sysenv::output();
 
     println!("cargo:rerun-if-changed=build.rs");
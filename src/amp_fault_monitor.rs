@@ -0,0 +1,176 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_state::AmpCommand, hardware_context::HardwareContext, lock::LockExt,
+    notifications::Notification, power::PowerRequest,
+};
+
+const POLL_INTERVAL_MS: u64 = 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FaultConfig {
+    /// FAULTZ has to stay asserted this long before it counts as a fault
+    pub debounce_ms: u32,
+    /// Time the outputs stay disabled before they are enabled again
+    pub cooldown_ms: u32,
+    /// Faults in a row after which the outputs stay off until the latch is cleared
+    pub max_retries: u8,
+    /// Fault-free operation for this long resets the retry counter
+    pub retry_reset_s: u32,
+}
+
+impl Default for FaultConfig {
+    fn default() -> Self {
+        FaultConfig {
+            debounce_ms: 60,
+            cooldown_ms: 2000,
+            max_retries: 3,
+            retry_reset_s: 60,
+        }
+    }
+}
+
+impl FaultConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.debounce_ms < POLL_INTERVAL_MS as u32 || self.debounce_ms > 1000 {
+            return Err(anyhow::Error::msg("Debounce must be between 20 ms and 1 s"));
+        }
+        if self.cooldown_ms < 100 {
+            return Err(anyhow::Error::msg("Cooldown must be at least 100 ms"));
+        }
+        if self.max_retries == 0 {
+            return Err(anyhow::Error::msg("At least one retry is required"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct FaultStatus {
+    /// Amplifiers currently reporting a fault, bit n = amplifier n
+    pub active: u8,
+    /// Debounced faults since boot
+    pub count: u32,
+    /// Faults since the last fault-free `retry_reset_s`
+    pub retries: u8,
+    /// The outputs were switched off for good after too many faults
+    pub latched: bool,
+    /// Milliseconds since boot of the last fault
+    pub last_ms: Option<u64>,
+}
+
+/// Watches FAULTZ of the TPA3116D2s, disables the outputs on a fault and
+/// re-enables them after a cooldown until `max_retries` is reached
+pub fn amp_fault_monitor(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    if !hardware_context
+        .self_test
+        .lock_value()
+        .amp_faults_supported()
+    {
+        log::warn!("Expander firmware has no amplifier fault register, faults are not monitored");
+        return Ok(());
+    }
+    log::info!("Amplifier fault monitor thread started");
+
+    let boot = Instant::now();
+    let mut asserted_since: Option<Instant> = None;
+    let mut last_fault: Option<Instant> = None;
+
+    loop {
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+        let config = *hardware_context
+            .fault_config
+            .lock_or_err("Could not lock fault config")?;
+        let active = hardware_context
+            .tpa3116d2
+            .lock_or_err("Could not lock TPA3116d2 driver")?
+            .fault_status()?;
+        status(&hardware_context, |status| status.active = active);
+
+        if active == 0 {
+            asserted_since = None;
+            if last_fault.is_some_and(|fault| {
+                fault.elapsed() >= Duration::from_secs(config.retry_reset_s as u64)
+            }) {
+                status(&hardware_context, |status| status.retries = 0);
+                last_fault = None;
+            }
+            continue;
+        }
+
+        let since = *asserted_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < Duration::from_millis(config.debounce_ms as u64) {
+            continue;
+        }
+        asserted_since = None;
+        last_fault = Some(Instant::now());
+
+        hardware_context
+            .amp
+            .execute(AmpCommand::Power(PowerRequest::Fault))?;
+
+        let now_ms = boot.elapsed().as_millis() as u64;
+        let (count, retries) = status(&hardware_context, |status| {
+            status.count += 1;
+            status.retries += 1;
+            status.last_ms = Some(now_ms);
+            status.latched = status.retries > config.max_retries;
+            (status.count, status.retries)
+        });
+        log::error!(
+            "Amplifier fault 0x{:02X}, fault {} ({} in a row)",
+            active,
+            count,
+            retries
+        );
+        hardware_context.notifier.publish(Notification::AmpFault {
+            amps: active,
+            count,
+        });
+
+        if retries > config.max_retries {
+            log::error!("Too many amplifier faults, outputs stay off");
+            hardware_context
+                .notifier
+                .publish(Notification::AmpLatchedOff { count });
+            // Wait here until the latch is cleared through the API
+            while is_latched(&hardware_context) {
+                std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS * 10));
+            }
+        } else {
+            std::thread::sleep(Duration::from_millis(config.cooldown_ms as u64));
+        }
+
+        log::info!("Re-enabling amplifier outputs");
+        if hardware_context
+            .amp
+            .execute(AmpCommand::Power(PowerRequest::ClearFault))?
+        {
+            hardware_context
+                .notifier
+                .publish(Notification::AmpRecovered);
+        }
+    }
+}
+
+pub fn is_latched(hardware_context: &HardwareContext) -> bool {
+    status(hardware_context, |status| status.latched)
+}
+
+/// Allows the outputs to be enabled again after a latch-off
+pub fn clear_latch(hardware_context: &HardwareContext) {
+    status(hardware_context, |status| {
+        status.latched = false;
+        status.retries = 0;
+    });
+}
+
+fn status<T>(hardware_context: &HardwareContext, f: impl FnOnce(&mut FaultStatus) -> T) -> T {
+    f(&mut hardware_context.fault_status.lock_value())
+}
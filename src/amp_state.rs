@@ -0,0 +1,335 @@
+use std::sync::{
+    mpsc::{channel, sync_channel, Receiver, Sender, SyncSender},
+    Arc, Mutex,
+};
+
+use serde::Serialize;
+
+use crate::{
+    board::Chip,
+    clock_plan::ClockPlan,
+    drivers::adau1962a::{Taper, Volume},
+    dsp_config::{self, DspConfig},
+    hardware_context::HardwareContext,
+    input_selector::InputSelection,
+    lock::LockExt,
+    notifications::Notification,
+    power::{self, PowerRequest, PowerState},
+    settings::{self, ChannelTrims},
+    volume_ramp,
+};
+
+/// What the user asked the amplifier to do. The hardware follows this state, it is never
+/// read back from the chips.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct AmpState {
+    pub power: PowerState,
+    /// Master volume, unrounded so small knob steps add up below the 0.375 dB attenuator steps
+    pub volume_db: f32,
+    /// `volume_db` on the 0-100 scale of the volume taper
+    pub level: u8,
+    pub input: InputSelection,
+}
+
+impl AmpState {
+    /// Muted on request, a standby or fault does not count
+    pub fn muted(&self) -> bool {
+        self.power == PowerState::Muted
+    }
+
+    pub fn volume(&self) -> Volume {
+        Volume::from_db(self.volume_db)
+    }
+}
+
+/// Changes fed in by the knob, buttons, remote, HTTP API and monitors
+#[derive(Clone, Copy, Debug)]
+pub enum AmpCommand {
+    SetLevel(u8),
+    /// Relative change in levels, from volume keys
+    StepLevel(i8),
+    /// Relative change in dB, from the knob
+    StepVolume {
+        db: f32,
+    },
+    Power(PowerRequest),
+    /// Standby and back, powers on when off
+    ToggleStandby,
+    SelectInput(InputSelection),
+    CycleInput,
+    /// Switches the sample rate of the whole chain
+    SetClockPlan(ClockPlan),
+    /// Loudest master volume from the thermal monitor, None lifts the limit
+    SetVolumeLimit(Option<Volume>),
+    /// Switches to the DSP configuration and channel trims of a preset
+    RecallPreset {
+        dsp: DspConfig,
+        trims: ChannelTrims,
+    },
+}
+
+struct Envelope {
+    command: AmpCommand,
+    /// Gets whether the command applied, None for fire and forget
+    reply: Option<SyncSender<anyhow::Result<bool>>>,
+}
+
+/// Queue in front of the executor and the state it last applied
+pub struct AmpBus {
+    sender: Mutex<Sender<Envelope>>,
+    /// Held by the executor while it runs
+    receiver: Mutex<Receiver<Envelope>>,
+    state: Mutex<AmpState>,
+}
+
+impl AmpBus {
+    /// Starts from the restored settings, the chain is still off
+    pub fn new(input: InputSelection, volume_db: f32) -> Self {
+        let (sender, receiver) = channel();
+        let volume = Volume::from_db(volume_db);
+        AmpBus {
+            sender: Mutex::new(sender),
+            receiver: Mutex::new(receiver),
+            state: Mutex::new(AmpState {
+                power: PowerState::Off,
+                volume_db,
+                level: Taper::default().to_level(volume),
+                input,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> AmpState {
+        *self.state.lock_value()
+    }
+
+    /// Queues a command without waiting for it, for inputs that must not block (knob, buttons)
+    pub fn send(&self, command: AmpCommand) {
+        self.enqueue(Envelope {
+            command,
+            reply: None,
+        });
+    }
+
+    /// Queues a command and waits until it was applied. Returns false if it did not apply
+    /// in the current state, like a wake request while playing.
+    pub fn execute(&self, command: AmpCommand) -> anyhow::Result<bool> {
+        let (reply, result) = sync_channel(1);
+        self.enqueue(Envelope {
+            command,
+            reply: Some(reply),
+        });
+        result
+            .recv()
+            .map_err(|_| anyhow::Error::msg("Amplifier executor is not running"))?
+    }
+
+    fn enqueue(&self, envelope: Envelope) {
+        if self.sender.lock_value().send(envelope).is_err() {
+            log::error!("Amplifier executor is not running, command dropped");
+        }
+    }
+}
+
+/// Applies queued commands to the hardware one after the other and publishes the new state.
+/// The only place that changes volume, mute, power and input.
+pub fn amp_executor(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    // A crashed executor leaves the lock poisoned, the queue behind it is still intact
+    let receiver = hardware_context.amp.receiver.lock_value();
+    log::info!("Amplifier executor thread started");
+
+    let mut pending: Option<Envelope> = None;
+    loop {
+        let Envelope { mut command, reply } = match pending.take() {
+            Some(envelope) => envelope,
+            None => receiver.recv()?,
+        };
+
+        // Knob steps that queued up during a ramp are merged into one
+        if let AmpCommand::StepVolume { db } = command {
+            let mut total_db = db;
+            while let Ok(next) = receiver.try_recv() {
+                match next.command {
+                    AmpCommand::StepVolume { db } if next.reply.is_none() => total_db += db,
+                    _ => {
+                        pending = Some(next);
+                        break;
+                    }
+                }
+            }
+            command = AmpCommand::StepVolume { db: total_db };
+        }
+
+        let previous = hardware_context.amp.state();
+        let mut state = previous;
+        let result = apply(&hardware_context, &mut state, command);
+        state.power = hardware_context.power.state();
+        *hardware_context
+            .amp
+            .state
+            .lock_or_err("Could not lock amp state")? = state;
+
+        if let Err(e) = &result {
+            log::error!("{:?} failed: {:?}", command, e);
+        }
+        if state != previous {
+            hardware_context
+                .notifier
+                .publish(Notification::AmpState { state });
+        }
+        if let Some(reply) = reply {
+            // The caller may have given up waiting
+            let _ = reply.send(result);
+        }
+    }
+}
+
+fn apply(
+    hardware_context: &HardwareContext,
+    state: &mut AmpState,
+    command: AmpCommand,
+) -> anyhow::Result<bool> {
+    match command {
+        AmpCommand::SetLevel(level) => {
+            let volume = taper(hardware_context).to_volume(level);
+            set_volume(hardware_context, state, volume.db())?;
+        }
+        AmpCommand::StepLevel(steps) => {
+            let level = (state.level as i16 + steps as i16).clamp(0, 100) as u8;
+            let volume = taper(hardware_context).to_volume(level);
+            set_volume(hardware_context, state, volume.db())?;
+        }
+        AmpCommand::StepVolume { db } => {
+            set_volume(hardware_context, state, state.volume_db + db)?;
+        }
+        AmpCommand::Power(request) => {
+            return Ok(power::request(hardware_context, request)?.is_some());
+        }
+        AmpCommand::ToggleStandby => {
+            let request = match hardware_context.power.state() {
+                PowerState::Off => PowerRequest::PowerOn,
+                PowerState::Standby => PowerRequest::Wake,
+                _ => PowerRequest::Standby,
+            };
+            return Ok(power::request(hardware_context, request)?.is_some());
+        }
+        AmpCommand::SelectInput(selection) => select_input(hardware_context, state, selection)?,
+        AmpCommand::CycleInput => {
+            let selection = InputSelection {
+                source: state.input.source.next(),
+                auto: false,
+            };
+            select_input(hardware_context, state, selection)?;
+        }
+        AmpCommand::SetClockPlan(plan) => set_clock_plan(hardware_context, plan)?,
+        AmpCommand::SetVolumeLimit(limit) => {
+            hardware_context.volume_ramp.set_limit(limit);
+            // Ramps down to the limit, or back up to the volume once it is lifted. Behind a
+            // muted amp the volume is set again and the fade-in of the unmute limits it.
+            match hardware_context.power.state() {
+                PowerState::Playing => {
+                    volume_ramp::ramp_to_volume(hardware_context, state.volume())?
+                }
+                PowerState::Muted if hardware_context.has(Chip::Adau1962a) => {
+                    hardware_context
+                        .adau1962a
+                        .lock_or_err("Could not lock ADAU1962a driver")?
+                        .step_master_attenuation(state.volume())?;
+                }
+                _ => {}
+            }
+        }
+        AmpCommand::RecallPreset { dsp, trims } => recall_preset(hardware_context, dsp, trims)?,
+    }
+    Ok(true)
+}
+
+fn taper(hardware_context: &HardwareContext) -> Taper {
+    hardware_context.adau1962a.lock_value().taper()
+}
+
+/// Stores the volume and ramps to it. While the chain is off it is applied by the next power-up.
+fn set_volume(
+    hardware_context: &HardwareContext,
+    state: &mut AmpState,
+    db: f32,
+) -> anyhow::Result<()> {
+    state.volume_db = db.clamp(Volume::MIN_DB, 0.0);
+    state.level = taper(hardware_context).to_level(state.volume());
+    log::info!("Volume {} dB (level {})", state.volume_db, state.level);
+
+    if matches!(
+        hardware_context.power.state(),
+        PowerState::Off | PowerState::Booting
+    ) {
+        return Ok(());
+    }
+    volume_ramp::ramp_to_volume(hardware_context, state.volume())?;
+    dsp_config::follow_volume(hardware_context, state.volume_db)
+}
+
+/// Fades out while playing, switches the clocks and fades back in, also after a failed
+/// switch. While the chain is off the plan is only stored, the next power-up applies it.
+fn set_clock_plan(hardware_context: &HardwareContext, plan: ClockPlan) -> anyhow::Result<()> {
+    match hardware_context.power.state() {
+        PowerState::Off | PowerState::Booting => {
+            *hardware_context
+                .clock_plan
+                .lock_or_err("Could not lock clock plan")? = plan;
+            Ok(())
+        }
+        PowerState::Playing => {
+            let volume = volume_ramp::fade_out(hardware_context)?;
+            let result = plan.apply(hardware_context);
+            volume_ramp::fade_in(hardware_context, volume)?;
+            result
+        }
+        _ => plan.apply(hardware_context),
+    }
+}
+
+/// Fades out while playing so the filters and trims change in silence, then fades back
+/// in, also after a failed write. While the chain is off they are only stored, the next
+/// power-up writes them.
+fn recall_preset(
+    hardware_context: &HardwareContext,
+    dsp: DspConfig,
+    trims: ChannelTrims,
+) -> anyhow::Result<()> {
+    let apply = || {
+        dsp_config::apply(hardware_context, dsp)
+            .and_then(|_| settings::apply_trims(hardware_context, trims))
+    };
+    if hardware_context.power.state() != PowerState::Playing {
+        return apply();
+    }
+    let volume = volume_ramp::fade_out(hardware_context)?;
+    let result = apply();
+    volume_ramp::fade_in(hardware_context, volume)?;
+    result
+}
+
+fn select_input(
+    hardware_context: &HardwareContext,
+    state: &mut AmpState,
+    selection: InputSelection,
+) -> anyhow::Result<()> {
+    let pcm1865 = hardware_context
+        .pcm1865
+        .lock_or_err("Could not lock PCM1865 driver")?;
+    hardware_context
+        .input_selector
+        .select(&pcm1865, selection)?;
+    drop(pcm1865);
+
+    let changed = selection.source != state.input.source;
+    state.input = selection;
+    if changed {
+        hardware_context
+            .notifier
+            .publish(Notification::InputSelected {
+                source: selection.source,
+            });
+    }
+    Ok(())
+}
@@ -2,7 +2,35 @@ use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
-use crate::hardware_context::{HardwareContext};
+use crate::{
+    amp_fault_monitor::{self, FaultConfig, FaultStatus},
+    amp_state::{AmpCommand, AmpState},
+    asrc_monitor::InputStatus,
+    board::BoardProfile,
+    buttons::{ButtonAction, ButtonConfig},
+    clip_monitor::{ClipConfig, ClipStats},
+    clock_plan::ClockPlan,
+    drivers::{
+        adau1962a::{Taper, TdmConfig, Volume, ADAU1962A, DAC_CHANNEL_COUNT},
+        pcm1865::AdcStatus,
+    },
+    dsp_config::{self, DspConfig},
+    hardware_context::HardwareContext,
+    hardware_init::reload_dsp_program,
+    input_selector::{InputSelection, InputSource},
+    ir_decoder::IrCode,
+    ir_remote::IrBinding,
+    led_manager::LedConfig,
+    lock::LockExt,
+    power::{PowerRequest, PowerState},
+    presets::{self, Preset},
+    self_test::{self, SelfTestReport},
+    settings::{self, Settings},
+    standby::StandbyConfig,
+    supervisor::{self, TaskStatus},
+    thermal_monitor::{ThermalConfig, ThermalStatus},
+    volume_ramp::RampConfig,
+};
 
 /// Kommando-Typen, alle über /api empfangenen Requests
 #[derive(Deserialize)]
@@ -11,6 +39,78 @@ pub enum Command {
     SetVolume { level: u8 },
     Mute,
     Unmute,
+    Status,
+    GetStandbyConfig,
+    SetStandbyConfig { config: StandbyConfig },
+    SelectInput { source: InputSource },
+    SetAutoInput { enabled: bool },
+    GetClipping,
+    SetClipConfig { config: ClipConfig },
+    ResetClipStats,
+    GetChannels,
+    SetChannelVolume { channel: u8, db: f32 },
+    SetChannelMute { channel: u8, muted: bool },
+    SetVolumeTaper { taper: Taper },
+    GetClockPlan,
+    SetSampleRate { sample_rate: u32 },
+    GetTdmConfig,
+    SetTdmConfig { config: TdmConfig },
+    GetRampConfig,
+    SetRampConfig { config: RampConfig },
+    ReloadDsp,
+    GetThermal,
+    SetThermalConfig { config: ThermalConfig },
+    GetAmpFaults,
+    SetFaultConfig { config: FaultConfig },
+    ClearFaultLatch,
+    Power { request: PowerRequest },
+    GetState,
+    GetTasks,
+    GetButtonConfig,
+    SetButtonConfig { config: ButtonConfig },
+    GetLedConfig,
+    SetLedConfig { config: LedConfig },
+    /// The next code received from a remote gets bound to `action`
+    LearnIrCode { action: ButtonAction },
+    CancelIrLearn,
+    GetIrBindings,
+    DeleteIrBinding { code: IrCode },
+    ClearIrBindings,
+    GetDspConfig,
+    SetDspConfig { config: DspConfig },
+    GetSettings,
+    /// Erases the stored settings, IR bindings and presets and applies the defaults
+    FactoryReset,
+    GetPresets,
+    /// Stores the current DSP configuration and trims under `name`
+    SavePreset { name: String },
+    RecallPreset { name: String },
+    RenamePreset { name: String, new_name: String },
+    DeletePreset { name: String },
+    /// Profile the firmware booted with
+    GetBoardProfile,
+    /// Removes an uploaded profile, the built-in one applies from the next boot
+    ClearBoardProfile,
+    /// Result of the boot-time self-test and the last DSP program check
+    GetSelfTest,
+}
+
+/// Actual state of one DAC channel as read back from the ADAU1962A
+#[derive(Serialize)]
+pub struct ChannelState {
+    pub channel: u8,
+    pub db: f32,
+    pub muted: bool,
+}
+
+impl ChannelState {
+    fn read(adau1962a: &ADAU1962A, channel: u8) -> anyhow::Result<Self> {
+        Ok(ChannelState {
+            channel,
+            db: adau1962a.channel_volume(channel)?.db(),
+            muted: adau1962a.channel_muted(channel)?,
+        })
+    }
 }
 
 /// Antwort-Typen, die wir serialisieren
@@ -19,7 +119,72 @@ pub enum Command {
 #[serde(tag = "resp", rename_all = "snake_case")]
 pub enum Response {
     Ok,
-    Status { level: u8, muted: bool },
+    Status {
+        level: u8,
+        muted: bool,
+        input: InputStatus,
+        adc: Option<AdcStatus>,
+        standby: bool,
+        input_selection: InputSelection,
+        power: PowerState,
+    },
+    StandbyConfig { config: StandbyConfig },
+    Clipping { config: ClipConfig, stats: ClipStats },
+    Channels {
+        master_db: f32,
+        taper: Taper,
+        channels: Vec<ChannelState>,
+    },
+    Channel { state: ChannelState },
+    ClockPlan { plan: ClockPlan },
+    TdmConfig {
+        config: TdmConfig,
+        /// DAC channel and the (data line, slot) it reads, for each assigned channel
+        channel_map: Vec<(u8, (u8, u8))>,
+    },
+    RampConfig { config: RampConfig },
+    Thermal {
+        config: ThermalConfig,
+        status: ThermalStatus,
+    },
+    AmpFaults {
+        config: FaultConfig,
+        status: FaultStatus,
+    },
+    Power {
+        state: PowerState,
+    },
+    State {
+        state: AmpState,
+    },
+    Tasks {
+        tasks: Vec<TaskStatus>,
+    },
+    ButtonConfig {
+        config: ButtonConfig,
+    },
+    LedConfig {
+        config: LedConfig,
+    },
+    IrBindings {
+        bindings: Vec<IrBinding>,
+        learning: Option<ButtonAction>,
+    },
+    DspConfig {
+        config: DspConfig,
+    },
+    Settings {
+        settings: Settings,
+    },
+    Presets {
+        presets: Vec<Preset>,
+    },
+    BoardProfile {
+        profile: BoardProfile,
+    },
+    SelfTest {
+        report: SelfTestReport,
+    },
     Err { message: String },
 }
 
@@ -29,36 +194,411 @@ impl Command {
         match self {
             Command::SetVolume { level } if level <= 100 => {
                 log::info!("SetVolume called");
-                hardware_context
-                    .adau1962a
-                    .lock()
-                    .expect("Could not lock ADAU1962a driver")
-                    .set_master_volume(level)?;
+                hardware_context.amp.execute(AmpCommand::SetLevel(level))?;
                 Ok(Response::Ok)
             }
             Command::SetVolume { level } => Ok(Response::Err {
                 message: format!("Level {} out of range (0-100)", level),
             }),
             Command::Mute => {
-                // mute();
                 log::info!("Mute called");
                 hardware_context
-                    .tpa3116d2
-                    .lock()
-                    .expect("Could not lock TPA3116d2 driver")
-                    .mute_speaker_outputs(true)?;
+                    .amp
+                    .execute(AmpCommand::Power(PowerRequest::Mute))?;
                 Ok(Response::Ok)
             }
             Command::Unmute => {
-                // unmute();
                 log::info!("Unmute called");
                 hardware_context
-                    .tpa3116d2
-                    .lock()
-                    .expect("Could not lock TPA3116d2 driver")
-                    .mute_speaker_outputs(false)?;
+                    .amp
+                    .execute(AmpCommand::Power(PowerRequest::Unmute))?;
+                Ok(Response::Ok)
+            }
+            Command::Status => {
+                let state = hardware_context.amp.state();
+                let input = *hardware_context
+                    .input_status
+                    .lock_or_err("Could not lock input status")?;
+                let adc = hardware_context
+                    .pcm1865
+                    .lock_or_err("Could not lock PCM1865 driver")?
+                    .status()
+                    .map_err(|e| log::warn!("Could not read PCM1865 status: {:?}", e))
+                    .ok();
+                Ok(Response::Status {
+                    level: state.level,
+                    muted: state.muted(),
+                    input,
+                    adc,
+                    standby: state.power == PowerState::Standby,
+                    input_selection: state.input,
+                    power: state.power,
+                })
+            }
+            Command::GetStandbyConfig => {
+                let config = *hardware_context
+                    .standby_config
+                    .lock_or_err("Could not lock standby config")?;
+                Ok(Response::StandbyConfig { config })
+            }
+            Command::SetStandbyConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                *hardware_context
+                    .standby_config
+                    .lock_or_err("Could not lock standby config")? = config;
+                Ok(Response::Ok)
+            }
+            Command::SelectInput { source } => {
+                // A manual selection ends auto mode
+                hardware_context
+                    .amp
+                    .execute(AmpCommand::SelectInput(InputSelection {
+                        source,
+                        auto: false,
+                    }))?;
+                Ok(Response::Ok)
+            }
+            Command::SetAutoInput { enabled } => {
+                let mut selection = hardware_context.amp.state().input;
+                selection.auto = enabled;
+                hardware_context
+                    .amp
+                    .execute(AmpCommand::SelectInput(selection))?;
+                Ok(Response::Ok)
+            }
+            Command::GetClipping => {
+                let config = *hardware_context
+                    .clip_config
+                    .lock_or_err("Could not lock clip config")?;
+                let stats = *hardware_context
+                    .clip_stats
+                    .lock_or_err("Could not lock clip stats")?;
+                Ok(Response::Clipping { config, stats })
+            }
+            Command::SetClipConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                *hardware_context
+                    .clip_config
+                    .lock_or_err("Could not lock clip config")? = config;
+                Ok(Response::Ok)
+            }
+            Command::ResetClipStats => {
+                let mut stats = hardware_context
+                    .clip_stats
+                    .lock_or_err("Could not lock clip stats")?;
+                *stats = ClipStats {
+                    gain_db: stats.gain_db,
+                    ..ClipStats::default()
+                };
+                Ok(Response::Ok)
+            }
+            Command::GetChannels => {
+                let adau1962a = hardware_context
+                    .adau1962a
+                    .lock_or_err("Could not lock ADAU1962a driver")?;
+                let channels = (1..=DAC_CHANNEL_COUNT)
+                    .map(|channel| ChannelState::read(&adau1962a, channel))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Ok(Response::Channels {
+                    master_db: adau1962a.master_attenuation()?.db(),
+                    taper: adau1962a.taper(),
+                    channels,
+                })
+            }
+            Command::SetChannelVolume { channel, .. } | Command::SetChannelMute { channel, .. }
+                if !(1..=DAC_CHANNEL_COUNT).contains(&channel) =>
+            {
+                Ok(Response::Err {
+                    message: format!("Channel {} out of range (1-12)", channel),
+                })
+            }
+            Command::SetChannelVolume { channel, db } => {
+                let mut trims = *hardware_context
+                    .channel_trims
+                    .lock_or_err("Could not lock channel trims")?;
+                trims[channel as usize - 1] = Volume::from_db(db).db();
+                settings::apply_trims(hardware_context, trims)?;
+
+                let adau1962a = hardware_context
+                    .adau1962a
+                    .lock_or_err("Could not lock ADAU1962a driver")?;
+                Ok(Response::Channel {
+                    state: ChannelState::read(&adau1962a, channel)?,
+                })
+            }
+            Command::SetChannelMute { channel, muted } => {
+                let mut adau1962a = hardware_context
+                    .adau1962a
+                    .lock_or_err("Could not lock ADAU1962a driver")?;
+                adau1962a.set_channel_mute(channel, muted)?;
+                Ok(Response::Channel {
+                    state: ChannelState::read(&adau1962a, channel)?,
+                })
+            }
+            Command::SetVolumeTaper { taper } => {
+                if let Err(e) = taper.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                hardware_context
+                    .adau1962a
+                    .lock_or_err("Could not lock ADAU1962a driver")?
+                    .set_taper(taper);
+                Ok(Response::Ok)
+            }
+            Command::GetClockPlan => {
+                let plan = *hardware_context
+                    .clock_plan
+                    .lock_or_err("Could not lock clock plan")?;
+                Ok(Response::ClockPlan { plan })
+            }
+            Command::SetSampleRate { sample_rate } => match ClockPlan::new(sample_rate) {
+                Ok(plan) => {
+                    hardware_context
+                        .amp
+                        .execute(AmpCommand::SetClockPlan(plan))?;
+                    Ok(Response::ClockPlan { plan })
+                }
+                Err(e) => Ok(Response::Err {
+                    message: e.to_string(),
+                }),
+            },
+            Command::GetTdmConfig => {
+                let config = *hardware_context
+                    .tdm_config
+                    .lock_or_err("Could not lock TDM config")?;
+                Ok(Response::TdmConfig {
+                    config,
+                    channel_map: config
+                        .assigned_channels()
+                        .map(|channel| (channel, config.slot_for_channel(channel)))
+                        .collect(),
+                })
+            }
+            Command::SetTdmConfig { config } => {
+                match settings::apply_tdm_config(hardware_context, config) {
+                    Ok(()) => Ok(Response::Ok),
+                    Err(e) => Ok(Response::Err {
+                        message: e.to_string(),
+                    }),
+                }
+            }
+            Command::GetRampConfig => Ok(Response::RampConfig {
+                config: hardware_context.volume_ramp.config(),
+            }),
+            Command::SetRampConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                hardware_context.volume_ramp.set_config(config);
+                Ok(Response::Ok)
+            }
+            Command::ReloadDsp => {
+                reload_dsp_program(hardware_context)?;
+                Ok(Response::Ok)
+            }
+            Command::GetThermal => {
+                let config = *hardware_context
+                    .thermal_config
+                    .lock_or_err("Could not lock thermal config")?;
+                let status = *hardware_context
+                    .thermal_status
+                    .lock_or_err("Could not lock thermal status")?;
+                Ok(Response::Thermal { config, status })
+            }
+            Command::SetThermalConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                *hardware_context
+                    .thermal_config
+                    .lock_or_err("Could not lock thermal config")? = config;
+                Ok(Response::Ok)
+            }
+            Command::GetAmpFaults => {
+                let config = *hardware_context
+                    .fault_config
+                    .lock_or_err("Could not lock fault config")?;
+                let status = *hardware_context
+                    .fault_status
+                    .lock_or_err("Could not lock fault status")?;
+                Ok(Response::AmpFaults { config, status })
+            }
+            Command::SetFaultConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                *hardware_context
+                    .fault_config
+                    .lock_or_err("Could not lock fault config")? = config;
+                Ok(Response::Ok)
+            }
+            Command::ClearFaultLatch => {
+                // The fault monitor re-enables the outputs once it sees the cleared latch
+                amp_fault_monitor::clear_latch(hardware_context);
+                Ok(Response::Ok)
+            }
+            Command::Power { request } => {
+                let applied = hardware_context.amp.execute(AmpCommand::Power(request))?;
+                let state = hardware_context.amp.state().power;
+                if applied {
+                    Ok(Response::Power { state })
+                } else {
+                    Ok(Response::Err {
+                        message: format!("{:?} not possible in power state {:?}", request, state),
+                    })
+                }
+            }
+            Command::GetState => Ok(Response::State {
+                state: hardware_context.amp.state(),
+            }),
+            Command::GetTasks => Ok(Response::Tasks {
+                tasks: supervisor::tasks(hardware_context),
+            }),
+            Command::GetButtonConfig => {
+                let config = *hardware_context
+                    .button_config
+                    .lock_or_err("Could not lock button config")?;
+                Ok(Response::ButtonConfig { config })
+            }
+            Command::SetButtonConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                *hardware_context
+                    .button_config
+                    .lock_or_err("Could not lock button config")? = config;
+                Ok(Response::Ok)
+            }
+            Command::GetLedConfig => {
+                let config = *hardware_context
+                    .led_config
+                    .lock_or_err("Could not lock LED config")?;
+                Ok(Response::LedConfig { config })
+            }
+            Command::SetLedConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                *hardware_context
+                    .led_config
+                    .lock_or_err("Could not lock LED config")? = config;
+                Ok(Response::Ok)
+            }
+            Command::LearnIrCode { action } => {
+                hardware_context.ir_remote.learn(Some(action));
+                Ok(Response::Ok)
+            }
+            Command::CancelIrLearn => {
+                hardware_context.ir_remote.learn(None);
+                Ok(Response::Ok)
+            }
+            Command::GetIrBindings => Ok(Response::IrBindings {
+                bindings: hardware_context.ir_remote.bindings(),
+                learning: hardware_context.ir_remote.learning(),
+            }),
+            Command::DeleteIrBinding { code } => {
+                if hardware_context.ir_remote.unbind(code)? {
+                    Ok(Response::Ok)
+                } else {
+                    Ok(Response::Err {
+                        message: format!("IR code {:?} is not bound", code),
+                    })
+                }
+            }
+            Command::ClearIrBindings => {
+                hardware_context.ir_remote.clear()?;
+                Ok(Response::Ok)
+            }
+            Command::GetDspConfig => {
+                let config = *hardware_context
+                    .dsp_config
+                    .lock_or_err("Could not lock DSP config")?;
+                Ok(Response::DspConfig { config })
+            }
+            Command::SetDspConfig { config } => {
+                if let Err(e) = config.validate() {
+                    return Ok(Response::Err {
+                        message: e.to_string(),
+                    });
+                }
+                dsp_config::apply(hardware_context, config)?;
+                Ok(Response::Ok)
+            }
+            Command::GetSettings => Ok(Response::Settings {
+                settings: hardware_context.settings.stored(),
+            }),
+            Command::FactoryReset => {
+                settings::factory_reset(hardware_context)?;
+                Ok(Response::Ok)
+            }
+            Command::GetPresets => Ok(Response::Presets {
+                presets: hardware_context.presets.presets()?,
+            }),
+            Command::SavePreset { name } => {
+                let preset = Preset::capture(hardware_context, name)?;
+                match hardware_context.presets.save(preset) {
+                    Ok(()) => Ok(Response::Ok),
+                    Err(e) => Ok(Response::Err {
+                        message: e.to_string(),
+                    }),
+                }
+            }
+            Command::RecallPreset { name } => match hardware_context.presets.get(&name)? {
+                Some(preset) => {
+                    presets::recall(hardware_context, &preset)?;
+                    Ok(Response::Ok)
+                }
+                None => Ok(Response::Err {
+                    message: format!("Preset {} does not exist", name),
+                }),
+            },
+            Command::RenamePreset { name, new_name } => {
+                match hardware_context.presets.rename(&name, new_name) {
+                    Ok(()) => Ok(Response::Ok),
+                    Err(e) => Ok(Response::Err {
+                        message: e.to_string(),
+                    }),
+                }
+            }
+            Command::DeletePreset { name } => match hardware_context.presets.delete(&name) {
+                Ok(()) => Ok(Response::Ok),
+                Err(e) => Ok(Response::Err {
+                    message: e.to_string(),
+                }),
+            },
+            Command::GetBoardProfile => Ok(Response::BoardProfile {
+                profile: hardware_context.board.clone(),
+            }),
+            Command::ClearBoardProfile => {
+                hardware_context
+                    .board_store
+                    .lock_or_err("Could not lock board store")?
+                    .clear()?;
                 Ok(Response::Ok)
             }
+            Command::GetSelfTest => Ok(Response::SelfTest {
+                report: self_test::report(hardware_context),
+            }),
         }
     }
 }
@@ -0,0 +1,146 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::Serialize;
+
+use crate::{
+    amp_state::AmpCommand,
+    drivers::adau1467_registers::ASRC_COUNT,
+    hardware_context::HardwareContext,
+    lock::LockExt,
+    notifications::Notification,
+    power::{PowerRequest, PowerState},
+};
+
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// Sample rates the detected rate is snapped to
+const STANDARD_RATES: [u32; 9] = [
+    8_000, 16_000, 32_000, 44_100, 48_000, 88_200, 96_000, 176_400, 192_000,
+];
+/// Maximum deviation from a standard rate that is still accepted, in percent
+const RATE_TOLERANCE_PERCENT: u32 = 2;
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InputStatus {
+    pub locked: bool,
+    /// Detected input sample rate in Hz, None if unlocked or not a standard rate
+    pub sample_rate: Option<u32>,
+}
+
+/// Converts an ASRCx_RATIO value (input rate / output rate in 4.12 format) into the input rate
+pub fn ratio_to_sample_rate(ratio: u16, output_rate: u32) -> u32 {
+    ((ratio as u64 * output_rate as u64 + (1 << 11)) >> 12) as u32
+}
+
+/// Snaps a measured rate to the closest standard sample rate
+pub fn snap_to_standard_rate(measured: u32) -> Option<u32> {
+    STANDARD_RATES
+        .iter()
+        .copied()
+        .min_by_key(|rate| rate.abs_diff(measured))
+        .filter(|rate| rate.abs_diff(measured) * 100 <= rate * RATE_TOLERANCE_PERCENT)
+}
+
+pub fn asrc_monitor(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    log::info!("ASRC monitor thread started");
+
+    let active_asrcs = hardware_context
+        .adau1467
+        .lock_or_err("Could not lock ADAU1467 driver")?
+        .active_asrcs()?;
+
+    // Start with everything considered locked so a missing input at boot is reported
+    let mut last_lock = active_asrcs;
+    let mut selected = None;
+    let mut primary_asrc = None;
+
+    loop {
+        // The ASRC the selected input is routed through, looked up again when it changes
+        let source = hardware_context.input_selector.selection().source;
+        if selected != Some(source) {
+            let (asrc_source, channel) = source.dsp_input();
+            primary_asrc = hardware_context
+                .adau1467
+                .lock_or_err("Could not lock ADAU1467 driver")?
+                .find_asrc(asrc_source, channel)?;
+            match primary_asrc {
+                Some(asrc) => log::info!("Input {:?} runs through ASRC{}", source, asrc),
+                None => log::warn!("Input {:?} is not routed through an ASRC", source),
+            }
+            selected = Some(source);
+        }
+
+        let (lock, statuses) = read_asrc_status(&hardware_context, active_asrcs)?;
+
+        for asrc in 0..ASRC_COUNT {
+            let bit = 1 << asrc;
+            if active_asrcs & bit == 0 || (lock & bit) == (last_lock & bit) {
+                continue;
+            }
+            if lock & bit != 0 {
+                hardware_context
+                    .notifier
+                    .publish(Notification::InputLocked {
+                        asrc,
+                        sample_rate: statuses[asrc as usize].sample_rate,
+                    });
+            } else {
+                hardware_context
+                    .notifier
+                    .publish(Notification::InputLockLost { asrc });
+            }
+        }
+
+        // Losing the selected input mutes the outputs until it locks again. Without an ASRC
+        // there is no lock to watch and the outputs are left alone.
+        let primary = primary_asrc.map_or(InputStatus::default(), |asrc| statuses[asrc as usize]);
+        *hardware_context
+            .input_status
+            .lock_or_err("Could not lock input status")? = primary;
+        let watched = primary_asrc.is_some();
+
+        // The power state machine refuses the request while muted, off or in standby
+        let state = hardware_context.power.state();
+        if watched && !primary.locked && state == PowerState::Playing {
+            log::warn!("Input lost lock, muting outputs");
+            hardware_context
+                .amp
+                .execute(AmpCommand::Power(PowerRequest::SignalLost))?;
+        } else if (primary.locked || !watched) && state == PowerState::NoSignal {
+            log::info!("Input locked again, unmuting outputs");
+            hardware_context
+                .amp
+                .execute(AmpCommand::Power(PowerRequest::SignalRestored))?;
+        }
+
+        last_lock = lock;
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    }
+}
+
+fn read_asrc_status(
+    hardware_context: &HardwareContext,
+    active_asrcs: u8,
+) -> anyhow::Result<(u8, [InputStatus; ASRC_COUNT as usize])> {
+    let adau1467 = hardware_context
+        .adau1467
+        .lock_or_err("Could not lock ADAU1467 driver")?;
+
+    // The ASRC outputs run at the DSP core rate
+    let output_rate = adau1467.sample_rate();
+    let lock = adau1467.asrc_lock_status()? & active_asrcs;
+    let mut statuses = [InputStatus::default(); ASRC_COUNT as usize];
+
+    for asrc in 0..ASRC_COUNT {
+        if lock & (1 << asrc) == 0 {
+            continue;
+        }
+        let ratio = adau1467.asrc_ratio(asrc)?;
+        statuses[asrc as usize] = InputStatus {
+            locked: true,
+            sample_rate: snap_to_standard_rate(ratio_to_sample_rate(ratio, output_rate)),
+        };
+    }
+
+    Ok((lock, statuses))
+}
@@ -0,0 +1,236 @@
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use serde::{Deserialize, Serialize};
+
+/// Profile of the board revision the firmware was built for
+const BUILTIN_PROFILE: &str = include_str!("../boards/default.json");
+const NVS_NAMESPACE: &str = "board";
+const NVS_KEY_PROFILE: &str = "profile";
+/// Largest profile accepted for upload
+pub const MAX_PROFILE_LEN: usize = 2048;
+/// Highest GPIO number of the ESP32-C6, the RISC-V target in `.cargo/config.toml`
+#[cfg(not(target_arch = "xtensa"))]
+const MAX_GPIO: i32 = 30;
+/// Highest GPIO number of the ESP32-S3, the Xtensa target in `.cargo/config.toml`
+#[cfg(target_arch = "xtensa")]
+const MAX_GPIO: i32 = 48;
+
+/// Chips on the I2C bus
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Chip {
+    Pcm1865,
+    Adau1962a,
+    Adau1467,
+    /// RP2040 that drives the amplifier enables, mutes and the chip reset lines
+    Expander,
+}
+
+impl Chip {
+    pub const ALL: [Chip; 4] = [
+        Chip::Pcm1865,
+        Chip::Adau1962a,
+        Chip::Adau1467,
+        Chip::Expander,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chip::Pcm1865 => "PCM1865",
+            Chip::Adau1962a => "ADAU1962A",
+            Chip::Adau1467 => "ADAU1467",
+            Chip::Expander => "RP2040 expander",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct I2cBusConfig {
+    pub sda: i32,
+    pub scl: i32,
+    pub baudrate_hz: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// 7 bit I2C address
+    pub address: u8,
+    /// Not fitted chips are left alone, the functions that need them are unavailable
+    pub fitted: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Devices {
+    pub pcm1865: DeviceConfig,
+    pub adau1962a: DeviceConfig,
+    pub adau1467: DeviceConfig,
+    pub expander: DeviceConfig,
+}
+
+impl Devices {
+    pub fn get(&self, chip: Chip) -> DeviceConfig {
+        match chip {
+            Chip::Pcm1865 => self.pcm1865,
+            Chip::Adau1962a => self.adau1962a,
+            Chip::Adau1467 => self.adau1467,
+            Chip::Expander => self.expander,
+        }
+    }
+
+    /// Address of the expander if it is fitted, the reset lines of the DAC and DSP run through it
+    pub fn expander_address(&self) -> Option<u8> {
+        self.expander.fitted.then_some(self.expander.address)
+    }
+}
+
+/// Front panel GPIOs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ControlPins {
+    pub encoder_a: i32,
+    pub encoder_b: i32,
+    pub button_mute: i32,
+    pub button_bassboost: i32,
+    pub button_standby: i32,
+    pub led_red: i32,
+    pub led_green: i32,
+    pub led_blue: i32,
+}
+
+impl ControlPins {
+    fn pins(&self) -> [i32; 8] {
+        [
+            self.encoder_a,
+            self.encoder_b,
+            self.button_mute,
+            self.button_bassboost,
+            self.button_standby,
+            self.led_red,
+            self.led_green,
+            self.led_blue,
+        ]
+    }
+}
+
+/// Description of one board revision: wiring, fitted chips and which parts of the
+/// firmware run
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BoardProfile {
+    pub name: String,
+    /// Off runs the firmware without the audio chain, e.g. on a bare dev kit
+    pub hardware_connected: bool,
+    pub enable_web: bool,
+    pub i2c: I2cBusConfig,
+    pub devices: Devices,
+    /// None on boards without a front panel
+    pub controls: Option<ControlPins>,
+    /// GPIO of the IR receiver module, None if not fitted
+    pub ir_receiver: Option<i32>,
+}
+
+impl BoardProfile {
+    /// The profile compiled into the firmware, held to the same checks as an upload
+    pub fn builtin() -> Self {
+        Self::parse(BUILTIN_PROFILE.as_bytes()).expect("Built-in board profile is invalid")
+    }
+
+    /// Parses and validates an uploaded profile
+    pub fn parse(json: &[u8]) -> anyhow::Result<Self> {
+        let profile: BoardProfile = serde_json::from_slice(json)
+            .map_err(|e| anyhow::anyhow!("Invalid board profile: {}", e))?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(10_000..=1_000_000).contains(&self.i2c.baudrate_hz) {
+            return Err(anyhow::Error::msg(
+                "I2C speed must be between 10 kHz and 1 MHz",
+            ));
+        }
+
+        let mut pins = vec![self.i2c.sda, self.i2c.scl];
+        pins.extend(self.controls.iter().flat_map(ControlPins::pins));
+        pins.extend(self.ir_receiver);
+        for (index, pin) in pins.iter().enumerate() {
+            if !(0..=MAX_GPIO).contains(pin) {
+                return Err(anyhow::anyhow!("GPIO {} does not exist", pin));
+            }
+            if pins[..index].contains(pin) {
+                return Err(anyhow::anyhow!("GPIO {} is used twice", pin));
+            }
+        }
+
+        let fitted: Vec<(Chip, u8)> = Chip::ALL
+            .iter()
+            .map(|&chip| (chip, self.devices.get(chip)))
+            .filter(|(_, device)| device.fitted)
+            .map(|(chip, device)| (chip, device.address))
+            .collect();
+        for (index, (chip, address)) in fitted.iter().enumerate() {
+            if !(0x01..=0x7F).contains(address) {
+                return Err(anyhow::anyhow!(
+                    "{} address 0x{:02X} is outside the 7 bit range",
+                    chip.name(),
+                    address
+                ));
+            }
+            if let Some((other, _)) = fitted[..index].iter().find(|(_, a)| a == address) {
+                return Err(anyhow::anyhow!(
+                    "{} and {} share address 0x{:02X}",
+                    other.name(),
+                    chip.name(),
+                    address
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Profile kept in NVS, written for boards that differ from the built-in one
+pub struct BoardStore {
+    nvs: EspDefaultNvs,
+}
+
+impl BoardStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        Ok(BoardStore {
+            nvs: EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?,
+        })
+    }
+
+    /// The stored profile, or the built-in one if there is none or it is unusable
+    pub fn load(&self) -> BoardProfile {
+        let mut buffer = vec![0u8; MAX_PROFILE_LEN];
+        let profile = match self.nvs.get_blob(NVS_KEY_PROFILE, &mut buffer) {
+            Ok(Some(json)) => BoardProfile::parse(json).unwrap_or_else(|e| {
+                log::error!("Stored board profile ignored: {}", e);
+                BoardProfile::builtin()
+            }),
+            Ok(None) => BoardProfile::builtin(),
+            Err(e) => {
+                log::error!("Could not read the stored board profile: {:?}", e);
+                BoardProfile::builtin()
+            }
+        };
+        log::info!("Board profile: {:?}", profile);
+        profile
+    }
+
+    /// Stores a profile for the next boot
+    pub fn save(&mut self, profile: &BoardProfile) -> anyhow::Result<()> {
+        self.nvs
+            .set_blob(NVS_KEY_PROFILE, &serde_json::to_vec(profile)?)?;
+        Ok(())
+    }
+
+    /// Goes back to the built-in profile with the next boot
+    pub fn clear(&mut self) -> anyhow::Result<()> {
+        self.nvs.remove(NVS_KEY_PROFILE)?;
+        Ok(())
+    }
+}
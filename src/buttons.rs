@@ -0,0 +1,132 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_state::AmpCommand,
+    gestures::{BoundGestures, Gesture, GestureTiming},
+    hardware_context::HardwareContext,
+    power::PowerRequest,
+};
+
+/// What a gesture on a button does
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonAction {
+    None,
+    ToggleMute,
+    CycleInput,
+    /// Standby and back, powers on when off
+    ToggleStandby,
+    PowerOff,
+    VolumeUp,
+    VolumeDown,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ButtonBindings {
+    pub short: ButtonAction,
+    pub double: ButtonAction,
+    pub long: ButtonAction,
+    pub repeat: ButtonAction,
+}
+
+impl ButtonBindings {
+    pub fn action(&self, gesture: Gesture) -> ButtonAction {
+        match gesture {
+            Gesture::Short => self.short,
+            Gesture::Double => self.double,
+            Gesture::Long => self.long,
+            Gesture::Repeat => self.repeat,
+        }
+    }
+
+    /// The optional gestures the detector has to wait for
+    pub fn bound(&self) -> BoundGestures {
+        BoundGestures {
+            double: self.double != ButtonAction::None,
+            repeat: self.repeat != ButtonAction::None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ButtonConfig {
+    pub timing: GestureTiming,
+    pub mute: ButtonBindings,
+    pub bassboost: ButtonBindings,
+    pub standby: ButtonBindings,
+}
+
+impl Default for ButtonConfig {
+    fn default() -> Self {
+        ButtonConfig {
+            timing: GestureTiming::default(),
+            mute: ButtonBindings {
+                short: ButtonAction::ToggleMute,
+                double: ButtonAction::None,
+                long: ButtonAction::CycleInput,
+                repeat: ButtonAction::None,
+            },
+            // Bass boost has no DSP block yet, the button cycles through the inputs for now
+            bassboost: ButtonBindings {
+                short: ButtonAction::CycleInput,
+                double: ButtonAction::None,
+                long: ButtonAction::None,
+                repeat: ButtonAction::None,
+            },
+            standby: ButtonBindings {
+                short: ButtonAction::ToggleStandby,
+                double: ButtonAction::None,
+                long: ButtonAction::PowerOff,
+                repeat: ButtonAction::None,
+            },
+        }
+    }
+}
+
+impl ButtonConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let timing = &self.timing;
+        if !(5..=200).contains(&timing.debounce_ms) {
+            return Err(anyhow::Error::msg(
+                "Debounce must be between 5 ms and 200 ms",
+            ));
+        }
+        if timing.long_press_ms <= timing.debounce_ms || timing.long_press_ms > 5000 {
+            return Err(anyhow::Error::msg(
+                "Long press must be longer than the debounce and at most 5 s",
+            ));
+        }
+        if !(50..=1000).contains(&timing.double_click_ms) {
+            return Err(anyhow::Error::msg(
+                "Double click window must be between 50 ms and 1 s",
+            ));
+        }
+        if !(20..=2000).contains(&timing.repeat_ms) {
+            return Err(anyhow::Error::msg(
+                "Repeat interval must be between 20 ms and 2 s",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ButtonAction {
+    pub fn command(&self) -> Option<AmpCommand> {
+        match self {
+            ButtonAction::None => None,
+            ButtonAction::ToggleMute => Some(AmpCommand::Power(PowerRequest::ToggleMute)),
+            ButtonAction::CycleInput => Some(AmpCommand::CycleInput),
+            ButtonAction::ToggleStandby => Some(AmpCommand::ToggleStandby),
+            ButtonAction::PowerOff => Some(AmpCommand::Power(PowerRequest::PowerOff)),
+            ButtonAction::VolumeUp => Some(AmpCommand::StepLevel(1)),
+            ButtonAction::VolumeDown => Some(AmpCommand::StepLevel(-1)),
+        }
+    }
+}
+
+/// Queues an action bound to a button or remote control key, the executor carries it out
+pub fn run_action(hardware_context: &HardwareContext, action: ButtonAction) {
+    if let Some(command) = action.command() {
+        hardware_context.amp.send(command);
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    drivers::pcm1865::{AdcChannel, AdcNumber},
+    hardware_context::HardwareContext,
+    lock::LockExt,
+    notifications::Notification,
+};
+
+const POLL_INTERVAL_MS: u64 = 100;
+/// Clipping that continues within this time is reported as one event
+const EVENT_HOLDOFF_MS: u64 = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ClipConfig {
+    /// Lower the PGA gain step by step while the ADC keeps clipping
+    pub auto_gain_staging: bool,
+    /// Gain reduction per step in dB
+    pub step_db: f32,
+    /// The gain is never lowered below this
+    pub min_gain_db: f32,
+    /// Time between two steps so the effect of the previous one can be seen
+    pub step_interval_ms: u32,
+}
+
+impl Default for ClipConfig {
+    fn default() -> Self {
+        ClipConfig {
+            auto_gain_staging: false,
+            step_db: 1.0,
+            min_gain_db: -12.0,
+            step_interval_ms: 1000,
+        }
+    }
+}
+
+impl ClipConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0.5..=12.0).contains(&self.step_db) {
+            return Err(anyhow::Error::msg("Step must be between 0.5 dB and 12 dB"));
+        }
+        if !(-12.0..=32.0).contains(&self.min_gain_db) {
+            return Err(anyhow::Error::msg(
+                "Minimum gain must be between -12 dB and 32 dB",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct ClipStats {
+    /// Number of polls the ADC was found clipping in
+    pub count: u32,
+    /// Milliseconds since boot of the first and the last clip event
+    pub first_ms: Option<u64>,
+    pub last_ms: Option<u64>,
+    /// Current PGA gain of ADC1, None until it was read the first time
+    pub gain_db: Option<f32>,
+}
+
+/// Watches the PCM1865 clip flag and optionally lowers the PGA gain. Only ADC overload is
+/// detected: DSP peak levels cannot be read, the exported program has no readback or level
+/// detector cell (systemfiles_IC_1_PARAM.h).
+pub fn clip_monitor(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    log::info!("Clip monitor thread started");
+
+    let boot = Instant::now();
+    {
+        let pcm1865 = hardware_context
+            .pcm1865
+            .lock_or_err("Could not lock PCM1865 driver")?;
+        pcm1865.enable_automatic_clipping_suppression(true)?;
+        pcm1865.enable_clip_detection(true)?;
+        let gain_db = pcm1865.pga_gain(AdcNumber::Adc1, AdcChannel::Left)?;
+        stats(&hardware_context, |stats| stats.gain_db = Some(gain_db));
+    }
+
+    let mut last_step: Option<Instant> = None;
+    let mut last_clip: Option<Instant> = None;
+
+    loop {
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+        let pcm1865 = hardware_context
+            .pcm1865
+            .lock_or_err("Could not lock PCM1865 driver")?;
+        if !pcm1865.take_clip_flag()? {
+            continue;
+        }
+
+        let new_event = last_clip.map_or(true, |clip| {
+            clip.elapsed() >= Duration::from_millis(EVENT_HOLDOFF_MS)
+        });
+        last_clip = Some(Instant::now());
+
+        let now_ms = boot.elapsed().as_millis() as u64;
+        let count = stats(&hardware_context, |stats| {
+            stats.count += 1;
+            stats.first_ms.get_or_insert(now_ms);
+            stats.last_ms = Some(now_ms);
+            stats.count
+        });
+
+        let config = *hardware_context
+            .clip_config
+            .lock_or_err("Could not lock clip config")?;
+        let step_due = last_step.map_or(true, |step| {
+            step.elapsed() >= Duration::from_millis(config.step_interval_ms as u64)
+        });
+
+        let mut gain_db = None;
+        if config.auto_gain_staging && step_due {
+            let current = pcm1865.pga_gain(AdcNumber::Adc1, AdcChannel::Left)?;
+            let lowered = (current - config.step_db).max(config.min_gain_db);
+            if lowered < current {
+                log::warn!("ADC clipping, lowering PGA gain to {} dB", lowered);
+                pcm1865.set_pga_gain(AdcNumber::Adc1, AdcChannel::Left, lowered)?;
+                pcm1865.set_pga_gain(AdcNumber::Adc1, AdcChannel::Right, lowered)?;
+                stats(&hardware_context, |stats| stats.gain_db = Some(lowered));
+                gain_db = Some(lowered);
+                last_step = Some(Instant::now());
+            }
+        }
+        drop(pcm1865);
+
+        if !new_event && gain_db.is_none() {
+            continue;
+        }
+        hardware_context.notifier.publish(Notification::Clipping {
+            count,
+            timestamp_ms: now_ms,
+            gain_db,
+        });
+    }
+}
+
+fn stats<T>(hardware_context: &HardwareContext, f: impl FnOnce(&mut ClipStats) -> T) -> T {
+    f(&mut hardware_context.clip_stats.lock_value())
+}
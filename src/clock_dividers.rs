@@ -0,0 +1,97 @@
+/// Crystal shared by the PCM1865 and the ADAU1962A, 512 * 48 kHz
+pub const XTAL_FREQUENCY: u32 = 24_576_000;
+/// BCK cycles per LRCK frame (2 x 32 bit slots)
+pub const BITS_PER_FRAME: u16 = 64;
+/// PLL setup of the SigmaStudio export (systemfiles_IC_1_REG.h): PLL_CTRL1 divides the
+/// 24.576 MHz reference by 8, PLL_CTRL0 multiplies it by 0x60, 294.912 MHz in total
+const EXPORT_PLL_CTRL0: u16 = 0x60;
+const EXPORT_PLL_CTRL1: u16 = 0x3;
+/// The clock generators run from the PLL output divided by 1024, 288 kHz
+const DSP_PLL_DIVIDER: u32 = 1024;
+/// CLK_GEN1_M and CLK_GEN1_N of the SigmaStudio export, which runs at 192 kHz
+pub const EXPORT_SAMPLE_RATE: u32 = 192_000;
+pub const EXPORT_CLOCK_M: u16 = 0x3;
+pub const EXPORT_CLOCK_N: u16 = 0x2;
+
+/// PCM1865 master clock to BCK divider for `sample_rate`
+pub fn pcm1865_bck_divider(sample_rate: u32) -> u8 {
+    (XTAL_FREQUENCY / (sample_rate * BITS_PER_FRAME as u32)) as u8
+}
+
+/// ADAU1467 PLL output for `reference_hz` from the PLL_CTRL0 and PLL_CTRL1 register values
+pub fn pll_frequency(reference_hz: u32, pll_ctrl0: u16, pll_ctrl1: u16) -> u32 {
+    let feedback_divider = (pll_ctrl0 & 0x7F) as u32;
+    let input_divider = 1 << (pll_ctrl1 & 0b11);
+    reference_hz / input_divider * feedback_divider
+}
+
+/// (M, N) of the DSP clock generator for `sample_rate`, the fraction of the 288 kHz
+/// generator base in lowest terms
+pub fn dsp_dividers(sample_rate: u32) -> (u16, u16) {
+    let base_rate =
+        pll_frequency(XTAL_FREQUENCY, EXPORT_PLL_CTRL0, EXPORT_PLL_CTRL1) / DSP_PLL_DIVIDER;
+    let divisor = gcd(base_rate, sample_rate);
+    ((base_rate / divisor) as u16, (sample_rate / divisor) as u16)
+}
+
+/// Output rate of an ADAU1467 clock generator, None for M=0
+pub fn dsp_rate(pll_frequency: u32, m: u16, n: u16) -> Option<u32> {
+    if m == 0 {
+        return None;
+    }
+    Some(((pll_frequency / DSP_PLL_DIVIDER) as u64 * n as u64 / m as u64) as u32)
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn export_pll_runs_at_294_912_mhz() {
+        assert_eq!(
+            pll_frequency(XTAL_FREQUENCY, EXPORT_PLL_CTRL0, EXPORT_PLL_CTRL1),
+            294_912_000
+        );
+    }
+
+    #[test]
+    fn dsp_dividers_match_the_export() {
+        assert_eq!(
+            dsp_dividers(EXPORT_SAMPLE_RATE),
+            (EXPORT_CLOCK_M, EXPORT_CLOCK_N)
+        );
+    }
+
+    #[test]
+    fn dsp_dividers_for_every_rate() {
+        let pll = pll_frequency(XTAL_FREQUENCY, EXPORT_PLL_CTRL0, EXPORT_PLL_CTRL1);
+        for (sample_rate, m, n) in [(48_000, 6, 1), (96_000, 3, 1), (192_000, 3, 2)] {
+            assert_eq!(dsp_dividers(sample_rate), (m, n), "{} Hz", sample_rate);
+            assert_eq!(dsp_rate(pll, m, n), Some(sample_rate), "{} Hz", sample_rate);
+        }
+    }
+
+    #[test]
+    fn dsp_rate_rejects_zero_m() {
+        assert_eq!(dsp_rate(294_912_000, 0, 2), None);
+    }
+
+    #[test]
+    fn pcm1865_dividers() {
+        for (sample_rate, divider) in [(48_000, 8), (96_000, 4), (192_000, 2)] {
+            assert_eq!(pcm1865_bck_divider(sample_rate), divider);
+            assert_eq!(
+                XTAL_FREQUENCY / divider as u32 / BITS_PER_FRAME as u32,
+                sample_rate
+            );
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{
+    board::Chip,
+    clock_dividers::{
+        self, BITS_PER_FRAME, EXPORT_CLOCK_M, EXPORT_CLOCK_N, EXPORT_SAMPLE_RATE, XTAL_FREQUENCY,
+    },
+    drivers::{
+        adau1467::ADAU1467,
+        adau1467_registers::ClockGenerator,
+        adau1962a::{MasterClockFsRatio, SampleRate, ADAU1962A},
+        pcm1865::PCM1865,
+    },
+    hardware_context::HardwareContext,
+    lock::LockExt,
+};
+
+/// Clock generator the DSP core and serial ports of the SigmaStudio project run from
+const DSP_CLOCK_GENERATOR: ClockGenerator = ClockGenerator::Gen1;
+/// Time the converters need to lock onto new clocks before unmuting
+const CLOCK_SETTLE_MS: u64 = 100;
+
+pub const SUPPORTED_RATES: [u32; 3] = [48_000, 96_000, 192_000];
+
+/// All clock settings of the signal chain for one sample rate
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct ClockPlan {
+    pub sample_rate: u32,
+    /// PCM1865 master clock to BCK divider (register 0x26)
+    pub pcm1865_bck_divider: u8,
+    /// PCM1865 BCK to LRCK divider (register 0x27)
+    pub pcm1865_lrck_divider: u16,
+    /// ADAU1467 clock generator dividers, output = 288 kHz * n / m
+    pub dsp_clock_n: u16,
+    pub dsp_clock_m: u16,
+    #[serde(skip)]
+    pub adau1962a_mclk_ratio: MasterClockFsRatio,
+    #[serde(skip)]
+    pub adau1962a_sample_rate: SampleRate,
+}
+
+impl ClockPlan {
+    pub fn new(sample_rate: u32) -> anyhow::Result<Self> {
+        let adau1962a_sample_rate = match sample_rate {
+            48_000 => SampleRate::FS48,
+            96_000 => SampleRate::FS96,
+            192_000 => SampleRate::FS192,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "Unsupported sample rate {} Hz, must be one of {:?}",
+                    sample_rate,
+                    SUPPORTED_RATES
+                ))
+            }
+        };
+
+        let (dsp_clock_m, dsp_clock_n) = clock_dividers::dsp_dividers(sample_rate);
+        let plan = ClockPlan {
+            sample_rate,
+            pcm1865_bck_divider: clock_dividers::pcm1865_bck_divider(sample_rate),
+            pcm1865_lrck_divider: BITS_PER_FRAME,
+            dsp_clock_n,
+            dsp_clock_m,
+            // The ratio is referenced to 48 kHz, the DAC divides it down for higher rates
+            adau1962a_mclk_ratio: MasterClockFsRatio::Ratio512,
+            adau1962a_sample_rate,
+        };
+        plan.validate()?;
+        Ok(plan)
+    }
+
+    /// Checks that the converters end up at exactly the sample rate and that the DSP
+    /// dividers match the SigmaStudio export at its rate. The DSP rate itself is checked
+    /// against the chip by `verify_adau1467` once the dividers are written.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.sample_rate == EXPORT_SAMPLE_RATE
+            && (self.dsp_clock_m, self.dsp_clock_n) != (EXPORT_CLOCK_M, EXPORT_CLOCK_N)
+        {
+            return Err(anyhow::anyhow!(
+                "DSP clock M={} N={} differs from the SigmaStudio export M={} N={}",
+                self.dsp_clock_m,
+                self.dsp_clock_n,
+                EXPORT_CLOCK_M,
+                EXPORT_CLOCK_N
+            ));
+        }
+
+        let adc_rate =
+            XTAL_FREQUENCY / self.pcm1865_bck_divider as u32 / self.pcm1865_lrck_divider as u32;
+        let dac_rate = XTAL_FREQUENCY / self.adau1962a_mclk_ratio.ratio()
+            * self.adau1962a_sample_rate.multiplier();
+
+        if adc_rate != self.sample_rate
+            || dac_rate != self.sample_rate
+            || XTAL_FREQUENCY % (self.sample_rate * BITS_PER_FRAME as u32) != 0
+        {
+            return Err(anyhow::anyhow!(
+                "Inconsistent clock plan for {} Hz: ADC {} Hz, DAC {} Hz",
+                self.sample_rate,
+                adc_rate,
+                dac_rate
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the PLL and clock generator settings back from the DSP and checks that they
+    /// produce the sample rate
+    pub fn verify_adau1467(&self, adau1467: &ADAU1467) -> anyhow::Result<()> {
+        let pll_frequency = adau1467.pll_frequency(XTAL_FREQUENCY)?;
+        let (m, n) = adau1467.clock_generator(DSP_CLOCK_GENERATOR)?;
+        let Some(dsp_rate) = clock_dividers::dsp_rate(pll_frequency, m, n) else {
+            return Err(anyhow::anyhow!("DSP clock generator reads back M=0"));
+        };
+        if dsp_rate != self.sample_rate {
+            return Err(anyhow::anyhow!(
+                "DSP runs at {} Hz instead of {} Hz (PLL {} Hz, M={} N={})",
+                dsp_rate,
+                self.sample_rate,
+                pll_frequency,
+                m,
+                n
+            ));
+        }
+        Ok(())
+    }
+
+    /// Switches the chips the board has to this plan. The DAC is muted while the clocks
+    /// change and unmuted again even if a step failed. Runs on the amplifier executor, which
+    /// fades the outputs out around it.
+    pub fn apply(&self, hardware_context: &HardwareContext) -> anyhow::Result<()> {
+        log::info!("Switching signal chain to {} Hz", self.sample_rate);
+
+        let has_dac = hardware_context.has(Chip::Adau1962a);
+        if has_dac {
+            hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?
+                .set_master_mute(true)?;
+        }
+
+        let result = self.switch_clocks(hardware_context);
+        if result.is_ok() {
+            std::thread::sleep(Duration::from_millis(CLOCK_SETTLE_MS));
+        }
+
+        if has_dac {
+            hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?
+                .set_master_mute(false)?;
+        }
+        result
+    }
+
+    fn switch_clocks(&self, hardware_context: &HardwareContext) -> anyhow::Result<()> {
+        if hardware_context.has(Chip::Pcm1865) {
+            self.apply_pcm1865(
+                &mut *hardware_context
+                    .pcm1865
+                    .lock_or_err("Could not lock PCM1865 driver")?,
+            )?;
+        }
+        if hardware_context.has(Chip::Adau1467) {
+            self.apply_adau1467(
+                &mut *hardware_context
+                    .adau1467
+                    .lock_or_err("Could not lock ADAU1467 driver")?,
+            )?;
+        }
+        if hardware_context.has(Chip::Adau1962a) {
+            self.apply_adau1962a(
+                &mut *hardware_context
+                    .adau1962a
+                    .lock_or_err("Could not lock ADAU1962a driver")?,
+            )?;
+        }
+        *hardware_context
+            .clock_plan
+            .lock_or_err("Could not lock clock plan")? = *self;
+        Ok(())
+    }
+
+    pub fn apply_pcm1865(&self, pcm1865: &mut PCM1865) -> anyhow::Result<()> {
+        pcm1865.master_clock_to_bit_clock_divider_value(self.pcm1865_bck_divider)?;
+        pcm1865.bit_clock_to_left_right_clock_divider_value(self.pcm1865_lrck_divider)?;
+        Ok(())
+    }
+
+    /// Sets the DSP rate and recalculates the filter coefficients for it
+    pub fn apply_adau1467(&self, adau1467: &mut ADAU1467) -> anyhow::Result<()> {
+        adau1467.set_clock_generator(DSP_CLOCK_GENERATOR, self.dsp_clock_m, self.dsp_clock_n)?;
+        self.verify_adau1467(adau1467)?;
+        adau1467.set_sample_rate(self.sample_rate)
+    }
+
+    pub fn apply_adau1962a(&self, adau1962a: &mut ADAU1962A) -> anyhow::Result<()> {
+        adau1962a.set_master_clock_fs_ratio(self.adau1962a_mclk_ratio)?;
+        adau1962a.set_sample_rate_selection(self.adau1962a_sample_rate)?;
+        Ok(())
+    }
+}
+
+impl Default for ClockPlan {
+    fn default() -> Self {
+        ClockPlan::new(192_000).expect("Default clock plan is valid")
+    }
+}
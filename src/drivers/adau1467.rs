@@ -1,4 +1,5 @@
 use std::{
+    cell::Cell,
     sync::{Arc, Mutex},
     time::Duration,
 };
@@ -8,76 +9,308 @@ use esp_idf_svc::hal::i2c::I2cDriver;
 
 use anyhow::Result;
 
+use crate::drivers::adau1467_registers::{
+    AsrcSource, ClockGenerator, ControlRegister, CoreStatus, MclkOutRate, MpPinMode,
+    PllClockSource, PllInputDivider, SerialPortConfig, ASRC_COUNT,
+};
+use crate::drivers::tpa3116d2::EXPANDER_DSP_RESET;
+use crate::dsp_config::DspConfig;
 use crate::linkwitz_riley_coeffs::LinkwitzRileyCoeffs;
+use crate::lock::LockExt;
 
 pub struct ADAU1467<'a> {
     i2c: Arc<Mutex<I2cDriver<'a>>>, // Use the lifetime parameter here
     address: u8,
+    /// RP2040 that drives the reset line, None if the line is not controllable
+    expander: Option<u8>,
+    /// Rate the DSP core runs at, filter coefficients are calculated for it
+    sample_rate: u32,
+    /// Last filter settings, rewritten when the sample rate changes
+    dsp_config: Cell<Option<DspConfig>>,
 }
 impl<'a> ADAU1467<'a> {
-    pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>, address: u8) -> Self {
-        ADAU1467 { i2c, address }
+    pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>, address: u8, expander: Option<u8>) -> Self {
+        ADAU1467 {
+            i2c,
+            address,
+            expander,
+            sample_rate: 192_000,
+            dsp_config: Cell::new(None),
+        }
     }
 
-    fn set_bits(&self, register: u16, mask: u16, value: u16) -> Result<()> {
-        log::info!("Settings bits");
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
+    /// Reads a 16 bit control register (big-endian)
+    pub fn read_register(&self, register: ControlRegister) -> Result<u16> {
+        register.validate()?;
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
 
-        // Step 1: Read the current value of the register
-        let mut current_value = [0u8; 2];
+        let mut value = [0u8; 2];
         i2c.write_read(
             self.address,
-            &register.to_le_bytes(),
-            &mut current_value,
+            &register.address().to_be_bytes(),
+            &mut value,
             BLOCK,
         )?;
+        Ok(u16::from_be_bytes(value))
+    }
 
-        // Step 2: Modify the specific bits
-        let new_value = (u16::from_le_bytes(current_value) & !mask) | (value & mask);
+    /// Writes a 16 bit control register (big-endian)
+    pub fn write_register(&self, register: ControlRegister, value: u16) -> Result<()> {
+        register.validate()?;
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
 
         let mut data_to_write = Vec::with_capacity(4);
-        data_to_write.extend_from_slice(&register.to_le_bytes());
-        data_to_write.extend_from_slice(&new_value.to_le_bytes());
+        data_to_write.extend_from_slice(&register.address().to_be_bytes());
+        data_to_write.extend_from_slice(&value.to_be_bytes());
 
-        // Step 3: Write the modified value back
         i2c.write(self.address, &data_to_write, BLOCK)?;
         Ok(())
     }
 
+    fn set_bits(&self, register: ControlRegister, mask: u16, value: u16) -> Result<()> {
+        log::info!("Settings bits");
+
+        // Step 1: Read the current value of the register
+        let current_value = self.read_register(register)?;
+
+        // Step 2: Modify the specific bits
+        let new_value = (current_value & !mask) | (value & mask);
+
+        // Step 3: Write the modified value back
+        self.write_register(register, new_value)
+    }
+
     /// Set the RESET pin of the ADAU1467
     /// reset = true means the device is turned on
     pub fn set_reset(&mut self, reset: bool) -> Result<(), anyhow::Error> {
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
-        i2c.write(0x42, &[0x7, reset as u8], BLOCK)?;
+        let Some(expander) = self.expander else {
+            log::warn!("ADAU1467 reset line is not controllable on this board");
+            return Ok(());
+        };
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        i2c.write(expander, &[EXPANDER_DSP_RESET, reset as u8], BLOCK)?;
         std::thread::sleep(Duration::from_millis(15));
         Ok(())
     }
 
-    fn clear_panic(&self) -> Result<(), anyhow::Error> {
-        self.set_bits(0xF421, 0b1, 0b1)?;
-        self.set_bits(0xF421, 0b1, 0b0)?;
+    pub fn clear_panic(&self) -> Result<(), anyhow::Error> {
+        self.set_bits(ControlRegister::PanicClear, 0b1, 0b1)?;
+        self.set_bits(ControlRegister::PanicClear, 0b1, 0b0)?;
+        Ok(())
+    }
+
+    /// Returns the PANIC_CODE register if the panic flag is set
+    pub fn panic_code(&self) -> Result<Option<u16>, anyhow::Error> {
+        if self.read_register(ControlRegister::PanicFlag)? & 0b1 == 0 {
+            return Ok(None);
+        }
+        Ok(Some(self.read_register(ControlRegister::PanicCode)?))
+    }
+
+    pub fn core_status(&self) -> Result<CoreStatus, anyhow::Error> {
+        Ok(CoreStatus::from_value(
+            self.read_register(ControlRegister::CoreStatus)?,
+        ))
+    }
+
+    /// Identifies the chip by registers only the ADAU146x has at these 16 bit addresses:
+    /// SOFT_RESET reads 1 outside of a soft reset and CORE_STATUS holds a defined state
+    pub fn identify(&self) -> Result<bool, anyhow::Error> {
+        let soft_reset = self.read_register(ControlRegister::SoftReset)?;
+        let core_status = self.core_status()?;
+        Ok(soft_reset & 0b1 == 1 && !matches!(core_status, CoreStatus::Unknown(_)))
+    }
+
+    /// Configures the PLL. The PLL is disabled while the settings change.
+    /// Output frequency is (reference / input_divider) * feedback_divider
+    #[allow(unused)]
+    pub fn configure_pll(
+        &self,
+        source: PllClockSource,
+        input_divider: PllInputDivider,
+        feedback_divider: u8,
+    ) -> Result<(), anyhow::Error> {
+        if feedback_divider > 0x7F || feedback_divider == 0 {
+            return Err(anyhow::anyhow!(
+                "Invalid PLL feedback divider. Must be a value between 1 and 127"
+            ));
+        }
+        self.set_bits(ControlRegister::PllEnable, 0b1, 0b0)?;
+        self.set_bits(ControlRegister::PllCtrl0, 0x7F, feedback_divider as u16)?;
+        self.set_bits(ControlRegister::PllCtrl1, 0b11, input_divider.value())?;
+        self.set_bits(ControlRegister::PllClkSrc, 0b1, source.value())?;
+        self.set_bits(ControlRegister::PllEnable, 0b1, 0b1)?;
+        Ok(())
+    }
+
+    /// PLL output for `reference_hz` at the PLL input, from PLL_CTRL0 and PLL_CTRL1 as the
+    /// chip reports them
+    pub fn pll_frequency(&self, reference_hz: u32) -> Result<u32, anyhow::Error> {
+        Ok(crate::clock_dividers::pll_frequency(
+            reference_hz,
+            self.read_register(ControlRegister::PllCtrl0)?,
+            self.read_register(ControlRegister::PllCtrl1)?,
+        ))
+    }
+
+    #[allow(unused)]
+    pub fn pll_locked(&self) -> Result<bool, anyhow::Error> {
+        Ok(self.read_register(ControlRegister::PllLock)? & 0b1 != 0)
+    }
+
+    #[allow(unused)]
+    pub fn set_mclk_out(&self, enabled: bool, rate: MclkOutRate) -> Result<(), anyhow::Error> {
+        self.set_bits(
+            ControlRegister::MclkOut,
+            0b111,
+            (rate.value() << 1) | enabled as u16,
+        )
+    }
+
+    /// Sets a clock generator to base_rate * n / m
+    #[allow(unused)]
+    pub fn set_clock_generator(
+        &self,
+        generator: ClockGenerator,
+        m: u16,
+        n: u16,
+    ) -> Result<(), anyhow::Error> {
+        let mask = generator.divider_mask();
+        if m == 0 || m > mask || n > mask {
+            return Err(anyhow::anyhow!(
+                "Invalid clock generator divider M={} N={}",
+                m,
+                n
+            ));
+        }
+        self.write_register(ControlRegister::ClkGenM(generator), m)?;
+        self.write_register(ControlRegister::ClkGenN(generator), n)?;
+        Ok(())
+    }
+
+    /// (M, N) of a clock generator as the chip reports them
+    pub fn clock_generator(&self, generator: ClockGenerator) -> Result<(u16, u16), anyhow::Error> {
+        let mask = generator.divider_mask();
+        Ok((
+            self.read_register(ControlRegister::ClkGenM(generator))? & mask,
+            self.read_register(ControlRegister::ClkGenN(generator))? & mask,
+        ))
+    }
+
+    #[allow(unused)]
+    pub fn set_serial_port(
+        &self,
+        port: u8,
+        config: &SerialPortConfig,
+    ) -> Result<(), anyhow::Error> {
+        let (byte0, byte1) = config.to_registers();
+        self.write_register(ControlRegister::SerialByte0(port), byte0)?;
+        self.write_register(ControlRegister::SerialByte1(port), byte1)?;
         Ok(())
     }
 
+    #[allow(unused)]
+    pub fn serial_port(&self, port: u8) -> Result<SerialPortConfig, anyhow::Error> {
+        SerialPortConfig::from_registers(
+            self.read_register(ControlRegister::SerialByte0(port))?,
+            self.read_register(ControlRegister::SerialByte1(port))?,
+        )
+    }
+
+    /// Routes a serial input channel pair into an ASRC
+    #[allow(unused)]
+    pub fn set_asrc_input(
+        &self,
+        asrc: u8,
+        source: AsrcSource,
+        serial_input_channel: u8,
+    ) -> Result<(), anyhow::Error> {
+        if serial_input_channel > 0x1F {
+            return Err(anyhow::anyhow!("Invalid serial input channel"));
+        }
+        self.write_register(
+            ControlRegister::AsrcInput(asrc),
+            source.value() | ((serial_input_channel as u16) << 3),
+        )
+    }
+
+    /// Sets which port rate the ASRC output runs at (ASRC_RATE field)
+    #[allow(unused)]
+    pub fn set_asrc_output_rate(&self, asrc: u8, rate: u8) -> Result<(), anyhow::Error> {
+        self.set_bits(ControlRegister::AsrcOutRate(asrc), 0xF, rate as u16)
+    }
+
+    /// Returns a bitmask of ASRCs that are configured with an input source
+    pub fn active_asrcs(&self) -> Result<u8, anyhow::Error> {
+        let mut mask = 0;
+        for asrc in 0..ASRC_COUNT {
+            if self.read_register(ControlRegister::AsrcInput(asrc))? & 0b111 != 0 {
+                mask |= 1 << asrc;
+            }
+        }
+        Ok(mask)
+    }
+
+    /// The first ASRC fed from `source` and `serial_input_channel`, None if none is routed there
+    pub fn find_asrc(
+        &self,
+        source: AsrcSource,
+        serial_input_channel: u8,
+    ) -> Result<Option<u8>, anyhow::Error> {
+        let routing = source.value() | ((serial_input_channel as u16) << 3);
+        for asrc in 0..ASRC_COUNT {
+            if self.read_register(ControlRegister::AsrcInput(asrc))? & 0xFF == routing {
+                return Ok(Some(asrc));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the ASRC_LOCK register, one bit per ASRC
+    pub fn asrc_lock_status(&self) -> Result<u8, anyhow::Error> {
+        Ok((self.read_register(ControlRegister::AsrcLock)? & 0xFF) as u8)
+    }
+
+    /// Returns the input/output rate ratio of an ASRC in 4.12 fixed-point format
+    pub fn asrc_ratio(&self, asrc: u8) -> Result<u16, anyhow::Error> {
+        self.read_register(ControlRegister::AsrcRatio(asrc))
+    }
+
+    #[allow(unused)]
+    pub fn set_mp_pin_mode(
+        &self,
+        pin: u8,
+        mode: MpPinMode,
+        debounce: u8,
+    ) -> Result<(), anyhow::Error> {
+        self.set_bits(
+            ControlRegister::MpMode(pin),
+            0xFF,
+            0b1 | (mode.value() << 1) | (((debounce & 0xF) as u16) << 4),
+        )
+    }
+
+    #[allow(unused)]
+    pub fn write_mp_pin(&self, pin: u8, high: bool) -> Result<(), anyhow::Error> {
+        self.write_register(ControlRegister::MpWrite(pin), high as u16)
+    }
+
+    #[allow(unused)]
+    pub fn read_mp_pin(&self, pin: u8) -> Result<bool, anyhow::Error> {
+        Ok(self.read_register(ControlRegister::MpRead(pin))? & 0b1 != 0)
+    }
+
     pub fn load_dsp_program(&self) -> Result<(), anyhow::Error> {
-        crate::sigmastudio::interop::load_sigmastudio_dsp_program(&self.i2c);
+        crate::sigmastudio::interop::load_sigmastudio_dsp_program(&self.i2c)?;
         self.clear_panic()?;
         Ok(())
     }
 
     #[allow(unused)]
     pub fn read_second_page_select_reg(&self) -> Result<(), anyhow::Error> {
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
-
-        let mut current_value = [0u8; 2];
-        i2c.write_read(
-            self.address,
-            &(0xF899 as u16).to_le_bytes(),
-            &mut current_value,
-            BLOCK,
-        )?;
+        let current_value = self.read_register(ControlRegister::SecondaryPageSelect)?;
 
-        log::info!("Second page select: {:?}", current_value);
+        log::info!("Second page select: 0x{:04X}", current_value);
 
         Ok(())
     }
@@ -120,7 +353,7 @@ impl<'a> ADAU1467<'a> {
         // Safeload lower/upper page setting / words to write
         buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
 
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
         i2c.write(self.address, &buf, BLOCK)?;
 
         Ok(())
@@ -131,26 +364,67 @@ impl<'a> ADAU1467<'a> {
         (value * (1 << 23) as f32) as u32
     }
 
-    pub fn set_subwoofer_gain(&self, target_gain_db: f32) -> Result<(), anyhow::Error> {
-        // Make sure to write new filter coeffs to both crossover filters, as there is one for each channel
-        const CROSSOVER1_LOWPASS_FILTER1_BASE_ADDR: u16 = 52;
-        const CROSSOVER2_LOWPASS_FILTER1_BASE_ADDR: u16 = 62;
-
-        let coeffs = LinkwitzRileyCoeffs::new(192000.0, 100.0, target_gain_db as f64);
+    /// Writes the crossover and EQ filters of the DSP program
+    pub fn set_dsp_config(&self, config: &DspConfig) -> Result<(), anyhow::Error> {
+        // Both crossovers (CROSSOVER1, CROSSOVER2) get the same coefficients, one for each channel
+        const LOWPASS_FILTER1_ADDRS: [u16; 2] = [52, 62];
+        const LOWPASS_FILTER2_ADDRS: [u16; 2] = [57, 67];
+        const HIGHPASS_FILTER1_ADDRS: [u16; 2] = [24584, 24594];
+        const HIGHPASS_FILTER2_ADDRS: [u16; 2] = [24589, 24599];
+        const LOW_INVERT_ADDRS: [u16; 2] = [95, 96];
+        const GENFILTER1_ADDR: u16 = 24604;
+
+        let crossover = &config.crossover;
+        let coeffs = LinkwitzRileyCoeffs::new(
+            self.sample_rate as f64,
+            crossover.frequency_hz as f64,
+            crossover.subwoofer_gain_db as f64,
+        );
 
         log::debug!("Filter coefficients: {:?}", coeffs);
 
+        // The high-pass filters are in the upper data memory page
+        for (filter, addrs, lowerpage) in [
+            (&coeffs.lowpass_filter1, LOWPASS_FILTER1_ADDRS, true),
+            (&coeffs.lowpass_filter2, LOWPASS_FILTER2_ADDRS, true),
+            (&coeffs.highpass_filter1, HIGHPASS_FILTER1_ADDRS, false),
+            (&coeffs.highpass_filter2, HIGHPASS_FILTER2_ADDRS, false),
+        ] {
+            for addr in addrs {
+                self.safeload_write(&filter.to_fixed(), addr, lowerpage)?;
+            }
+        }
+        // The invert cells are gains in 8.24 format, -1 inverts
+        let polarity: i32 = if crossover.subwoofer_inverted { -1 } else { 1 };
+        for addr in LOW_INVERT_ADDRS {
+            self.safeload_write(&[(polarity << 24) as u32], addr, true)?;
+        }
         self.safeload_write(
-            &coeffs.lowpass_filter1.to_fixed(),
-            CROSSOVER1_LOWPASS_FILTER1_BASE_ADDR,
-            true,
-        )?;
-        self.safeload_write(
-            &coeffs.lowpass_filter1.to_fixed(),
-            CROSSOVER2_LOWPASS_FILTER1_BASE_ADDR,
-            true,
+            &config.eq.coeffs(self.sample_rate as f64).to_fixed(),
+            GENFILTER1_ADDR,
+            false,
         )?;
+        self.dsp_config.set(Some(*config));
 
         Ok(())
     }
+
+    /// The configuration last written by `set_dsp_config`
+    pub fn dsp_config(&self) -> Option<DspConfig> {
+        self.dsp_config.get()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Sets the rate the filter coefficients are calculated for and rewrites them.
+    /// The core clock itself comes from the clock generators, see `ClockPlan`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) -> Result<(), anyhow::Error> {
+        self.sample_rate = sample_rate;
+        if let Some(config) = self.dsp_config.get() {
+            self.set_dsp_config(&config)?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,550 @@
+//! Register map for the ADAU1467 control register space (0xF000 and up).
+//!
+//! All control registers are 16 bit wide and are transferred big-endian,
+//! the register address itself is big-endian as well.
+//! Names and bit positions follow the ADAU1463/ADAU1467 datasheet and the
+//! SigmaStudio export in `sigmastudio/systemfiles_IC_1_REG.h`.
+
+/// Number of serial input/output ports
+pub const SERIAL_PORT_COUNT: u8 = 8;
+/// Number of stereo ASRCs
+pub const ASRC_COUNT: u8 = 8;
+/// Number of multipurpose pins that are addressable through MPx_MODE/WRITE/READ
+pub const MP_PIN_COUNT: u8 = 14;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum ControlRegister {
+    PllCtrl0,
+    PllCtrl1,
+    PllClkSrc,
+    PllEnable,
+    PllLock,
+    MclkOut,
+    PllWatchdog,
+    ClkGenM(ClockGenerator),
+    ClkGenN(ClockGenerator),
+    ClkGen3Src,
+    ClkGen3Lock,
+    PowerEnable0,
+    PowerEnable1,
+    /// ASRC_INPUTx, x = 0..7
+    AsrcInput(u8),
+    /// ASRC_OUT_RATEx, x = 0..7
+    AsrcOutRate(u8),
+    /// SERIAL_BYTE_x_0, x = 0..7 (inputs 0..3, outputs 4..7)
+    SerialByte0(u8),
+    /// SERIAL_BYTE_x_1, x = 0..7
+    SerialByte1(u8),
+    Hibernate,
+    StartPulse,
+    StartCore,
+    KillCore,
+    StartAddress,
+    CoreStatus,
+    PanicClear,
+    PanicParityMask,
+    PanicSoftwareMask,
+    PanicWdMask,
+    PanicStackMask,
+    PanicLoopMask,
+    PanicFlag,
+    PanicCode,
+    /// MPx_MODE, x = 0..13
+    MpMode(u8),
+    /// MPx_WRITE, x = 0..13
+    MpWrite(u8),
+    /// MPx_READ, x = 0..13
+    MpRead(u8),
+    AsrcLock,
+    AsrcMute,
+    /// ASRCx_RATIO, x = 0..7
+    AsrcRatio(u8),
+    SoftReset,
+    SecondaryPageSelect,
+}
+
+impl ControlRegister {
+    /// Returns the 16 bit register address.
+    /// Indexed registers are not range checked here, use `validate` before accessing the bus.
+    pub fn address(&self) -> u16 {
+        match self {
+            ControlRegister::PllCtrl0 => 0xF000,
+            ControlRegister::PllCtrl1 => 0xF001,
+            ControlRegister::PllClkSrc => 0xF002,
+            ControlRegister::PllEnable => 0xF003,
+            ControlRegister::PllLock => 0xF004,
+            ControlRegister::MclkOut => 0xF005,
+            ControlRegister::PllWatchdog => 0xF006,
+            ControlRegister::ClkGenM(generator) => 0xF020 + 2 * generator.index(),
+            ControlRegister::ClkGenN(generator) => 0xF021 + 2 * generator.index(),
+            ControlRegister::ClkGen3Src => 0xF026,
+            ControlRegister::ClkGen3Lock => 0xF027,
+            ControlRegister::PowerEnable0 => 0xF050,
+            ControlRegister::PowerEnable1 => 0xF051,
+            ControlRegister::AsrcInput(n) => 0xF100 + *n as u16,
+            ControlRegister::AsrcOutRate(n) => 0xF140 + *n as u16,
+            ControlRegister::SerialByte0(n) => 0xF200 + 4 * *n as u16,
+            ControlRegister::SerialByte1(n) => 0xF201 + 4 * *n as u16,
+            ControlRegister::Hibernate => 0xF400,
+            ControlRegister::StartPulse => 0xF401,
+            ControlRegister::StartCore => 0xF402,
+            ControlRegister::KillCore => 0xF403,
+            ControlRegister::StartAddress => 0xF404,
+            ControlRegister::CoreStatus => 0xF405,
+            ControlRegister::PanicClear => 0xF421,
+            ControlRegister::PanicParityMask => 0xF422,
+            ControlRegister::PanicSoftwareMask => 0xF423,
+            ControlRegister::PanicWdMask => 0xF424,
+            ControlRegister::PanicStackMask => 0xF425,
+            ControlRegister::PanicLoopMask => 0xF426,
+            ControlRegister::PanicFlag => 0xF427,
+            ControlRegister::PanicCode => 0xF428,
+            ControlRegister::MpMode(n) => 0xF510 + *n as u16,
+            ControlRegister::MpWrite(n) => 0xF520 + *n as u16,
+            ControlRegister::MpRead(n) => 0xF530 + *n as u16,
+            ControlRegister::AsrcLock => 0xF580,
+            ControlRegister::AsrcMute => 0xF581,
+            ControlRegister::AsrcRatio(n) => 0xF582 + *n as u16,
+            ControlRegister::SoftReset => 0xF890,
+            ControlRegister::SecondaryPageSelect => 0xF899,
+        }
+    }
+
+    /// Checks that the index of an indexed register exists on the chip
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let (index, count) = match self {
+            ControlRegister::AsrcInput(n)
+            | ControlRegister::AsrcOutRate(n)
+            | ControlRegister::AsrcRatio(n) => (*n, ASRC_COUNT),
+            ControlRegister::SerialByte0(n) | ControlRegister::SerialByte1(n) => {
+                (*n, SERIAL_PORT_COUNT)
+            }
+            ControlRegister::MpMode(n)
+            | ControlRegister::MpWrite(n)
+            | ControlRegister::MpRead(n) => (*n, MP_PIN_COUNT),
+            _ => return Ok(()),
+        };
+
+        if index >= count {
+            return Err(anyhow::anyhow!(
+                "Invalid index {} for register {:?}. Must be below {}",
+                index,
+                self,
+                count
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum ClockGenerator {
+    Gen1,
+    Gen2,
+    Gen3,
+}
+
+impl ClockGenerator {
+    fn index(&self) -> u16 {
+        match self {
+            ClockGenerator::Gen1 => 0,
+            ClockGenerator::Gen2 => 1,
+            ClockGenerator::Gen3 => 2,
+        }
+    }
+
+    /// Width of the M and N divider fields
+    pub fn divider_mask(&self) -> u16 {
+        match self {
+            ClockGenerator::Gen1 | ClockGenerator::Gen2 => 0x01FF,
+            ClockGenerator::Gen3 => 0xFFFF,
+        }
+    }
+}
+
+/// PLL_CTRL1: PLL input clock divider
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum PllInputDivider {
+    Div1,
+    Div2,
+    Div4,
+    Div8,
+}
+
+impl PllInputDivider {
+    pub fn value(&self) -> u16 {
+        match self {
+            PllInputDivider::Div1 => 0b00,
+            PllInputDivider::Div2 => 0b01,
+            PllInputDivider::Div4 => 0b10,
+            PllInputDivider::Div8 => 0b11,
+        }
+    }
+}
+
+/// PLL_CLK_SRC: where the PLL reference comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum PllClockSource {
+    MclkIn,     // 0
+    Oscillator, // 1
+}
+
+impl PllClockSource {
+    pub fn value(&self) -> u16 {
+        match self {
+            PllClockSource::MclkIn => 0b0,
+            PllClockSource::Oscillator => 0b1,
+        }
+    }
+}
+
+/// MCLK_OUT: CLKOUT pin frequency
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum MclkOutRate {
+    Mhz3_072,
+    Mhz6_144,
+    Mhz12_288,
+    Mhz24_576,
+}
+
+impl MclkOutRate {
+    pub fn value(&self) -> u16 {
+        match self {
+            MclkOutRate::Mhz3_072 => 0b00,
+            MclkOutRate::Mhz6_144 => 0b01,
+            MclkOutRate::Mhz12_288 => 0b10,
+            MclkOutRate::Mhz24_576 => 0b11,
+        }
+    }
+}
+
+/// SERIAL_BYTE_x_0 TDM_MODE: channels per frame and bits per channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum TdmMode {
+    Ch2Bits32,
+    Ch4Bits32,
+    Ch8Bits32,
+    Ch16Bits32,
+    Ch4Bits16,
+    Ch2Bits16,
+}
+
+impl TdmMode {
+    pub fn value(&self) -> u16 {
+        match self {
+            TdmMode::Ch2Bits32 => 0b000,
+            TdmMode::Ch4Bits32 => 0b001,
+            TdmMode::Ch8Bits32 => 0b010,
+            TdmMode::Ch16Bits32 => 0b011,
+            TdmMode::Ch4Bits16 => 0b100,
+            TdmMode::Ch2Bits16 => 0b101,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Option<Self> {
+        match value & 0b111 {
+            0b000 => Some(TdmMode::Ch2Bits32),
+            0b001 => Some(TdmMode::Ch4Bits32),
+            0b010 => Some(TdmMode::Ch8Bits32),
+            0b011 => Some(TdmMode::Ch16Bits32),
+            0b100 => Some(TdmMode::Ch4Bits16),
+            0b101 => Some(TdmMode::Ch2Bits16),
+            _ => None,
+        }
+    }
+}
+
+/// SERIAL_BYTE_x_0 DATA_FMT: delay between LRCLK edge and MSB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum DataFormat {
+    I2s,           // delay by 1
+    LeftJustified, // delay by 0
+    DelayBy8,
+    DelayBy16,
+}
+
+impl DataFormat {
+    pub fn value(&self) -> u16 {
+        match self {
+            DataFormat::I2s => 0b00,
+            DataFormat::LeftJustified => 0b01,
+            DataFormat::DelayBy8 => 0b10,
+            DataFormat::DelayBy16 => 0b11,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Self {
+        match value & 0b11 {
+            0b00 => DataFormat::I2s,
+            0b01 => DataFormat::LeftJustified,
+            0b10 => DataFormat::DelayBy8,
+            _ => DataFormat::DelayBy16,
+        }
+    }
+}
+
+/// SERIAL_BYTE_x_0 WORD_LEN
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum WordLength {
+    Bits24,
+    Bits16,
+    Bits32,
+}
+
+impl WordLength {
+    pub fn value(&self) -> u16 {
+        match self {
+            WordLength::Bits24 => 0b00,
+            WordLength::Bits16 => 0b01,
+            WordLength::Bits32 => 0b10,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Option<Self> {
+        match value & 0b11 {
+            0b00 => Some(WordLength::Bits24),
+            0b01 => Some(WordLength::Bits16),
+            0b10 => Some(WordLength::Bits32),
+            _ => None,
+        }
+    }
+}
+
+/// SERIAL_BYTE_x_0 BCLK_SRC / LRCLK_SRC
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum SerialClockSource {
+    /// Clock comes from the pin of this port (slave)
+    Slave,
+    /// Clock is shared from the pins of serial port 0..3
+    Port(u8),
+    /// The port is master and generates the clock from a clock generator
+    Master(ClockGenerator),
+}
+
+impl SerialClockSource {
+    pub fn value(&self) -> u16 {
+        match self {
+            SerialClockSource::Slave => 0b000,
+            SerialClockSource::Port(n) => 0b001 + (*n as u16 & 0b11),
+            SerialClockSource::Master(ClockGenerator::Gen1) => 0b101,
+            SerialClockSource::Master(ClockGenerator::Gen2) => 0b110,
+            SerialClockSource::Master(ClockGenerator::Gen3) => 0b111,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Self {
+        match value & 0b111 {
+            0b000 => SerialClockSource::Slave,
+            0b101 => SerialClockSource::Master(ClockGenerator::Gen1),
+            0b110 => SerialClockSource::Master(ClockGenerator::Gen2),
+            0b111 => SerialClockSource::Master(ClockGenerator::Gen3),
+            n => SerialClockSource::Port((n - 1) as u8),
+        }
+    }
+}
+
+/// SERIAL_BYTE_x_1 FS: sample rate of the serial port
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum SerialPortRate {
+    FS48,
+    FS8,
+    FS12,
+    FS16,
+    FS24,
+    FS32,
+    FS96,
+    FS192,
+}
+
+impl SerialPortRate {
+    pub fn value(&self) -> u16 {
+        match self {
+            SerialPortRate::FS48 => 0b000,
+            SerialPortRate::FS8 => 0b001,
+            SerialPortRate::FS12 => 0b010,
+            SerialPortRate::FS16 => 0b011,
+            SerialPortRate::FS24 => 0b100,
+            SerialPortRate::FS32 => 0b101,
+            SerialPortRate::FS96 => 0b110,
+            SerialPortRate::FS192 => 0b111,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Self {
+        match value & 0b111 {
+            0b000 => SerialPortRate::FS48,
+            0b001 => SerialPortRate::FS8,
+            0b010 => SerialPortRate::FS12,
+            0b011 => SerialPortRate::FS16,
+            0b100 => SerialPortRate::FS24,
+            0b101 => SerialPortRate::FS32,
+            0b110 => SerialPortRate::FS96,
+            _ => SerialPortRate::FS192,
+        }
+    }
+}
+
+/// SERIAL_BYTE_x_1 CLK_DOMAIN
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum ClockDomain {
+    ClockGenerator1,
+    ClockGenerator2,
+    ClockGenerator3,
+    Asynchronous,
+}
+
+impl ClockDomain {
+    pub fn value(&self) -> u16 {
+        match self {
+            ClockDomain::ClockGenerator1 => 0b00,
+            ClockDomain::ClockGenerator2 => 0b01,
+            ClockDomain::ClockGenerator3 => 0b10,
+            ClockDomain::Asynchronous => 0b11,
+        }
+    }
+
+    pub fn from_value(value: u16) -> Self {
+        match value & 0b11 {
+            0b00 => ClockDomain::ClockGenerator1,
+            0b01 => ClockDomain::ClockGenerator2,
+            0b10 => ClockDomain::ClockGenerator3,
+            _ => ClockDomain::Asynchronous,
+        }
+    }
+}
+
+/// Complete configuration of one serial port (SERIAL_BYTE_x_0 and SERIAL_BYTE_x_1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SerialPortConfig {
+    pub tdm_mode: TdmMode,
+    pub data_format: DataFormat,
+    pub word_length: WordLength,
+    /// false = data changes on the falling BCLK edge
+    pub bclk_rising_edge: bool,
+    /// false = left channel while LRCLK is low
+    pub lrclk_inverted: bool,
+    /// false = 50% duty cycle, true = single BCLK pulse
+    pub lrclk_pulse_mode: bool,
+    pub bclk_source: SerialClockSource,
+    pub lrclk_source: SerialClockSource,
+    pub rate: SerialPortRate,
+    pub clock_domain: ClockDomain,
+    pub tristate: bool,
+}
+
+impl SerialPortConfig {
+    /// Packs the configuration into (SERIAL_BYTE_x_0, SERIAL_BYTE_x_1)
+    pub fn to_registers(&self) -> (u16, u16) {
+        let byte0 = self.tdm_mode.value()
+            | (self.data_format.value() << 3)
+            | (self.word_length.value() << 5)
+            | ((self.bclk_rising_edge as u16) << 7)
+            | ((self.lrclk_inverted as u16) << 8)
+            | ((self.lrclk_pulse_mode as u16) << 9)
+            | (self.bclk_source.value() << 10)
+            | (self.lrclk_source.value() << 13);
+        let byte1 =
+            self.rate.value() | (self.clock_domain.value() << 3) | ((self.tristate as u16) << 5);
+        (byte0, byte1)
+    }
+
+    pub fn from_registers(byte0: u16, byte1: u16) -> Result<Self, anyhow::Error> {
+        Ok(SerialPortConfig {
+            tdm_mode: TdmMode::from_value(byte0)
+                .ok_or_else(|| anyhow::anyhow!("Reserved TDM mode in 0x{:04X}", byte0))?,
+            data_format: DataFormat::from_value(byte0 >> 3),
+            word_length: WordLength::from_value(byte0 >> 5)
+                .ok_or_else(|| anyhow::anyhow!("Reserved word length in 0x{:04X}", byte0))?,
+            bclk_rising_edge: byte0 & (1 << 7) != 0,
+            lrclk_inverted: byte0 & (1 << 8) != 0,
+            lrclk_pulse_mode: byte0 & (1 << 9) != 0,
+            bclk_source: SerialClockSource::from_value(byte0 >> 10),
+            lrclk_source: SerialClockSource::from_value(byte0 >> 13),
+            rate: SerialPortRate::from_value(byte1),
+            clock_domain: ClockDomain::from_value(byte1 >> 3),
+            tristate: byte1 & (1 << 5) != 0,
+        })
+    }
+}
+
+/// ASRC_INPUTx ASRC_SOURCE
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum AsrcSource {
+    NotUsed,
+    SerialInput,
+    DspCore,
+    SpdifReceiver,
+}
+
+impl AsrcSource {
+    pub fn value(&self) -> u16 {
+        match self {
+            AsrcSource::NotUsed => 0b000,
+            AsrcSource::SerialInput => 0b001,
+            AsrcSource::DspCore => 0b010,
+            AsrcSource::SpdifReceiver => 0b011,
+        }
+    }
+}
+
+/// MPx_MODE MP_MODE: function of a multipurpose pin
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum MpPinMode {
+    InputControlPort,
+    InputDsp,
+    OutputControlPort,
+    OutputDsp,
+    PdmMicData,
+    FlagOutput,
+}
+
+impl MpPinMode {
+    pub fn value(&self) -> u16 {
+        match self {
+            MpPinMode::InputControlPort => 0b000,
+            MpPinMode::InputDsp => 0b001,
+            MpPinMode::OutputControlPort => 0b010,
+            MpPinMode::OutputDsp => 0b011,
+            MpPinMode::PdmMicData => 0b100,
+            MpPinMode::FlagOutput => 0b101,
+        }
+    }
+}
+
+/// CORE_STATUS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub enum CoreStatus {
+    NotRunning,
+    Running,
+    Paused,
+    Sleep,
+    Halted,
+    Unknown(u16),
+}
+
+impl CoreStatus {
+    pub fn from_value(value: u16) -> Self {
+        match value & 0b111 {
+            0b000 => CoreStatus::NotRunning,
+            0b001 => CoreStatus::Running,
+            0b010 => CoreStatus::Paused,
+            0b011 => CoreStatus::Sleep,
+            0b100 => CoreStatus::Halted,
+            other => CoreStatus::Unknown(other),
+        }
+    }
+}
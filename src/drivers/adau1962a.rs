@@ -7,20 +7,43 @@ use esp_idf_svc::hal::delay::BLOCK;
 use esp_idf_svc::hal::i2c::I2cDriver;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::drivers::adau1467::ADAU1467;
+use crate::drivers::adau1467_registers::{SerialClockSource, SerialPortConfig, TdmMode};
+use crate::drivers::tpa3116d2::EXPANDER_DAC_RESET;
+use crate::lock::LockExt;
+
+pub const DAC_CHANNEL_COUNT: u8 = 12;
+/// ADAU1467 serial output port wired to DSDATA1 of the ADAU1962A, further data lines follow
+const DAC_SERIAL_OUTPUT_PORT: u8 = 4;
+const LAST_SERIAL_OUTPUT_PORT: u8 = 7;
+/// THRM_TEMP_STAT reads 1 °C per LSB starting at -60 °C
+const TEMPERATURE_OFFSET_C: i16 = 60;
 
 pub struct ADAU1962A<'a> {
     i2c: Arc<Mutex<I2cDriver<'a>>>, // Use the lifetime parameter here
     address: u8,
+    /// RP2040 that drives the reset line, None if the line is not controllable
+    expander: Option<u8>,
+    taper: Taper,
+    tdm_config: TdmConfig,
 }
 
 impl<'a> ADAU1962A<'a> {
-    pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>, address: u8) -> Self {
-        ADAU1962A { i2c, address }
+    pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>, address: u8, expander: Option<u8>) -> Self {
+        ADAU1962A {
+            i2c,
+            address,
+            expander,
+            taper: Taper::default(),
+            tdm_config: TdmConfig::default(),
+        }
     }
 
     fn set_bits(&self, register: u8, mask: u8, value: u8) -> Result<()> {
         log::info!("Settings bits");
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
 
         // Step 1: Read the current value of the register
         let mut current_value = [0u8; 1];
@@ -37,13 +60,34 @@ impl<'a> ADAU1962A<'a> {
     /// Set the RESET pin of the ADAU1962a
     /// reset = true means the device is turned on
     pub fn set_reset(&mut self, reset: bool) -> Result<(), anyhow::Error> {
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
-        i2c.write(0x42, &[0x8, reset as u8], BLOCK)
-            .expect("Failed to communicate to RP2040");
+        let Some(expander) = self.expander else {
+            log::warn!("ADAU1962a reset line is not controllable on this board");
+            return Ok(());
+        };
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        i2c.write(expander, &[EXPANDER_DAC_RESET, reset as u8], BLOCK)?;
         std::thread::sleep(Duration::from_millis(300));
         Ok(())
     }
 
+    /// The ADAU1962A has no ID register. It is identified by two patterns written to the
+    /// channel 1 volume register reading back. The old value is restored on every path,
+    /// a failed write or read included, so the channel never keeps a test pattern.
+    pub fn identify(&self) -> Result<bool> {
+        let register = Self::channel_volume_register(1)?;
+        let old = self.read_register(register)?;
+        let identified = [0x55, 0xAA]
+            .into_iter()
+            .try_fold(true, |identified, pattern| {
+                self.set_bits(register, 0xFF, pattern)?;
+                Ok::<_, anyhow::Error>(identified && self.read_register(register)? == pattern)
+            });
+        let restored = self.set_bits(register, 0xFF, old);
+        let identified = identified?;
+        restored?;
+        Ok(identified)
+    }
+
     pub fn master_power_up(&mut self, power_up: bool) -> Result<(), anyhow::Error> {
         self.set_bits(0x00, 0b00000001, if power_up { 0x1 } else { 0x0 })
     }
@@ -97,11 +141,243 @@ impl<'a> ADAU1962A<'a> {
         self.set_bits(0x07, 0b00000001, if is_master { 0x1 } else { 0x0 })
     }
 
+    /// Configures the serial audio interface for stereo or TDM operation
+    /// Switches the serial interface over. The setup is checked against the ADAU1467 output
+    /// ports first, on a mismatch or a failed write the old setup stays in place.
+    pub fn set_tdm_config(
+        &mut self,
+        config: TdmConfig,
+        adau1467: &ADAU1467,
+    ) -> Result<(), anyhow::Error> {
+        config.validate()?;
+        config.check_link(adau1467)?;
+        let old = self.tdm_config;
+        if let Err(e) = self.write_tdm_config(config) {
+            if let Err(restore) = self.write_tdm_config(old) {
+                log::error!("Could not restore the old TDM config: {:?}", restore);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Writes the serial interface setup without looking at the DSP, for the power-up
+    /// before the DSP program runs
+    pub fn write_tdm_config(&mut self, config: TdmConfig) -> Result<(), anyhow::Error> {
+        config.validate()?;
+        self.set_serial_audio_interface(config.interface)?;
+        self.set_bits(0x06, 0b11000000, config.data_format.value() << 6)?;
+        self.set_dlrclk_polarity(config.lrclk_inverted)?;
+        self.set_bits(
+            0x07,
+            0b01000110,
+            ((config.lrclk_pulse_mode as u8) << 6)
+                | ((config.slot_width.value()) << 2)
+                | ((config.bclk_rising_edge as u8) << 1),
+        )?;
+        // Channels without a slot are muted, channels that get one back are unmuted. A mute
+        // set through the channel API on an assigned channel is left alone.
+        for channel in 1..=DAC_CHANNEL_COUNT {
+            if !config.is_assigned(channel) {
+                self.set_channel_mute(channel, true)?;
+            } else if !self.tdm_config.is_assigned(channel) {
+                self.set_channel_mute(channel, false)?;
+            }
+        }
+        self.tdm_config = config;
+        Ok(())
+    }
+
+    pub fn tdm_config(&self) -> TdmConfig {
+        self.tdm_config
+    }
+
+    /// Sets the master volume on the 0-100 UI scale, mapped to dB by the taper
     pub fn set_master_volume(&mut self, volume: u8) -> Result<(), anyhow::Error> {
-        let clamped = volume.min(100);
-        let max_step: u16 = 120; // 85 steps = 31.875 dB range
-        let vol_scaled = (max_step - (clamped as u16 * max_step) / 100) as u8;
-        self.set_bits(0x0B, 0xFF, vol_scaled)
+        self.set_master_attenuation(self.taper.to_volume(volume))?;
+        Ok(())
+    }
+
+    /// Reads back the master volume on the 0-100 scale used by `set_master_volume`
+    pub fn master_volume(&self) -> Result<u8, anyhow::Error> {
+        Ok(self.taper.to_level(self.master_attenuation()?))
+    }
+
+    pub fn set_taper(&mut self, taper: Taper) {
+        self.taper = taper;
+    }
+
+    pub fn taper(&self) -> Taper {
+        self.taper
+    }
+
+    /// Writes the master attenuator (0x0B) and returns the value read back
+    pub fn set_master_attenuation(&mut self, volume: Volume) -> Result<Volume, anyhow::Error> {
+        self.write_verified(0x0B, volume.register())
+            .map(Volume::from_register)
+    }
+
+    /// Writes the master attenuator without read back, for the many small steps of a ramp
+    pub fn step_master_attenuation(&mut self, volume: Volume) -> Result<(), anyhow::Error> {
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        i2c.write(self.address, &[0x0B, volume.register()], BLOCK)?;
+        Ok(())
+    }
+
+    pub fn master_attenuation(&self) -> Result<Volume, anyhow::Error> {
+        self.read_register(0x0B).map(Volume::from_register)
+    }
+
+    /// Writes the attenuator of one DAC channel (1..12) and returns the value read back
+    pub fn set_channel_volume(&mut self, channel: u8, volume: Volume) -> Result<Volume> {
+        let register = Self::channel_volume_register(channel)?;
+        self.write_verified(register, volume.register())
+            .map(Volume::from_register)
+    }
+
+    pub fn channel_volume(&self, channel: u8) -> Result<Volume> {
+        self.read_register(Self::channel_volume_register(channel)?)
+            .map(Volume::from_register)
+    }
+
+    /// Mutes one DAC channel (1..12) and returns the mute state read back
+    pub fn set_channel_mute(&mut self, channel: u8, muted: bool) -> Result<bool> {
+        let (register, bit) = Self::channel_mute_bit(channel)?;
+        self.set_bits(register, bit, if muted { bit } else { 0x0 })?;
+        self.channel_muted(channel)
+    }
+
+    pub fn channel_muted(&self, channel: u8) -> Result<bool> {
+        let (register, bit) = Self::channel_mute_bit(channel)?;
+        Ok(self.read_register(register)? & bit != 0)
+    }
+
+    /// Powers the temperature sensor (PDN_THRMSENS_CTRL_1, 0x02) in continuous conversion mode
+    pub fn enable_temperature_sensor(&mut self, enabled: bool) -> Result<()> {
+        // TS_PDN (bit 2) powers the sensor down, THRM_MODE (bit 3) = 0 converts continuously
+        self.set_bits(0x02, 0b00001100, if enabled { 0x0 } else { 0x1 << 2 })
+    }
+
+    /// Die temperature in °C from the last conversion (THRM_TEMP_STAT, 0x05)
+    pub fn temperature(&self) -> Result<i16> {
+        Ok(self.read_register(0x05)? as i16 - TEMPERATURE_OFFSET_C)
+    }
+
+    fn channel_volume_register(channel: u8) -> Result<u8> {
+        if !(1..=DAC_CHANNEL_COUNT).contains(&channel) {
+            return Err(anyhow::Error::msg("Invalid DAC channel. Must be 1..12"));
+        }
+        Ok(0x0B + channel)
+    }
+
+    /// DAC_MUTE1 (0x09) holds channels 1-8, DAC_MUTE2 (0x0A) channels 9-12
+    fn channel_mute_bit(channel: u8) -> Result<(u8, u8)> {
+        match channel {
+            1..=8 => Ok((0x09, 1 << (channel - 1))),
+            9..=DAC_CHANNEL_COUNT => Ok((0x0A, 1 << (channel - 9))),
+            _ => Err(anyhow::Error::msg("Invalid DAC channel. Must be 1..12")),
+        }
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8> {
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        let mut value = [0u8; 1];
+        i2c.write_read(self.address, &[register], &mut value, BLOCK)?;
+        Ok(value[0])
+    }
+
+    /// Writes a whole register and returns what the chip reports afterwards
+    fn write_verified(&self, register: u8, value: u8) -> Result<u8> {
+        self.set_bits(register, 0xFF, value)?;
+        let actual = self.read_register(register)?;
+        if actual != value {
+            log::warn!(
+                "ADAU1962A register 0x{:02X} reads 0x{:02X} after writing 0x{:02X}",
+                register,
+                actual,
+                value
+            );
+        }
+        Ok(actual)
+    }
+}
+
+/// Attenuation of a DAC volume control, 0.375 dB per register step
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Volume(u8);
+
+impl Volume {
+    pub const STEP_DB: f32 = 0.375;
+    /// Largest attenuation (register value 0xFF)
+    pub const MIN_DB: f32 = -95.625;
+    pub const MUTE: Volume = Volume(0xFF);
+
+    /// Rounds to the nearest step, values above 0 dB are clamped to 0 dB
+    pub fn from_db(db: f32) -> Self {
+        let steps = (-db.clamp(Self::MIN_DB, 0.0) / Self::STEP_DB).round();
+        Volume(steps as u8)
+    }
+
+    pub fn from_register(value: u8) -> Self {
+        Volume(value)
+    }
+
+    pub fn register(&self) -> u8 {
+        self.0
+    }
+
+    /// Gain in dB, 0 dB or below
+    pub fn db(&self) -> f32 {
+        -(self.0 as f32) * Self::STEP_DB
+    }
+}
+
+/// Maps the 0-100 UI scale onto dB: `db = -range_db * (1 - level / 100) ^ curve`.
+/// A curve of 1 is linear in dB, larger values give finer steps near full volume.
+/// Level 0 always mutes with the largest attenuation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Taper {
+    pub range_db: f32,
+    pub curve: f32,
+}
+
+impl Default for Taper {
+    fn default() -> Self {
+        // 120 steps, the range the volume knob always had
+        Taper {
+            range_db: 45.0,
+            curve: 1.0,
+        }
+    }
+}
+
+impl Taper {
+    pub fn validate(&self) -> Result<()> {
+        if !(Volume::STEP_DB..=-Volume::MIN_DB).contains(&self.range_db) {
+            return Err(anyhow::Error::msg(
+                "Taper range must be between 0.375 dB and 95.625 dB",
+            ));
+        }
+        if !(0.1..=10.0).contains(&self.curve) {
+            return Err(anyhow::Error::msg("Taper curve must be between 0.1 and 10"));
+        }
+        Ok(())
+    }
+
+    pub fn to_volume(&self, level: u8) -> Volume {
+        match level.min(100) {
+            0 => Volume::from_db(Volume::MIN_DB),
+            level => {
+                let position = 1.0 - level as f32 / 100.0;
+                Volume::from_db(-self.range_db * position.powf(self.curve))
+            }
+        }
+    }
+
+    /// Inverse of `to_volume`, attenuation beyond the range reads as 0
+    pub fn to_level(&self, volume: Volume) -> u8 {
+        let position = (-volume.db() / self.range_db).min(1.0);
+        (100.0 * (1.0 - position.powf(1.0 / self.curve))).round() as u8
     }
 }
 
@@ -121,7 +397,7 @@ impl MclkiXtaliOrDlrclkSelection {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
 /// Ratio is referenced to 48kHz, it is divided down for higher sampling rates
 pub enum MasterClockFsRatio {
@@ -140,6 +416,16 @@ impl MasterClockFsRatio {
             MasterClockFsRatio::Ratio768 => 0b11,
         }
     }
+
+    /// Master clock frequency divided by 48 kHz
+    pub fn ratio(&self) -> u32 {
+        match self {
+            MasterClockFsRatio::Ratio256 => 256,
+            MasterClockFsRatio::Ratio384 => 384,
+            MasterClockFsRatio::Ratio512 => 512,
+            MasterClockFsRatio::Ratio768 => 768,
+        }
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -158,9 +444,193 @@ impl DacClockSource {
     }
 }
 
-#[derive(Clone, Copy)]
+/// Serial interface setup of the DAC. The ADAU1962A assigns slots in a fixed order:
+/// DAC channel n is slot (n - 1) % slots_per_line on data line (n - 1) / slots_per_line + 1.
+/// Which DAC channels get their slot is set per channel, which signal the DSP puts into a
+/// slot is fixed by the SigmaStudio program.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TdmConfig {
+    pub interface: AudioInterface,
+    pub slot_width: SlotWidth,
+    pub data_format: DacDataFormat,
+    /// false = data changes on the falling BCLK edge
+    pub bclk_rising_edge: bool,
+    /// false = left channel while LRCLK is low
+    pub lrclk_inverted: bool,
+    /// false = 50% duty cycle, true = single BCLK pulse
+    pub lrclk_pulse_mode: bool,
+    /// DAC channels driven by the DSP, channel 1 first. The others are muted.
+    pub assigned: [bool; DAC_CHANNEL_COUNT as usize],
+}
+
+impl Default for TdmConfig {
+    fn default() -> Self {
+        // Stereo on DAC channels 1 and 2
+        let mut assigned = [false; DAC_CHANNEL_COUNT as usize];
+        assigned[..2].fill(true);
+        TdmConfig {
+            interface: AudioInterface::Stero,
+            slot_width: SlotWidth::Bits32,
+            data_format: DacDataFormat::I2s,
+            bclk_rising_edge: false,
+            lrclk_inverted: false,
+            lrclk_pulse_mode: false,
+            assigned,
+        }
+    }
+}
+
+impl TdmConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.assigned.contains(&true) {
+            return Err(anyhow::Error::msg(
+                "At least one DAC channel must be assigned",
+            ));
+        }
+        if self.slot_width == SlotWidth::Bits16 && self.interface == AudioInterface::TDM16 {
+            return Err(anyhow::Error::msg("TDM16 requires 32 bit slots"));
+        }
+        Ok(())
+    }
+
+    pub fn is_assigned(&self, channel: u8) -> bool {
+        (1..=DAC_CHANNEL_COUNT).contains(&channel) && self.assigned[channel as usize - 1]
+    }
+
+    /// Assigned DAC channels in ascending order
+    pub fn assigned_channels(&self) -> impl Iterator<Item = u8> + '_ {
+        (1..=DAC_CHANNEL_COUNT).filter(|channel| self.is_assigned(*channel))
+    }
+
+    /// (data line 1..6, slot 0..15) a DAC channel reads its sample from
+    pub fn slot_for_channel(&self, channel: u8) -> (u8, u8) {
+        let slots = self.interface.slots_per_line();
+        ((channel - 1) / slots + 1, (channel - 1) % slots)
+    }
+
+    /// Number of data lines up to the last one an assigned channel reads from
+    pub fn data_lines(&self) -> u8 {
+        self.assigned_channels()
+            .map(|channel| self.slot_for_channel(channel).0)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Checks every ADAU1467 output port that carries DAC channels against this setup
+    pub fn check_link(&self, adau1467: &ADAU1467) -> Result<()> {
+        let ports = DAC_SERIAL_OUTPUT_PORT..DAC_SERIAL_OUTPUT_PORT + self.data_lines();
+        if ports.end > LAST_SERIAL_OUTPUT_PORT + 1 {
+            return Err(anyhow::anyhow!(
+                "The assigned channels need {} data lines, the ADAU1467 only has 4 output ports",
+                self.data_lines()
+            ));
+        }
+
+        for port in ports {
+            self.check_serial_port(&adau1467.serial_port(port)?)
+                .map_err(|e| anyhow::anyhow!("Serial port {}: {}", port, e))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that an ADAU1467 serial output port produces the frame this DAC expects.
+    /// The DAC is the clock master, so the port has to be a clock slave.
+    pub fn check_serial_port(&self, port: &SerialPortConfig) -> Result<()> {
+        let (slots, width) = match port.tdm_mode {
+            TdmMode::Ch2Bits32 => (2, SlotWidth::Bits32),
+            TdmMode::Ch4Bits32 => (4, SlotWidth::Bits32),
+            TdmMode::Ch8Bits32 => (8, SlotWidth::Bits32),
+            TdmMode::Ch16Bits32 => (16, SlotWidth::Bits32),
+            TdmMode::Ch4Bits16 => (4, SlotWidth::Bits16),
+            TdmMode::Ch2Bits16 => (2, SlotWidth::Bits16),
+        };
+
+        let mut mismatches = Vec::new();
+        if slots != self.interface.slots_per_line() {
+            mismatches.push(format!(
+                "{} slots instead of {}",
+                slots,
+                self.interface.slots_per_line()
+            ));
+        }
+        if width != self.slot_width {
+            mismatches.push(format!(
+                "{:?} slots instead of {:?}",
+                width, self.slot_width
+            ));
+        }
+        if port.data_format.value() != self.data_format.value() as u16 {
+            mismatches.push(format!("data format {:?}", port.data_format));
+        }
+        if port.bclk_rising_edge != self.bclk_rising_edge {
+            mismatches.push("BCLK edge".to_string());
+        }
+        if port.lrclk_inverted != self.lrclk_inverted {
+            mismatches.push("LRCLK polarity".to_string());
+        }
+        if port.lrclk_pulse_mode != self.lrclk_pulse_mode {
+            mismatches.push("LRCLK mode".to_string());
+        }
+        if matches!(port.bclk_source, SerialClockSource::Master(_))
+            || matches!(port.lrclk_source, SerialClockSource::Master(_))
+        {
+            mismatches.push("port is clock master".to_string());
+        }
+
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "ADAU1467 serial port does not match the DAC: {}",
+                mismatches.join(", ")
+            ))
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlotWidth {
+    Bits32,
+    Bits16,
+}
+
+impl SlotWidth {
+    /// DAC_CTRL1 BCLK_RATE
+    pub fn value(&self) -> u8 {
+        match self {
+            SlotWidth::Bits32 => 0b0,
+            SlotWidth::Bits16 => 0b1,
+        }
+    }
+}
+
+/// DAC_CTRL0 SDATA_FMT: delay between LRCLK edge and MSB
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DacDataFormat {
+    I2s,
+    LeftJustified,
+    DelayBy8,
+    DelayBy16,
+}
+
+impl DacDataFormat {
+    pub fn value(&self) -> u8 {
+        match self {
+            DacDataFormat::I2s => 0b00,
+            DacDataFormat::LeftJustified => 0b01,
+            DacDataFormat::DelayBy8 => 0b10,
+            DacDataFormat::DelayBy16 => 0b11,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
 #[allow(dead_code)]
 pub enum AudioInterface {
+    #[serde(rename = "stereo")]
     Stero,
     TDM2,
     TDM4,
@@ -178,9 +648,18 @@ impl AudioInterface {
             AudioInterface::TDM16 => 0b100,
         }
     }
+
+    pub fn slots_per_line(&self) -> u8 {
+        match self {
+            AudioInterface::Stero | AudioInterface::TDM2 => 2,
+            AudioInterface::TDM4 => 4,
+            AudioInterface::TDM8 => 8,
+            AudioInterface::TDM16 => 16,
+        }
+    }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 #[allow(dead_code)]
 pub enum SampleRate {
     FS48,
@@ -198,4 +677,13 @@ impl SampleRate {
             SampleRate::FS192LowPropagationDelay => 0b11,
         }
     }
+
+    /// Sample rate divided by 48 kHz
+    pub fn multiplier(&self) -> u32 {
+        match self {
+            SampleRate::FS48 => 1,
+            SampleRate::FS96 => 2,
+            SampleRate::FS192 | SampleRate::FS192LowPropagationDelay => 4,
+        }
+    }
 }
@@ -1,4 +1,5 @@
 pub mod adau1467;
+pub mod adau1467_registers;
 pub mod adau1962a;
 pub mod pcm1865;
 pub mod tpa3116d2;
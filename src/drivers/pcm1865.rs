@@ -1,49 +1,230 @@
 use anyhow::{Error, Result};
 use esp_idf_svc::hal::delay::BLOCK;
 use esp_idf_svc::hal::i2c::I2cDriver;
+use serde::Serialize;
+use std::cell::Cell;
 use std::sync::{Arc, Mutex};
 
+use crate::lock::LockExt;
+
+/// Register 0x00 selects the page on every page
+const PAGE_SELECT_REGISTER: u8 = 0x00;
+/// Page holding all registers used by this driver
+const PAGE_0: u8 = 0x00;
+/// Page selected by `identify`, any page other than the power-on default works
+const PAGE_3: u8 = 0x03;
+
+const PGA_MIN_DB: f32 = -12.0;
+const PGA_MAX_DB: f32 = 32.0;
+
 pub struct PCM1865<'a> {
     i2c: Arc<Mutex<I2cDriver<'a>>>, // Use the lifetime parameter here
     address: u8,
+    /// Last page written to the page select register, None until the first access
+    current_page: Cell<Option<u8>>,
 }
 
 impl<'a> PCM1865<'a> {
     /// Creates a new instance of PCM1865 with a thread-safe I2C driver and device address
     pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>, address: u8) -> Self {
-        PCM1865 { i2c, address }
+        PCM1865 {
+            i2c,
+            address,
+            current_page: Cell::new(None),
+        }
     }
 
-    /// Sets the input source for a specified channel
-    #[allow(unused)]
-    pub fn set_input_source(&self, channel: u8, source_id: u8) -> Result<()> {
-        let register = match channel {
-            1 => 0x3B,
-            2 => 0x3C,
-            3 => 0x3D,
-            4 => 0x3E,
-            _ => return Err(Error::msg("Invalid channel selected")),
-        };
+    /// Selects the register page (0, 1, 3 or 253). Skipped if the page is already selected.
+    pub fn select_page(&self, page: u8) -> Result<()> {
+        if !matches!(page, 0 | 1 | 3 | 253) {
+            return Err(Error::msg("Invalid page. Must be 0, 1, 3 or 253"));
+        }
+        if self.current_page.get() == Some(page) {
+            return Ok(());
+        }
 
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
-        i2c.write(self.address, &[register, source_id], BLOCK)?;
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        i2c.write(self.address, &[PAGE_SELECT_REGISTER, page], BLOCK)?;
+        self.current_page.set(Some(page));
         Ok(())
     }
 
-    /// Mutes or unmutes a specified channel
+    /// The PCM1865 has no ID register. It is identified by the page select register
+    /// reading back a page other than the power-on default.
+    pub fn identify(&self) -> Result<bool> {
+        self.invalidate_page();
+        self.select_page(PAGE_3)?;
+        let mut page = [0u8; 1];
+        self.i2c
+            .lock_or_err("Failed to lock I2C driver")?
+            .write_read(self.address, &[PAGE_SELECT_REGISTER], &mut page, BLOCK)?;
+        self.select_page(PAGE_0)?;
+        Ok(page[0] == PAGE_3)
+    }
+
+    /// Forgets the selected page, e.g. after the chip was reset
+    pub fn invalidate_page(&self) {
+        self.current_page.set(None);
+    }
+
+    /// Sets the input source for a specified channel (1 = ADC1L, 2 = ADC1R, 3 = ADC2L, 4 = ADC2R)
+    #[allow(unused)]
+    pub fn set_input_source(&self, channel: u8, input: AdcInput) -> Result<()> {
+        let (adc, channel) = Self::channel_to_adc(channel)?;
+        self.set_adc_input(adc, channel, input, false)
+    }
+
+    /// Mutes or unmutes a specified channel (1..4) with the digital mute in DSP_CTRL
     #[allow(dead_code)]
     pub fn mute_channel(&self, channel: u8, mute: bool) -> Result<()> {
-        let mute_register = 0x10 + channel;
-        let mute_value = if mute { 0x01 } else { 0x00 };
+        if !(1..=4).contains(&channel) {
+            return Err(Error::msg("Invalid channel selected"));
+        }
+        let bit = 1 << (channel - 1);
+        self.set_bits(0x71, bit, if mute { bit } else { 0x0 })
+    }
 
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
-        i2c.write(self.address, &[mute_register, mute_value], BLOCK)?;
-        Ok(())
+    /// Sets the analog PGA gain of one channel in dB, -12 dB to +32 dB in 0.5 dB steps
+    #[allow(unused)]
+    pub fn set_pga_gain(&self, adc: AdcNumber, channel: AdcChannel, gain_db: f32) -> Result<()> {
+        if !(PGA_MIN_DB..=PGA_MAX_DB).contains(&gain_db) {
+            return Err(Error::msg(
+                "Invalid PGA gain. Must be between -12 dB and 32 dB",
+            ));
+        }
+        let value = (gain_db * 2.0).round() as i8 as u8;
+        self.set_bits(Self::pga_register(adc, channel), 0xFF, value)
+    }
+
+    /// Reads back the PGA gain of one channel in dB
+    #[allow(unused)]
+    pub fn pga_gain(&self, adc: AdcNumber, channel: AdcChannel) -> Result<f32> {
+        let value = self.read_register(Self::pga_register(adc, channel))?;
+        Ok(value as i8 as f32 / 2.0)
+    }
+
+    /// Ramps PGA gain changes instead of jumping (SMOOTH bit of PGA_CTRL)
+    #[allow(unused)]
+    pub fn set_pga_smooth_change(&self, smooth: bool) -> Result<()> {
+        self.set_bits(0x05, 0b10000000, if smooth { 0x1 << 7 } else { 0x0 })
+    }
+
+    /// Enables the high-pass filter that removes DC from the ADC output
+    #[allow(unused)]
+    pub fn set_high_pass_filter(&self, enabled: bool) -> Result<()> {
+        // HPF_EN: 0 = enabled, 1 = disabled
+        self.set_bits(0x71, 0b00010000, if enabled { 0x0 } else { 0x1 << 4 })
+    }
+
+    /// Configures the audio output format of the serial interface (register 0x0B)
+    #[allow(unused)]
+    pub fn set_output_format(
+        &self,
+        format: AudioFormat,
+        word_length: TxWordLength,
+    ) -> Result<(), anyhow::Error> {
+        self.set_bits(
+            0x0B,
+            0b00001111,
+            (word_length.value() << 2) | format.value(),
+        )
+    }
+
+    /// Reads the clock detection and power state status registers (0x72..0x75)
+    pub fn status(&self) -> Result<AdcStatus> {
+        let power_state = self.read_register(0x72)?;
+        let sample_rate = self.read_register(0x73)?;
+        let clock_ratio = self.read_register(0x74)?;
+        let clock_error = self.read_register(0x75)?;
+
+        Ok(AdcStatus {
+            power_state: PowerState::from_value(power_state),
+            sample_rate: DetectedSampleRate::from_value(sample_rate),
+            bck_lrck_ratio: match (clock_ratio >> 4) & 0b111 {
+                0b001 => Some(32),
+                0b010 => Some(48),
+                0b011 => Some(64),
+                0b100 => Some(256),
+                _ => None,
+            },
+            sck_lrck_ratio: match clock_ratio & 0b111 {
+                0b001 => Some(128),
+                0b010 => Some(256),
+                0b011 => Some(384),
+                0b100 => Some(512),
+                0b101 => Some(768),
+                _ => None,
+            },
+            lrck_halted: clock_error & (1 << 6) != 0,
+            bck_halted: clock_error & (1 << 5) != 0,
+            sck_halted: clock_error & (1 << 4) != 0,
+            lrck_error: clock_error & (1 << 2) != 0,
+            bck_error: clock_error & (1 << 1) != 0,
+            sck_error: clock_error & 1 != 0,
+        })
+    }
+
+    /// Selects which analog inputs the energysense signal detector scans.
+    /// Bit n of the mask: 0 = VIN1L, 1 = VIN1R, 2 = VIN2L, ... 7 = VIN4R
+    pub fn set_signal_detect_mask(&self, mask: u8) -> Result<()> {
+        self.set_bits(0x31, 0xFF, mask)
+    }
+
+    /// Returns one bit per scanned input (same layout as the mask), set while a signal is present
+    pub fn signal_detect_status(&self) -> Result<u8> {
+        self.read_register(0x32)
+    }
+
+    /// Sets the level difference an input needs to count as a signal (SIGDET_DC_DIFF)
+    pub fn set_signal_detect_threshold(&self, input: u8, threshold: u8) -> Result<()> {
+        if input > 7 {
+            return Err(Error::msg("Invalid signal detect input. Must be 0..7"));
+        }
+        self.set_bits(0x41 + input * 3, 0xFF, threshold)
+    }
+
+    /// Enables the clip detection interrupt source, its flag can then be polled with `take_clip_flag`
+    pub fn enable_clip_detection(&self, enabled: bool) -> Result<()> {
+        self.set_bits(0x60, 0b00000100, if enabled { 0x1 << 2 } else { 0x0 })
+    }
+
+    /// Returns whether the ADC clipped since the last call, reading INT_FLAG clears it
+    pub fn take_clip_flag(&self) -> Result<bool> {
+        Ok(self.read_register(0x61)? & 0b00000100 != 0)
+    }
+
+    fn channel_to_adc(channel: u8) -> Result<(AdcNumber, AdcChannel)> {
+        match channel {
+            1 => Ok((AdcNumber::Adc1, AdcChannel::Left)),
+            2 => Ok((AdcNumber::Adc1, AdcChannel::Right)),
+            3 => Ok((AdcNumber::Adc2, AdcChannel::Left)),
+            4 => Ok((AdcNumber::Adc2, AdcChannel::Right)),
+            _ => Err(Error::msg("Invalid channel selected")),
+        }
+    }
+
+    fn pga_register(adc: AdcNumber, channel: AdcChannel) -> u8 {
+        match (adc, channel) {
+            (AdcNumber::Adc1, AdcChannel::Left) => 0x01,
+            (AdcNumber::Adc1, AdcChannel::Right) => 0x02,
+            (AdcNumber::Adc2, AdcChannel::Left) => 0x03,
+            (AdcNumber::Adc2, AdcChannel::Right) => 0x04,
+        }
+    }
+
+    fn read_register(&self, register: u8) -> Result<u8> {
+        self.select_page(PAGE_0)?;
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+
+        let mut value = [0u8; 1];
+        i2c.write_read(self.address, &[register], &mut value, BLOCK)?;
+        Ok(value[0])
     }
 
     fn set_bits(&self, register: u8, mask: u8, value: u8) -> Result<()> {
         log::info!("Settings bits");
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
+        self.select_page(PAGE_0)?;
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
 
         // Step 1: Read the current value of the register
         let mut current_value = [0u8; 1];
@@ -57,8 +238,13 @@ impl<'a> PCM1865<'a> {
         Ok(())
     }
 
-    #[allow(dead_code)]
-    fn enable_automatic_clipping_suppression(&mut self, state: bool) -> Result<(), anyhow::Error> {
+    /// Powers the whole ADC down (PWRDN_CTRL bit 0), the registers keep their values
+    pub fn set_power_down(&self, power_down: bool) -> Result<()> {
+        self.set_bits(0x70, 0b00000001, if power_down { 0x1 } else { 0x0 })
+    }
+
+    /// Lets the PGA back off its gain on its own when the ADC would clip (PGA_CTRL bit 0)
+    pub fn enable_automatic_clipping_suppression(&self, state: bool) -> Result<(), anyhow::Error> {
         self.set_bits(0x05, 0x1, if state { 0x1 } else { 0x00 })
     }
 
@@ -154,6 +340,148 @@ impl SckXtalSelection {
     }
 }
 
+/// Clock and power state as detected by the PCM1865
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct AdcStatus {
+    pub power_state: PowerState,
+    pub sample_rate: DetectedSampleRate,
+    pub bck_lrck_ratio: Option<u16>,
+    pub sck_lrck_ratio: Option<u16>,
+    pub lrck_halted: bool,
+    pub bck_halted: bool,
+    pub sck_halted: bool,
+    pub lrck_error: bool,
+    pub bck_error: bool,
+    pub sck_error: bool,
+}
+
+impl AdcStatus {
+    pub fn has_clock_error(&self) -> bool {
+        self.lrck_halted
+            || self.bck_halted
+            || self.sck_halted
+            || self.lrck_error
+            || self.bck_error
+            || self.sck_error
+    }
+
+    /// The ADC is running with valid clocks at a detected sample rate
+    pub fn is_healthy(&self) -> bool {
+        self.power_state == PowerState::Run
+            && !self.has_clock_error()
+            && self.sample_rate.is_valid()
+    }
+}
+
+/// Current power state (register 0x72)
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    PowerDown,
+    WaitClockStable,
+    ReleaseReset,
+    Standby,
+    FadeIn,
+    FadeOut,
+    Sleep,
+    Run,
+    Reserved,
+}
+
+impl PowerState {
+    pub fn from_value(value: u8) -> Self {
+        match value & 0x0F {
+            0b0000 => PowerState::PowerDown,
+            0b0001 => PowerState::WaitClockStable,
+            0b0010 => PowerState::ReleaseReset,
+            0b0011 => PowerState::Standby,
+            0b0100 => PowerState::FadeIn,
+            0b0101 => PowerState::FadeOut,
+            0b1001 => PowerState::Sleep,
+            0b1111 => PowerState::Run,
+            _ => PowerState::Reserved,
+        }
+    }
+}
+
+/// Detected sampling frequency (register 0x73)
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DetectedSampleRate {
+    OutOfRangeLow,
+    FS8,
+    FS16,
+    FS32To48,
+    FS88To96,
+    FS176To192,
+    OutOfRangeHigh,
+    Invalid,
+}
+
+impl DetectedSampleRate {
+    pub fn from_value(value: u8) -> Self {
+        match value & 0b111 {
+            0b000 => DetectedSampleRate::OutOfRangeLow,
+            0b001 => DetectedSampleRate::FS8,
+            0b010 => DetectedSampleRate::FS16,
+            0b011 => DetectedSampleRate::FS32To48,
+            0b100 => DetectedSampleRate::FS88To96,
+            0b101 => DetectedSampleRate::FS176To192,
+            0b110 => DetectedSampleRate::OutOfRangeHigh,
+            _ => DetectedSampleRate::Invalid,
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        !matches!(
+            self,
+            DetectedSampleRate::OutOfRangeLow
+                | DetectedSampleRate::OutOfRangeHigh
+                | DetectedSampleRate::Invalid
+        )
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum AudioFormat {
+    I2s,
+    LeftJustified,
+    RightJustified,
+    Tdm,
+}
+
+impl AudioFormat {
+    pub fn value(&self) -> u8 {
+        match self {
+            AudioFormat::I2s => 0b00,
+            AudioFormat::LeftJustified => 0b01,
+            AudioFormat::RightJustified => 0b10,
+            AudioFormat::Tdm => 0b11,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum TxWordLength {
+    Bits32,
+    Bits24,
+    Bits20,
+    Bits16,
+}
+
+impl TxWordLength {
+    pub fn value(&self) -> u8 {
+        match self {
+            TxWordLength::Bits32 => 0b00,
+            TxWordLength::Bits24 => 0b01,
+            TxWordLength::Bits20 => 0b10,
+            TxWordLength::Bits16 => 0b11,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum AdcNumber {
     Adc1,
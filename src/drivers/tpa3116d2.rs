@@ -3,38 +3,77 @@ use std::{
     time::Duration,
 };
 
+use crate::lock::LockExt;
 use esp_idf_svc::hal::{delay::BLOCK, i2c::I2cDriver};
 
+/// Number of TPA3116D2 amplifiers behind the RP2040
+pub const AMP_COUNT: u8 = 2;
+
+// Register map of the RP2040 expander firmware. Every register mirrors one GPIO (or a
+// group of inputs for the fault register), written and read back as 0 or 1.
+/// Enable (SDZ) of amplifier 1 and 2, 1 = outputs switching
+const EXPANDER_AMP_ENABLE: [u8; AMP_COUNT as usize] = [0x0, 0x2];
+/// Mute of amplifier 1 and 2, 1 = muted
+const EXPANDER_AMP_MUTE: [u8; AMP_COUNT as usize] = [0x4, 0x5];
+/// Reset line of the ADAU1467, 1 = running
+pub const EXPANDER_DSP_RESET: u8 = 0x7;
+/// Reset line of the ADAU1962A, 1 = running
+pub const EXPANDER_DAC_RESET: u8 = 0x8;
+/// FAULTZ of the amplifiers, read only, bit n = amplifier n reports a fault. Unlike the
+/// registers above it is not documented for every expander firmware, the self-test checks
+/// that it answers before the fault monitor relies on it.
+const EXPANDER_AMP_FAULT: u8 = 0x9;
+
+/// The amplifiers are controlled through GPIOs of the RP2040 expander
 pub struct TPA3116D2<'a> {
     i2c: Arc<Mutex<I2cDriver<'a>>>, // Use the lifetime parameter here
+    expander: u8,
 }
 
 impl<'a> TPA3116D2<'a> {
-    pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>) -> Self {
-        TPA3116D2 { i2c }
+    pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>, expander: u8) -> Self {
+        TPA3116D2 { i2c, expander }
     }
 
-    #[allow(dead_code)]
     pub fn enable_speaker_outputs(&self, enabled: bool) -> Result<(), anyhow::Error> {
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
-        i2c.write(0x42, &[0x0, enabled as u8], BLOCK)?;
-        i2c.write(0x42, &[0x2, enabled as u8], BLOCK)?;
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        for register in EXPANDER_AMP_ENABLE {
+            i2c.write(self.expander, &[register, enabled as u8], BLOCK)?;
+        }
         std::thread::sleep(Duration::from_millis(300));
         Ok(())
     }
 
     pub fn mute_speaker_outputs(&self, muted: bool) -> Result<(), anyhow::Error> {
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
-        i2c.write(0x42, &[0x4, muted as u8], BLOCK)?;
-        i2c.write(0x42, &[0x5, muted as u8], BLOCK)?;
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        for register in EXPANDER_AMP_MUTE {
+            i2c.write(self.expander, &[register, muted as u8], BLOCK)?;
+        }
         std::thread::sleep(Duration::from_millis(1));
         Ok(())
     }
 
+    /// Mute state of amplifier 1, both are always switched together
     pub fn speakers_muted(&self) -> Result<bool, anyhow::Error> {
-        let mut i2c = self.i2c.lock().expect("Failed to lock I2C driver");
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
         let mut buffer = [0; 1];
-        i2c.write_read(0x42, &[0x4], &mut buffer, BLOCK)?;
+        i2c.write_read(self.expander, &[EXPANDER_AMP_MUTE[0]], &mut buffer, BLOCK)?;
         Ok(buffer[0] != 0)
     }
+
+    /// FAULTZ of the amplifiers as sampled by the RP2040.
+    /// Bit n is set while amplifier n reports overcurrent, DC or over-temperature.
+    /// Bits above the amplifiers mean the firmware does not have the register.
+    pub fn fault_status(&self) -> Result<u8, anyhow::Error> {
+        let mut i2c = self.i2c.lock_or_err("Failed to lock I2C driver")?;
+        let mut buffer = [0; 1];
+        i2c.write_read(self.expander, &[EXPANDER_AMP_FAULT], &mut buffer, BLOCK)?;
+        if buffer[0] >> AMP_COUNT != 0 {
+            return Err(anyhow::anyhow!(
+                "Fault register reads 0x{:02X}, not a fault mask",
+                buffer[0]
+            ));
+        }
+        Ok(buffer[0])
+    }
 }
@@ -0,0 +1,273 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::Chip, hardware_context::HardwareContext, linkwitz_riley_coeffs::SecondOrderCoeffs,
+    lock::LockExt, power::PowerState,
+};
+
+const MIN_CROSSOVER_HZ: f32 = 40.0;
+const MAX_CROSSOVER_HZ: f32 = 250.0;
+const MAX_SUBWOOFER_GAIN_DB: f32 = 12.0;
+const MIN_EQ_HZ: f32 = 10.0;
+const MAX_EQ_HZ: f32 = 20_000.0;
+const MAX_EQ_GAIN_DB: f32 = 12.0;
+const MAX_LOUDNESS_BOOST_DB: f32 = 12.0;
+/// Master volume at and above which the loudness adds no boost
+const LOUDNESS_FLAT_DB: f32 = -10.0;
+/// Master volume at and below which the loudness adds its full boost
+const LOUDNESS_FULL_DB: f32 = -50.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CrossoverConfig {
+    /// Linkwitz-Riley crossover frequency between the subwoofer and the main channels
+    pub frequency_hz: f32,
+    /// Gain of the low-pass branch that feeds the subwoofer
+    pub subwoofer_gain_db: f32,
+    /// Inverts the polarity of the subwoofer branch, for a sub placed out of phase
+    #[serde(default)]
+    pub subwoofer_inverted: bool,
+}
+
+impl Default for CrossoverConfig {
+    fn default() -> Self {
+        CrossoverConfig {
+            frequency_hz: 100.0,
+            subwoofer_gain_db: 6.0,
+            subwoofer_inverted: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EqFilter {
+    /// Passes the signal unchanged
+    Flat,
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+/// One second-order EQ stage, designed after the RBJ audio EQ cookbook
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct EqBand {
+    pub filter: EqFilter,
+    pub frequency_hz: f32,
+    /// Only used by the peaking and shelving filters
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl Default for EqBand {
+    /// The subsonic high-pass the DSP program ships with
+    fn default() -> Self {
+        EqBand {
+            filter: EqFilter::HighPass,
+            frequency_hz: 28.0,
+            gain_db: 0.0,
+            q: 0.707,
+        }
+    }
+}
+
+impl EqBand {
+    pub fn coeffs(&self, sample_rate: f64) -> SecondOrderCoeffs {
+        let a = 10f64.powf(self.gain_db as f64 / 40.0);
+        let w0 = 2.0 * PI * self.frequency_hz as f64 / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * self.q as f64);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        // (b0, b1, b2, a0, a1, a2) before normalisation
+        let (b0, b1, b2, a0, a1, a2) = match self.filter {
+            EqFilter::Flat => (1.0, 0.0, 0.0, 1.0, 0.0, 0.0),
+            EqFilter::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            EqFilter::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            EqFilter::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+            EqFilter::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            EqFilter::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+
+        // The DSP adds the feedback terms, so the denominator is stored negated
+        SecondOrderCoeffs {
+            b: [b0 / a0, b1 / a0, b2 / a0],
+            a: [-a1 / a0, -a2 / a0],
+        }
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if !(MIN_EQ_HZ..=MAX_EQ_HZ).contains(&self.frequency_hz) {
+            return Err(anyhow::anyhow!(
+                "EQ frequency must be between {} and {} Hz",
+                MIN_EQ_HZ,
+                MAX_EQ_HZ
+            ));
+        }
+        if self.gain_db.abs() > MAX_EQ_GAIN_DB {
+            return Err(anyhow::anyhow!(
+                "EQ gain must be within ±{} dB",
+                MAX_EQ_GAIN_DB
+            ));
+        }
+        if !(0.1..=10.0).contains(&self.q) {
+            return Err(anyhow::Error::msg("EQ Q must be between 0.1 and 10"));
+        }
+        Ok(())
+    }
+}
+
+/// Raises the subwoofer branch as the master volume drops, the ear loses bass first
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoudnessConfig {
+    pub enabled: bool,
+    /// Boost at and below `LOUDNESS_FULL_DB`, it fades out towards `LOUDNESS_FLAT_DB`
+    pub max_boost_db: f32,
+}
+
+impl LoudnessConfig {
+    /// Subwoofer boost at a master volume
+    pub fn boost_db(&self, volume_db: f32) -> f32 {
+        if !self.enabled {
+            return 0.0;
+        }
+        let depth = (LOUDNESS_FLAT_DB - volume_db) / (LOUDNESS_FLAT_DB - LOUDNESS_FULL_DB);
+        self.max_boost_db * depth.clamp(0.0, 1.0)
+    }
+}
+
+/// Settings of the filter blocks in the DSP program
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct DspConfig {
+    pub crossover: CrossoverConfig,
+    /// The program has a single general filter stage (GENFILTER1) ahead of the crossovers
+    pub eq: EqBand,
+    #[serde(default)]
+    pub loudness: LoudnessConfig,
+}
+
+impl DspConfig {
+    /// The configuration written to the DSP at a master volume: the loudness boost is
+    /// added to the subwoofer gain, limited to the largest gain a user may set
+    pub fn at_volume(&self, volume_db: f32) -> DspConfig {
+        let mut config = *self;
+        config.crossover.subwoofer_gain_db = (self.crossover.subwoofer_gain_db
+            + self.loudness.boost_db(volume_db))
+        .min(MAX_SUBWOOFER_GAIN_DB);
+        config
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let crossover = &self.crossover;
+        if !(MIN_CROSSOVER_HZ..=MAX_CROSSOVER_HZ).contains(&crossover.frequency_hz) {
+            return Err(anyhow::anyhow!(
+                "Crossover frequency must be between {} and {} Hz",
+                MIN_CROSSOVER_HZ,
+                MAX_CROSSOVER_HZ
+            ));
+        }
+        if crossover.subwoofer_gain_db.abs() > MAX_SUBWOOFER_GAIN_DB {
+            return Err(anyhow::anyhow!(
+                "Subwoofer gain must be within ±{} dB",
+                MAX_SUBWOOFER_GAIN_DB
+            ));
+        }
+        if !(0.0..=MAX_LOUDNESS_BOOST_DB).contains(&self.loudness.max_boost_db) {
+            return Err(anyhow::anyhow!(
+                "Loudness boost must be between 0 and {} dB",
+                MAX_LOUDNESS_BOOST_DB
+            ));
+        }
+        self.eq.validate()
+    }
+}
+
+/// Stores the configuration and writes it to the DSP. While the chain is off it is
+/// written by the next power-up.
+pub fn apply(hardware_context: &HardwareContext, config: DspConfig) -> anyhow::Result<()> {
+    *hardware_context
+        .dsp_config
+        .lock_or_err("Could not lock DSP config")? = config;
+
+    if matches!(
+        hardware_context.power.state(),
+        PowerState::Off | PowerState::Booting
+    ) || !hardware_context.has(Chip::Adau1467)
+    {
+        return Ok(());
+    }
+    hardware_context
+        .adau1467
+        .lock_or_err("Could not lock ADAU1467 driver")?
+        .set_dsp_config(&config.at_volume(hardware_context.amp.state().volume_db))
+}
+
+/// The stored configuration as written at the current master volume
+pub fn effective(hardware_context: &HardwareContext) -> anyhow::Result<DspConfig> {
+    let config = *hardware_context
+        .dsp_config
+        .lock_or_err("Could not lock DSP config")?;
+    Ok(config.at_volume(hardware_context.amp.state().volume_db))
+}
+
+/// Rewrites the subwoofer gain after a volume change if the loudness boost changed
+pub fn follow_volume(hardware_context: &HardwareContext, volume_db: f32) -> anyhow::Result<()> {
+    let config = *hardware_context
+        .dsp_config
+        .lock_or_err("Could not lock DSP config")?;
+    if !config.loudness.enabled
+        || matches!(
+            hardware_context.power.state(),
+            PowerState::Off | PowerState::Booting
+        )
+        || !hardware_context.has(Chip::Adau1467)
+    {
+        return Ok(());
+    }
+    let adau1467 = hardware_context
+        .adau1467
+        .lock_or_err("Could not lock ADAU1467 driver")?;
+    let config = config.at_volume(volume_db);
+    if adau1467.dsp_config() == Some(config) {
+        return Ok(());
+    }
+    adau1467.set_dsp_config(&config)
+}
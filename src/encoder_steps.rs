@@ -0,0 +1,144 @@
+/// PCNT counts per mechanical detent, both channels count both edges
+const COUNTS_PER_DETENT: i32 = 4;
+/// Detents further apart than this are single steps
+const SLOW_DETENT_MS: u64 = 120;
+/// Detents this close together get the largest multiplier
+const FAST_DETENT_MS: u64 = 20;
+const MAX_MULTIPLIER: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncoderEvent {
+    Up(u32),
+    Down(u32),
+}
+
+/// Turns the absolute count of `Encoder::get_value` into detent steps with velocity based
+/// acceleration. Holds no hardware, time stamps are passed in.
+pub struct EncoderSteps {
+    last_count: i32,
+    /// Counts not yet making up a whole detent
+    remainder: i32,
+    last_detent_ms: Option<u64>,
+    last_up: bool,
+}
+
+impl EncoderSteps {
+    pub fn new(count: i32) -> Self {
+        EncoderSteps {
+            last_count: count,
+            remainder: 0,
+            last_detent_ms: None,
+            last_up: true,
+        }
+    }
+
+    /// Returns the steps turned since the last call, `now_ms` is a monotonic time in ms
+    pub fn update(&mut self, count: i32, now_ms: u64) -> Option<EncoderEvent> {
+        self.remainder += count - self.last_count;
+        self.last_count = count;
+
+        let detents = self.remainder / COUNTS_PER_DETENT;
+        if detents == 0 {
+            return None;
+        }
+        self.remainder -= detents * COUNTS_PER_DETENT;
+
+        // A change of direction always starts slow
+        let up = detents > 0;
+        let multiplier = match self.last_detent_ms {
+            Some(last) if up == self.last_up => {
+                multiplier(now_ms.saturating_sub(last) / detents.unsigned_abs() as u64)
+            }
+            _ => 1,
+        };
+        self.last_detent_ms = Some(now_ms);
+        self.last_up = up;
+
+        let steps = detents.unsigned_abs() * multiplier;
+        Some(if up {
+            EncoderEvent::Up(steps)
+        } else {
+            EncoderEvent::Down(steps)
+        })
+    }
+}
+
+/// Steps per detent for a time between detents: 1 when turned slowly,
+/// rising linearly up to `MAX_MULTIPLIER` for fast spins
+pub fn multiplier(interval_ms: u64) -> u32 {
+    if interval_ms >= SLOW_DETENT_MS {
+        1
+    } else if interval_ms <= FAST_DETENT_MS {
+        MAX_MULTIPLIER
+    } else {
+        let speed = (SLOW_DETENT_MS - interval_ms) * (MAX_MULTIPLIER - 1) as u64;
+        1 + (speed / (SLOW_DETENT_MS - FAST_DETENT_MS)) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiplier_range() {
+        assert_eq!(multiplier(1000), 1);
+        assert_eq!(multiplier(SLOW_DETENT_MS), 1);
+        assert_eq!(multiplier(SLOW_DETENT_MS - 1), 1);
+        assert_eq!(multiplier(70), 4);
+        assert_eq!(multiplier(FAST_DETENT_MS), MAX_MULTIPLIER);
+        assert_eq!(multiplier(0), MAX_MULTIPLIER);
+    }
+
+    #[test]
+    fn partial_detents_add_up() {
+        let mut steps = EncoderSteps::new(0);
+        assert_eq!(steps.update(2, 0), None);
+        assert_eq!(steps.update(4, 10), Some(EncoderEvent::Up(1)));
+        assert_eq!(steps.update(1, 500), None);
+        assert_eq!(steps.update(0, 510), Some(EncoderEvent::Down(1)));
+    }
+
+    #[test]
+    fn slow_turn_is_single_steps() {
+        let mut steps = EncoderSteps::new(100);
+        for detent in 1..=5 {
+            assert_eq!(
+                steps.update(100 - detent * COUNTS_PER_DETENT, detent as u64 * 200),
+                Some(EncoderEvent::Down(1))
+            );
+        }
+    }
+
+    #[test]
+    fn fast_spin_accelerates() {
+        let mut steps = EncoderSteps::new(0);
+        // The first detent has no previous one to measure against
+        assert_eq!(steps.update(4, 1000), Some(EncoderEvent::Up(1)));
+        assert_eq!(steps.update(8, 1070), Some(EncoderEvent::Up(4)));
+        assert_eq!(
+            steps.update(12, 1080),
+            Some(EncoderEvent::Up(MAX_MULTIPLIER))
+        );
+        // Two detents in one poll, 20 ms apart
+        assert_eq!(
+            steps.update(20, 1120),
+            Some(EncoderEvent::Up(2 * MAX_MULTIPLIER))
+        );
+        // Slowing down drops back to single steps
+        assert_eq!(steps.update(24, 1400), Some(EncoderEvent::Up(1)));
+    }
+
+    #[test]
+    fn direction_change_starts_slow() {
+        let mut steps = EncoderSteps::new(0);
+        steps.update(4, 0);
+        assert_eq!(steps.update(8, 10), Some(EncoderEvent::Up(MAX_MULTIPLIER)));
+        assert_eq!(steps.update(4, 20), Some(EncoderEvent::Down(1)));
+        assert_eq!(
+            steps.update(0, 30),
+            Some(EncoderEvent::Down(MAX_MULTIPLIER))
+        );
+        assert_eq!(steps.update(4, 40), Some(EncoderEvent::Up(1)));
+    }
+}
@@ -0,0 +1,213 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gesture {
+    Short,
+    Double,
+    Long,
+    /// Repeats every `repeat_ms` while the button stays held after a long press
+    Repeat,
+}
+
+/// Which of the optional gestures a button has an action for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BoundGestures {
+    pub double: bool,
+    pub repeat: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GestureTiming {
+    /// The pin has to keep its level this long to count
+    pub debounce_ms: u32,
+    pub long_press_ms: u32,
+    /// Time after a release in which a second press makes a double click
+    pub double_click_ms: u32,
+    pub repeat_ms: u32,
+}
+
+impl Default for GestureTiming {
+    fn default() -> Self {
+        GestureTiming {
+            debounce_ms: 30,
+            long_press_ms: 800,
+            double_click_ms: 300,
+            repeat_ms: 150,
+        }
+    }
+}
+
+/// Recognises gestures from the sampled pin level of one button.
+/// Holds no hardware, the level and a monotonic time are passed in on every poll.
+#[derive(Default)]
+pub struct GestureDetector {
+    raw: bool,
+    raw_since_ms: u64,
+    /// Debounced level
+    pressed: bool,
+    pressed_at_ms: u64,
+    long_fired: bool,
+    last_repeat_ms: u64,
+    /// Release of a click that may still become a double click
+    pending_click_ms: Option<u64>,
+    /// The current press was the second click, its release is not a click of its own
+    second_click: bool,
+}
+
+impl GestureDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds one sample. Short presses are delayed by the double click window
+    /// only when the button has a double click action.
+    pub fn update(
+        &mut self,
+        raw: bool,
+        now_ms: u64,
+        timing: &GestureTiming,
+        bound: BoundGestures,
+    ) -> Option<Gesture> {
+        if raw != self.raw {
+            self.raw = raw;
+            self.raw_since_ms = now_ms;
+        }
+        let stable = now_ms - self.raw_since_ms >= timing.debounce_ms as u64;
+
+        if stable && self.raw != self.pressed {
+            self.pressed = self.raw;
+            return if self.pressed {
+                self.press(now_ms)
+            } else {
+                self.release(now_ms, bound)
+            };
+        }
+
+        if self.pressed {
+            let held_ms = now_ms - self.pressed_at_ms;
+            if !self.long_fired && !self.second_click && held_ms >= timing.long_press_ms as u64 {
+                self.long_fired = true;
+                self.last_repeat_ms = now_ms;
+                return Some(Gesture::Long);
+            }
+            if self.long_fired
+                && bound.repeat
+                && now_ms - self.last_repeat_ms >= timing.repeat_ms as u64
+            {
+                self.last_repeat_ms = now_ms;
+                return Some(Gesture::Repeat);
+            }
+        } else if let Some(released_ms) = self.pending_click_ms {
+            if now_ms - released_ms >= timing.double_click_ms as u64 {
+                self.pending_click_ms = None;
+                return Some(Gesture::Short);
+            }
+        }
+        None
+    }
+
+    fn press(&mut self, now_ms: u64) -> Option<Gesture> {
+        self.pressed_at_ms = now_ms;
+        self.long_fired = false;
+        if self.pending_click_ms.take().is_some() {
+            self.second_click = true;
+            return Some(Gesture::Double);
+        }
+        None
+    }
+
+    fn release(&mut self, now_ms: u64, bound: BoundGestures) -> Option<Gesture> {
+        if self.long_fired || self.second_click {
+            self.second_click = false;
+            return None;
+        }
+        if !bound.double {
+            return Some(Gesture::Short);
+        }
+        self.pending_click_ms = Some(now_ms);
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Main loop period of the control task
+    const POLL_MS: u64 = 20;
+
+    /// Polls a detector until `end_ms`. `presses` are (pressed at, released at) in ms.
+    fn gestures(presses: &[(u64, u64)], end_ms: u64, bound: BoundGestures) -> Vec<(u64, Gesture)> {
+        let timing = GestureTiming::default();
+        let mut detector = GestureDetector::new();
+        (0..=end_ms)
+            .step_by(POLL_MS as usize)
+            .filter_map(|now_ms| {
+                let raw = presses
+                    .iter()
+                    .any(|&(down, up)| (down..up).contains(&now_ms));
+                detector
+                    .update(raw, now_ms, &timing, bound)
+                    .map(|gesture| (now_ms, gesture))
+            })
+            .collect()
+    }
+
+    const ONLY_SHORT: BoundGestures = BoundGestures {
+        double: false,
+        repeat: false,
+    };
+    const ALL: BoundGestures = BoundGestures {
+        double: true,
+        repeat: true,
+    };
+
+    #[test]
+    fn short_press_fires_on_release() {
+        assert_eq!(
+            gestures(&[(100, 300)], 1000, ONLY_SHORT),
+            [(340, Gesture::Short)]
+        );
+    }
+
+    #[test]
+    fn bounces_shorter_than_the_debounce_are_ignored() {
+        assert_eq!(gestures(&[(100, 120), (200, 220)], 1000, ALL), []);
+    }
+
+    #[test]
+    fn short_press_waits_for_a_double_click_only_if_bound() {
+        let bound = BoundGestures {
+            double: true,
+            repeat: false,
+        };
+        assert_eq!(
+            gestures(&[(100, 300)], 1000, bound),
+            [(640, Gesture::Short)]
+        );
+    }
+
+    #[test]
+    fn second_click_is_a_double_and_nothing_else() {
+        assert_eq!(
+            gestures(&[(100, 200), (300, 400)], 1500, ALL),
+            [(340, Gesture::Double)]
+        );
+    }
+
+    #[test]
+    fn long_press_repeats_while_held_if_bound() {
+        let fired = gestures(&[(100, 1300)], 2000, ALL);
+        assert_eq!(fired[0], (940, Gesture::Long));
+        assert!(fired[1..]
+            .iter()
+            .all(|&(_, gesture)| gesture == Gesture::Repeat));
+        // 940 to 1320 in 150 ms steps, the release ends it without a short press
+        assert_eq!(fired.len(), 3);
+
+        assert_eq!(
+            gestures(&[(100, 1300)], 2000, ONLY_SHORT),
+            [(940, Gesture::Long)]
+        );
+    }
+}
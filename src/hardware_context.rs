@@ -2,36 +2,137 @@ use std::sync::{Arc, Mutex};
 
 use esp_idf_svc::hal::i2c::I2cDriver;
 
-use crate::drivers::{
-    adau1467::ADAU1467,
-    adau1962a::{ADAU1962A},
-    pcm1865::{PCM1865},
-    tpa3116d2::TPA3116D2,
+use crate::{
+    amp_fault_monitor::{FaultConfig, FaultStatus},
+    amp_state::AmpBus,
+    asrc_monitor::InputStatus,
+    board::{BoardProfile, BoardStore, Chip},
+    buttons::ButtonConfig,
+    clip_monitor::{ClipConfig, ClipStats},
+    clock_plan::ClockPlan,
+    drivers::{
+        adau1467::ADAU1467,
+        adau1962a::{TdmConfig, ADAU1962A},
+        pcm1865::{PCM1865},
+        tpa3116d2::TPA3116D2,
+    },
+    dsp_config::DspConfig,
+    input_selector::InputSelector,
+    ir_remote::IrRemote,
+    led_manager::LedConfig,
+    lock::LockExt,
+    notifications::Notifier,
+    power::PowerManager,
+    presets::PresetStore,
+    self_test::SelfTestReport,
+    settings::{ChannelTrims, SettingsStore},
+    standby::StandbyConfig,
+    supervisor::TaskStatus,
+    thermal_monitor::{ThermalConfig, ThermalStatus},
+    volume_ramp::VolumeRamp,
 };
 
 #[allow(unused)]
 pub struct HardwareContext<'a> {
+    pub board: BoardProfile,
+    pub board_store: Mutex<BoardStore>,
     pub i2c: Arc<Mutex<I2cDriver<'a>>>,
     pub pcm1865: Mutex<PCM1865<'a>>,
     pub adau1467: Mutex<ADAU1467<'a>>,
     pub adau1962a: Mutex<ADAU1962A<'a>>,
     pub tpa3116d2: Mutex<TPA3116D2<'a>>,
+    pub input_status: Mutex<InputStatus>,
+    pub notifier: Notifier,
+    pub standby_config: Mutex<StandbyConfig>,
+    pub power: PowerManager,
+    pub input_selector: InputSelector,
+    pub clip_config: Mutex<ClipConfig>,
+    pub clip_stats: Mutex<ClipStats>,
+    pub clock_plan: Mutex<ClockPlan>,
+    pub volume_ramp: VolumeRamp,
+    pub thermal_config: Mutex<ThermalConfig>,
+    pub thermal_status: Mutex<ThermalStatus>,
+    pub fault_config: Mutex<FaultConfig>,
+    pub fault_status: Mutex<FaultStatus>,
+    pub button_config: Mutex<ButtonConfig>,
+    pub led_config: Mutex<LedConfig>,
+    pub ir_remote: IrRemote,
+    pub amp: AmpBus,
+    pub tasks: Mutex<Vec<TaskStatus>>,
+    pub settings: SettingsStore,
+    pub dsp_config: Mutex<DspConfig>,
+    pub channel_trims: Mutex<ChannelTrims>,
+    pub tdm_config: Mutex<TdmConfig>,
+    pub presets: PresetStore,
+    pub self_test: Mutex<SelfTestReport>,
 }
 
 impl<'a> HardwareContext<'a> {
     //pub fn new(i2c: I2cDriver<'a>, pcm1865: PCM1865<'a>, adau1467: ADAU1467<'a>, adau1962a: ADAU1962A<'a>, tpa3116d2: TPA3116D2<'a>) -> HardwareContext<'a> {
-    pub fn new(i2c: Arc<Mutex<I2cDriver<'a>>>) -> HardwareContext<'a> {
-        let pcm1865 = Mutex::new(PCM1865::new(i2c.clone(), 0x4a));
-        let adau1962a = Mutex::new(ADAU1962A::new(i2c.clone(), 0x04));
-        let adau1467 = Mutex::new(ADAU1467::new(i2c.clone(), 0x38));
-        let tpa3116d2 = Mutex::new(TPA3116D2::new(i2c.clone()));
+    pub fn new(
+        board: BoardProfile,
+        board_store: BoardStore,
+        i2c: Arc<Mutex<I2cDriver<'a>>>,
+        input_selector: InputSelector,
+        ir_remote: IrRemote,
+        settings: SettingsStore,
+        presets: PresetStore,
+    ) -> HardwareContext<'a> {
+        let devices = board.devices;
+        let expander = devices.expander_address();
+        let pcm1865 = Mutex::new(PCM1865::new(i2c.clone(), devices.pcm1865.address));
+        let adau1962a = Mutex::new(ADAU1962A::new(
+            i2c.clone(),
+            devices.adau1962a.address,
+            expander,
+        ));
+        let adau1467 = Mutex::new(ADAU1467::new(
+            i2c.clone(),
+            devices.adau1467.address,
+            expander,
+        ));
+        let tpa3116d2 = Mutex::new(TPA3116D2::new(i2c.clone(), devices.expander.address));
+
+        let stored = settings.stored();
+        let amp = AmpBus::new(input_selector.selection(), stored.volume_db);
 
         HardwareContext {
+            board,
+            board_store: Mutex::new(board_store),
             i2c,
             pcm1865,
             adau1467,
             adau1962a,
             tpa3116d2,
+            input_status: Mutex::new(InputStatus::default()),
+            notifier: Notifier::new(),
+            standby_config: Mutex::new(StandbyConfig::default()),
+            power: PowerManager::new(stored.muted),
+            input_selector,
+            clip_config: Mutex::new(ClipConfig::default()),
+            clip_stats: Mutex::new(ClipStats::default()),
+            clock_plan: Mutex::new(ClockPlan::default()),
+            volume_ramp: VolumeRamp::new(),
+            thermal_config: Mutex::new(ThermalConfig::default()),
+            thermal_status: Mutex::new(ThermalStatus::default()),
+            fault_config: Mutex::new(FaultConfig::default()),
+            fault_status: Mutex::new(FaultStatus::default()),
+            button_config: Mutex::new(ButtonConfig::default()),
+            led_config: Mutex::new(stored.led),
+            ir_remote,
+            amp,
+            tasks: Mutex::new(Vec::new()),
+            settings,
+            dsp_config: Mutex::new(stored.dsp),
+            channel_trims: Mutex::new(stored.trims),
+            tdm_config: Mutex::new(stored.tdm),
+            presets,
+            self_test: Mutex::new(SelfTestReport::default()),
         }
     }
+
+    /// Whether the board profile lists `chip` as fitted and it passed the self-test
+    pub fn has(&self, chip: Chip) -> bool {
+        self.board.devices.get(chip).fitted && self.self_test.lock_value().passed(chip)
+    }
 }
@@ -1,65 +1,68 @@
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use esp_idf_svc::hal::gpio::{AnyIOPin, AnyOutputPin, Input, InterruptType, PinDriver, Pull};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyOutputPin, Input, PinDriver, Pull};
 use esp_idf_svc::hal::ledc::config::TimerConfig;
 use esp_idf_svc::hal::ledc::{LedcDriver, LedcTimerDriver, LEDC};
-use esp_idf_svc::hal::pcnt::Pcnt;
-use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::pcnt::PCNT0;
 
+use crate::amp_state::AmpCommand;
+use crate::buttons::{self, ButtonAction, ButtonBindings};
 use crate::encoder::Encoder;
+use crate::encoder_steps::{EncoderEvent, EncoderSteps};
+use crate::gestures::{GestureDetector, GestureTiming};
 use crate::hardware_context::HardwareContext;
-use crate::sticky_limiter::StickyLimiter;
+use crate::led_manager::{LedManager, Rgb};
+use crate::lock::LockExt;
 
-const INITIAL_VOLUME_OFFSET: i32 = 66;
-const BUTTON_DEBOUNCE_DELAY_MS: u64 = 500;
+/// Volume change per encoder step, fast spins take several steps per detent
+const VOLUME_STEP_DB: f32 = 0.5;
 const MAIN_LOOP_DELAY_MS: u64 = 20;
 
-struct Button {
-    driver: Arc<Mutex<PinDriver<'static, AnyIOPin, Input>>>,
-    pressed_flag: Arc<AtomicBool>,
+/// Front panel pins and peripherals. The control task borrows them so a restart can
+/// create the drivers again.
+pub struct ControlPeripherals {
+    pub encoder_a: AnyIOPin,
+    pub encoder_b: AnyIOPin,
+    pub button_mute: AnyIOPin,
+    pub button_bassboost: AnyIOPin,
+    pub button_standby: AnyIOPin,
+    pub led_red: AnyOutputPin,
+    pub led_green: AnyOutputPin,
+    pub led_blue: AnyOutputPin,
+    pub ledc: LEDC,
+    pub pcnt: PCNT0,
 }
 
-impl Button {
-    fn new(
-        pin: AnyIOPin,
-        pull: Pull,
-        interrupt_type: InterruptType,
-        pressed_flag: Arc<AtomicBool>,
-    ) -> anyhow::Result<Self> {
-        let driver = Arc::new(Mutex::new(PinDriver::input(pin)?));
-        driver.lock().unwrap().set_pull(pull)?;
-
-        let pressed_flag_clone = Arc::clone(&pressed_flag);
-        unsafe {
-            driver.lock().unwrap().subscribe(move || {
-                pressed_flag_clone.store(true, Ordering::SeqCst);
-            })?;
-        }
+struct Button<'d> {
+    name: &'static str,
+    driver: PinDriver<'d, AnyIOPin, Input>,
+    detector: GestureDetector,
+}
 
-        driver.lock().unwrap().set_interrupt_type(interrupt_type)?;
-        driver.lock().unwrap().enable_interrupt()?;
+impl<'d> Button<'d> {
+    fn new(name: &'static str, pin: &'d mut AnyIOPin, pull: Pull) -> anyhow::Result<Self> {
+        let mut driver = PinDriver::input(pin)?;
+        driver.set_pull(pull)?;
 
         Ok(Self {
+            name,
             driver,
-            pressed_flag,
+            detector: GestureDetector::new(),
         })
     }
 
-    fn handle_press<F>(&self, callback: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        if self.pressed_flag.load(Ordering::SeqCst) {
-            self.pressed_flag.store(false, Ordering::SeqCst);
-            callback();
-
-            let driver = Arc::clone(&self.driver);
-            std::thread::spawn(move || {
-                std::thread::sleep(Duration::from_millis(BUTTON_DEBOUNCE_DELAY_MS));
-                driver.lock().unwrap().enable_interrupt().ok();
-            });
-        }
+    /// Samples the pin once per main loop pass, the buttons pull up against the pull-down
+    fn poll(
+        &mut self,
+        now_ms: u64,
+        timing: &GestureTiming,
+        bindings: &ButtonBindings,
+    ) -> Option<ButtonAction> {
+        let gesture = self
+            .detector
+            .update(self.driver.is_high(), now_ms, timing, bindings.bound())?;
+        log::info!("{} button: {:?}", self.name, gesture);
+        Some(bindings.action(gesture))
     }
 }
 
@@ -71,20 +74,24 @@ struct LedController<'a> {
 
 impl<'a> LedController<'a> {
     fn new(
-        ledc: LEDC,
-        red_pin: AnyOutputPin,
-        green_pin: AnyOutputPin,
-        blue_pin: AnyOutputPin,
+        ledc: &'a mut LEDC,
+        red_pin: &'a mut AnyOutputPin,
+        green_pin: &'a mut AnyOutputPin,
+        blue_pin: &'a mut AnyOutputPin,
     ) -> anyhow::Result<Self> {
-        let timer = LedcTimerDriver::new(ledc.timer0, &TimerConfig::default())?;
+        let timer = LedcTimerDriver::new(&mut ledc.timer0, &TimerConfig::default())?;
 
-        let red = LedcDriver::new(ledc.channel0, &timer, red_pin)?;
-        let green = LedcDriver::new(ledc.channel1, &timer, green_pin)?;
-        let blue = LedcDriver::new(ledc.channel2, &timer, blue_pin)?;
+        let red = LedcDriver::new(&mut ledc.channel0, &timer, red_pin)?;
+        let green = LedcDriver::new(&mut ledc.channel1, &timer, green_pin)?;
+        let blue = LedcDriver::new(&mut ledc.channel2, &timer, blue_pin)?;
 
         Ok(Self { red, green, blue })
     }
 
+    fn set_color(&mut self, color: Rgb) -> anyhow::Result<()> {
+        self.set_rgb(color.r, color.g, color.b)
+    }
+
     fn set_rgb(&mut self, r: u8, g: u8, b: u8) -> anyhow::Result<()> {
         self.red.set_duty(self.red.get_max_duty() * r as u32 / 255)?;
         self.green.set_duty(self.green.get_max_duty() * g as u32 / 255)?;
@@ -93,86 +100,82 @@ impl<'a> LedController<'a> {
     }
 }
 
-fn main_loop(
-    encoder: Encoder,
+fn main_loop<'d>(
+    encoder: Encoder<'d>,
+    mut led_controller: LedController<'d>,
     hardware_context: Arc<HardwareContext<'static>>,
-    button_mute: Button,
-    button_bassboost: Button,
-    button_standby: Button,
+    mut button_mute: Button<'d>,
+    mut button_bassboost: Button<'d>,
+    mut button_standby: Button<'d>,
 ) -> anyhow::Result<()> {
-    let mut last_value = INITIAL_VOLUME_OFFSET;
-    let mut volume = StickyLimiter::new(0, 100);
+    let start = Instant::now();
+    let mut steps = EncoderSteps::new(encoder.get_value()?);
+    let mut led_manager = LedManager::new(&hardware_context);
+    let mut led_color = Rgb::OFF;
 
     loop {
-        let value = encoder.get_value()? + INITIAL_VOLUME_OFFSET;
-        if value != last_value {
-            last_value = value;
-            let volume = volume.update(value);
-            log::info!("value: {value} volume: {volume}");
-            hardware_context.adau1962a.lock().unwrap().set_master_volume(volume as u8)?;
+        let now_ms = start.elapsed().as_millis() as u64;
+        if let Some(event) = steps.update(encoder.get_value()?, now_ms) {
+            let delta_db = match event {
+                EncoderEvent::Up(steps) => steps as f32 * VOLUME_STEP_DB,
+                EncoderEvent::Down(steps) => -(steps as f32) * VOLUME_STEP_DB,
+            };
+            log::info!("encoder: {event:?}");
+            hardware_context
+                .amp
+                .send(AmpCommand::StepVolume { db: delta_db });
         }
 
-        let hardware_context = hardware_context.clone();
-        button_mute.handle_press(move || {
-            log::info!("mute pressed");
-            let tpa3116d2 = hardware_context.tpa3116d2.lock().unwrap();
-            let speakers_muted = tpa3116d2.speakers_muted().unwrap();
-            tpa3116d2.mute_speaker_outputs(!speakers_muted).unwrap();
-        });
-
-        button_bassboost.handle_press(|| {
-            log::info!("bassboost pressed");
-        });
+        let config = *hardware_context.button_config.lock_value();
+        let buttons = [
+            (&mut button_mute, &config.mute),
+            (&mut button_bassboost, &config.bassboost),
+            (&mut button_standby, &config.standby),
+        ];
+        for (button, bindings) in buttons {
+            let Some(action) = button.poll(now_ms, &config.timing, bindings) else {
+                continue;
+            };
+            buttons::run_action(&hardware_context, action);
+        }
 
-        button_standby.handle_press(|| {
-            log::info!("standby pressed");
-        });
+        let color = led_manager.update(&hardware_context, now_ms);
+        if color != led_color {
+            led_controller.set_color(color)?;
+            led_color = color;
+        }
 
         std::thread::sleep(Duration::from_millis(MAIN_LOOP_DELAY_MS));
     }
 }
 
 pub fn hardware_control(
-    encoder_pin_a: AnyIOPin,
-    encoder_pin_b: AnyIOPin,
-    button_pin_1: AnyIOPin,
-    button_pin_2: AnyIOPin,
-    button_pin_3: AnyIOPin,
-    led_pin_red: AnyOutputPin,
-    led_pin_green: AnyOutputPin,
-    led_pin_blue: AnyOutputPin,
-    ledc: LEDC,
-    pcnt: impl Peripheral<P = impl Pcnt>,
+    peripherals: &mut ControlPeripherals,
     hardware_context: Arc<HardwareContext<'static>>,
 ) -> anyhow::Result<()> {
     log::info!("Hardware control thread started");
 
-    let button_mute = Button::new(
-        button_pin_1,
-        Pull::Down,
-        InterruptType::PosEdge,
-        Arc::new(AtomicBool::new(false)),
-    )?;
-    let button_bassboost = Button::new(
-        button_pin_2,
-        Pull::Down,
-        InterruptType::PosEdge,
-        Arc::new(AtomicBool::new(false)),
-    )?;
-    let button_standby = Button::new(
-        button_pin_3,
-        Pull::Down,
-        InterruptType::PosEdge,
-        Arc::new(AtomicBool::new(false)),
-    )?;
+    let button_mute = Button::new("mute", &mut peripherals.button_mute, Pull::Down)?;
+    let button_bassboost = Button::new("bassboost", &mut peripherals.button_bassboost, Pull::Down)?;
+    let button_standby = Button::new("standby", &mut peripherals.button_standby, Pull::Down)?;
 
-    let mut led_controller = LedController::new(ledc, led_pin_red, led_pin_green, led_pin_blue)?;
-    led_controller.set_rgb(150, 255, 200)?;
+    let mut led_controller = LedController::new(
+        &mut peripherals.ledc,
+        &mut peripherals.led_red,
+        &mut peripherals.led_green,
+        &mut peripherals.led_blue,
+    )?;
+    led_controller.set_color(Rgb::OFF)?;
 
-    let encoder = Encoder::new(pcnt, encoder_pin_a, encoder_pin_b).unwrap();
+    let encoder = Encoder::new(
+        &mut peripherals.pcnt,
+        &mut peripherals.encoder_a,
+        &mut peripherals.encoder_b,
+    )?;
 
     main_loop(
         encoder,
+        led_controller,
         hardware_context.clone(),
         button_mute,
         button_bassboost,
@@ -1,83 +1,217 @@
-use std::sync::Arc;
+use std::time::Duration;
 
 
 use crate::{
+    board::Chip,
+    clock_plan::ClockPlan,
     drivers::{
         adau1467::ADAU1467,
-        adau1962a::{self, ADAU1962A},
-        pcm1865::{self, AdcChannel, AdcInput, AdcNumber, PCM1865},
+        adau1962a::{self, TdmConfig, Volume, ADAU1962A},
+        pcm1865::{self, PCM1865},
         tpa3116d2::TPA3116D2,
     },
+    dsp_config::{self, DspConfig},
     hardware_context::HardwareContext,
+    input_selector::{self, InputSource},
+    lock::LockExt,
+    self_test,
+    settings::ChannelTrims,
+    volume_ramp,
 };
 
-pub fn hardware_init(hardware_context: Arc<HardwareContext<'_>>) -> anyhow::Result<()> {
-    setup_pcm1865(
-        &mut hardware_context
-            .pcm1865
-            .lock()
-            .expect("Could not lock PCM1865 driver"),
-    )?;
-    setup_adau1962a(
-        &mut hardware_context
+/// Time the PCM1865 clock detector needs before its status registers are valid
+const PCM1865_CLOCK_SETTLE_MS: u64 = 50;
+
+/// Powers the chain up in order: PCM1865, ADAU1962A, ADAU1467, TPA3116D2.
+/// Called through `power::request` so the power state follows the sequence.
+/// With `muted` the amplifier comes up muted instead of fading in.
+pub fn hardware_init(hardware_context: &HardwareContext, muted: bool) -> anyhow::Result<()> {
+    let clock_plan = *hardware_context
+        .clock_plan
+        .lock_or_err("Could not lock clock plan")?;
+    let dsp_config = dsp_config::effective(hardware_context)?;
+    let trims = *hardware_context
+        .channel_trims
+        .lock_or_err("Could not lock channel trims")?;
+    let tdm_config = *hardware_context
+        .tdm_config
+        .lock_or_err("Could not lock TDM config")?;
+
+    // Chips the board does not have are skipped, the rest of the chain still comes up
+    if hardware_context.has(Chip::Pcm1865) {
+        setup_pcm1865(
+            &mut *hardware_context
+                .pcm1865
+                .lock_or_err("Could not lock PCM1865 driver")?,
+            &clock_plan,
+            hardware_context.input_selector.selection().source,
+        )?;
+    }
+    if hardware_context.has(Chip::Adau1962a) {
+        setup_adau1962a(
+            &mut *hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?,
+            &clock_plan,
+            &trims,
+            tdm_config,
+        )?;
+    }
+    if hardware_context.has(Chip::Adau1467) {
+        setup_adau1467(
+            &mut *hardware_context
+                .adau1467
+                .lock_or_err("Could not lock ADAU1467 driver")?,
+            &clock_plan,
+            &dsp_config,
+        )?;
+        self_test::check_dsp_program(hardware_context);
+    }
+    if hardware_context.has(Chip::Adau1467) && hardware_context.has(Chip::Adau1962a) {
+        let adau1467 = hardware_context
+            .adau1467
+            .lock_or_err("Could not lock ADAU1467 driver")?;
+        let tdm_config = hardware_context
             .adau1962a
-            .lock()
-            .expect("Could not lock ADAU1962a driver"),
-    )?;
+            .lock_or_err("Could not lock ADAU1962a driver")?
+            .tdm_config();
+        if let Err(e) = tdm_config.check_link(&adau1467) {
+            log::error!("DSP to DAC link mismatch: {:?}", e);
+        }
+    }
+    if hardware_context.has(Chip::Expander) {
+        setup_tpa3116d2(
+            &mut *hardware_context
+                .tpa3116d2
+                .lock_or_err("Could not lock TPA3116d2 driver")?,
+        )?;
+    }
+
+    let volume = hardware_context.amp.state().volume();
+    if muted && hardware_context.has(Chip::Expander) {
+        // Silent behind the muted amp, unmuting fades in to the volume
+        hardware_context
+            .tpa3116d2
+            .lock_or_err("Could not lock TPA3116d2 driver")?
+            .mute_speaker_outputs(true)?;
+        if hardware_context.has(Chip::Adau1962a) {
+            hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?
+                .step_master_attenuation(volume)?;
+        }
+    } else {
+        // The self-test or the last power-down left the amp muted, the DAC starts silent
+        if hardware_context.has(Chip::Expander) {
+            hardware_context
+                .tpa3116d2
+                .lock_or_err("Could not lock TPA3116d2 driver")?
+                .mute_speaker_outputs(false)?;
+        }
+        volume_ramp::fade_in(hardware_context, volume)?;
+    }
+
+    Ok(())
+}
+
+/// Downloads the DSP program again. The output is faded out while the DSP restarts.
+pub fn reload_dsp_program(hardware_context: &HardwareContext) -> Result<(), anyhow::Error> {
+    if !hardware_context.has(Chip::Adau1467) {
+        return Err(anyhow::Error::msg(
+            "The ADAU1467 is not fitted or failed the self-test",
+        ));
+    }
+    let volume = volume_ramp::fade_out(hardware_context)?;
+
+    let clock_plan = *hardware_context
+        .clock_plan
+        .lock_or_err("Could not lock clock plan")?;
+    let dsp_config = dsp_config::effective(hardware_context)?;
     setup_adau1467(
-        &mut hardware_context
+        &mut *hardware_context
             .adau1467
-            .lock()
-            .expect("Could not lock ADAU1467 driver"),
-    )?;
-    setup_tpa3116d2(
-        &mut hardware_context
-            .tpa3116d2
-            .lock()
-            .expect("Could not lock TPA3116d2 driver"),
+            .lock_or_err("Could not lock ADAU1467 driver")?,
+        &clock_plan,
+        &dsp_config,
     )?;
+    self_test::check_dsp_program(hardware_context);
 
-    Ok(())
+    volume_ramp::fade_in(hardware_context, volume)
 }
 
-fn setup_pcm1865(pcm1865: &mut PCM1865) -> Result<(), anyhow::Error> {
+fn setup_pcm1865(
+    pcm1865: &mut PCM1865,
+    clock_plan: &ClockPlan,
+    source: InputSource,
+) -> Result<(), anyhow::Error> {
     log::info!("Setting up PCM1865");
 
+    pcm1865.set_power_down(false)?;
     pcm1865.set_sck_xtal_selection(pcm1865::SckXtalSelection::Xtal)?;
     pcm1865.select_mode(true)?;
-    pcm1865.master_clock_to_bit_clock_divider_value(2)?; // 12.228 Mhz BCLK
-    pcm1865.bit_clock_to_left_right_clock_divider_value(64)?;
+    clock_plan.apply_pcm1865(pcm1865)?;
     pcm1865.auto_clock_detector_configuration(true)?;
-    pcm1865.set_adc_input(AdcNumber::Adc1, AdcChannel::Left, AdcInput::Vin4PlusVin3PlusVin2PlusVin1, false)?;
-    pcm1865.set_adc_input(AdcNumber::Adc1, AdcChannel::Right, AdcInput::Vin4PlusVin3PlusVin2PlusVin1, false)?;
+    input_selector::apply_source(pcm1865, source)?;
+    check_pcm1865(pcm1865)?;
+    Ok(())
+}
+
+/// Verifies that the PCM1865 detected valid clocks after configuration.
+/// A failed check is only logged, the ADC may still lock once the clocks settle.
+fn check_pcm1865(pcm1865: &PCM1865) -> Result<(), anyhow::Error> {
+    std::thread::sleep(Duration::from_millis(PCM1865_CLOCK_SETTLE_MS));
+
+    let status = pcm1865.status()?;
+    if status.is_healthy() {
+        log::info!("PCM1865 running: {:?}", status);
+    } else {
+        log::error!("PCM1865 sanity check failed: {:?}", status);
+    }
+
     Ok(())
 }
 
-fn setup_adau1962a(adau1962a: &mut ADAU1962A) -> Result<(), anyhow::Error> {
+fn setup_adau1962a(
+    adau1962a: &mut ADAU1962A,
+    clock_plan: &ClockPlan,
+    trims: &ChannelTrims,
+    tdm_config: TdmConfig,
+) -> Result<(), anyhow::Error> {
     log::info!("Setting up ADAU1962a");
 
     adau1962a.set_reset(true)?;
     adau1962a.master_power_up(true)?;
     adau1962a.set_xtal_oscillator_enabled(true)?;
     adau1962a.set_pll_input_source(adau1962a::MclkiXtaliOrDlrclkSelection::MclkiOrXtali)?;
-    adau1962a.set_master_clock_fs_ratio(adau1962a::MasterClockFsRatio::Ratio512)?;
     adau1962a.set_dac_clock_select(adau1962a::DacClockSource::PLL)?;
-    adau1962a.set_serial_audio_interface(adau1962a::AudioInterface::Stero)?;
-    adau1962a.set_sample_rate_selection(adau1962a::SampleRate::FS192)?;
-    adau1962a.set_dlrclk_polarity(false)?;
+    // Checked against the DSP once its program runs
+    adau1962a.write_tdm_config(tdm_config)?;
+    clock_plan.apply_adau1962a(adau1962a)?;
     adau1962a.set_serial_interface_master(true)?;
-    adau1962a.set_master_volume(66)?;
+    // The reset turned it off, the thermal monitor reads it
+    adau1962a.enable_temperature_sensor(true)?;
+    for (channel, db) in (1..=adau1962a::DAC_CHANNEL_COUNT).zip(trims) {
+        adau1962a.set_channel_volume(channel, Volume::from_db(*db))?;
+    }
+    // Start silent, hardware_init fades in once the amplifier is running
+    adau1962a.step_master_attenuation(Volume::MUTE)?;
     adau1962a.set_master_mute(false)?;
 
     Ok(())
 }
 
-fn setup_adau1467(adau1467: &mut ADAU1467) -> Result<(), anyhow::Error> {
+fn setup_adau1467(
+    adau1467: &mut ADAU1467,
+    clock_plan: &ClockPlan,
+    dsp_config: &DspConfig,
+) -> Result<(), anyhow::Error> {
     log::info!("Setting up ADAU1467");
 
     adau1467.set_reset(true)?;
     adau1467.load_dsp_program()?;
-    adau1467.set_subwoofer_gain(6.0)?;
+    // The program download sets the clock generators, override them afterwards
+    clock_plan.apply_adau1467(adau1467)?;
+    adau1467.set_dsp_config(dsp_config)?;
 
     Ok(())
 }
@@ -2,6 +2,8 @@ use esp_idf_svc::hal::{delay::BLOCK, i2c::I2cDriver};
 use log;
 use std::sync::{Arc, Mutex};
 
+use crate::lock::LockExt;
+
 #[allow(unused)]
 pub fn read_registers_16bit_address(
     i2c: &Arc<Mutex<I2cDriver>>,
@@ -9,7 +11,7 @@ pub fn read_registers_16bit_address(
     address: u16,
     register_count: usize,
 ) {
-    let mut i2c = i2c.lock().expect("Failed to lock I2C driver");
+    let mut i2c = i2c.lock_value();
     let mut register_values = Vec::with_capacity(register_count * 4);
     i2c.write_read(
         i2c_device,
@@ -65,7 +67,7 @@ pub fn pretty_register_dump(i2c: &Arc<Mutex<I2cDriver>>) {
         let mut reg_values = Vec::new();
 
         for reg in device.start_reg..=device.end_reg {
-            let mut i2c = i2c.lock().expect("Failed to lock I2C driver");
+            let mut i2c = i2c.lock_value();
 
             let (reg_bytes, mut data): (Vec<u8>, Vec<u8>) = match device.reg_size {
                 1 => (vec![reg as u8], vec![0u8; 1]),
@@ -0,0 +1,205 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_state::AmpCommand,
+    drivers::{
+        adau1467_registers::AsrcSource,
+        pcm1865::{AdcChannel, AdcInput, AdcNumber, PCM1865},
+    },
+    hardware_context::HardwareContext,
+    lock::LockExt,
+};
+
+const POLL_INTERVAL_MS: u64 = 250;
+/// ADAU1467 serial input channel the PCM1865 data arrives on
+const ADC_SERIAL_INPUT_CHANNEL: u8 = 0;
+
+/// Sources auto mode chooses from, the differential inputs share pins with them
+const AUTO_SOURCES: [InputSource; 4] = [
+    InputSource::Analog1,
+    InputSource::Analog2,
+    InputSource::Analog3,
+    InputSource::Analog4,
+];
+
+/// Named inputs of the amp, each mapped onto a PCM1865 ADC1 input selection
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InputSource {
+    Analog1,
+    Analog2,
+    Analog3,
+    Analog4,
+    /// Differential input on VIN1/VIN2
+    Balanced1,
+    /// Differential input on VIN4/VIN3
+    Balanced2,
+    /// All single-ended inputs summed
+    Mix,
+}
+
+impl InputSource {
+    pub fn value(&self) -> u8 {
+        match self {
+            InputSource::Analog1 => 0,
+            InputSource::Analog2 => 1,
+            InputSource::Analog3 => 2,
+            InputSource::Analog4 => 3,
+            InputSource::Balanced1 => 4,
+            InputSource::Balanced2 => 5,
+            InputSource::Mix => 6,
+        }
+    }
+
+    pub fn from_value(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(InputSource::Analog1),
+            1 => Some(InputSource::Analog2),
+            2 => Some(InputSource::Analog3),
+            3 => Some(InputSource::Analog4),
+            4 => Some(InputSource::Balanced1),
+            5 => Some(InputSource::Balanced2),
+            6 => Some(InputSource::Mix),
+            _ => None,
+        }
+    }
+
+    pub fn adc_input(&self) -> AdcInput {
+        match self {
+            InputSource::Analog1 => AdcInput::Vin1,
+            InputSource::Analog2 => AdcInput::Vin2,
+            InputSource::Analog3 => AdcInput::Vin3,
+            InputSource::Analog4 => AdcInput::Vin4,
+            InputSource::Balanced1 => AdcInput::DiffVin1,
+            InputSource::Balanced2 => AdcInput::DiffVin4,
+            InputSource::Mix => AdcInput::Vin4PlusVin3PlusVin2PlusVin1,
+        }
+    }
+
+    /// Bits of the PCM1865 signal detect status that belong to this source
+    pub fn signal_detect_mask(&self) -> u8 {
+        match self {
+            InputSource::Analog1 => 0b0000_0011,
+            InputSource::Analog2 => 0b0000_1100,
+            InputSource::Analog3 => 0b0011_0000,
+            InputSource::Analog4 => 0b1100_0000,
+            InputSource::Balanced1 => 0b0000_1111,
+            InputSource::Balanced2 => 0b1111_0000,
+            InputSource::Mix => 0b1111_1111,
+        }
+    }
+
+    /// Where the DSP receives the source. Every source is converted by the PCM1865, whose
+    /// data arrives on serial input channel 0.
+    pub fn dsp_input(&self) -> (AsrcSource, u8) {
+        (AsrcSource::SerialInput, ADC_SERIAL_INPUT_CHANNEL)
+    }
+
+    /// The next source when cycling through the inputs with the button
+    pub fn next(&self) -> Self {
+        InputSource::from_value(self.value() + 1).unwrap_or(InputSource::Analog1)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InputSelection {
+    pub source: InputSource,
+    /// Follow the most recently active input
+    pub auto: bool,
+}
+
+impl Default for InputSelection {
+    fn default() -> Self {
+        InputSelection {
+            source: InputSource::Mix,
+            auto: false,
+        }
+    }
+}
+
+/// Keeps the selected input, the settings store persists it
+pub struct InputSelector {
+    selection: Mutex<InputSelection>,
+}
+
+impl InputSelector {
+    pub fn new(selection: InputSelection) -> Self {
+        InputSelector {
+            selection: Mutex::new(selection),
+        }
+    }
+
+    pub fn selection(&self) -> InputSelection {
+        *self.selection.lock_value()
+    }
+
+    /// Routes the source to ADC1 and keeps the new selection
+    pub fn select(&self, pcm1865: &PCM1865, selection: InputSelection) -> anyhow::Result<()> {
+        let mut current = self
+            .selection
+            .lock_or_err("Could not lock input selection")?;
+
+        apply_source(pcm1865, selection.source)?;
+        *current = selection;
+        Ok(())
+    }
+}
+
+pub fn apply_source(pcm1865: &PCM1865, source: InputSource) -> anyhow::Result<()> {
+    log::info!("Selecting input {:?}", source);
+    pcm1865.set_adc_input(AdcNumber::Adc1, AdcChannel::Left, source.adc_input(), false)?;
+    pcm1865.set_adc_input(
+        AdcNumber::Adc1,
+        AdcChannel::Right,
+        source.adc_input(),
+        false,
+    )?;
+    Ok(())
+}
+
+/// Switches to an input as soon as a signal starts on it while auto mode is enabled
+pub fn input_monitor(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    log::info!("Input monitor thread started");
+
+    let mut last_status = 0u8;
+
+    loop {
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+        let pcm1865 = hardware_context
+            .pcm1865
+            .lock_or_err("Could not lock PCM1865 driver")?;
+        let status = pcm1865.signal_detect_status()?;
+        let started = status & !last_status;
+        last_status = status;
+
+        let selection = hardware_context.input_selector.selection();
+        if !selection.auto || started == 0 {
+            continue;
+        }
+
+        let Some(source) = AUTO_SOURCES
+            .iter()
+            .copied()
+            .find(|source| started & source.signal_detect_mask() != 0)
+        else {
+            continue;
+        };
+        if source == selection.source {
+            continue;
+        }
+
+        drop(pcm1865);
+        hardware_context
+            .amp
+            .send(AmpCommand::SelectInput(InputSelection {
+                source,
+                auto: true,
+            }));
+    }
+}
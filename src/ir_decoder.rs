@@ -0,0 +1,369 @@
+use serde::{Deserialize, Serialize};
+
+/// Allowed deviation from the nominal timings, receivers stretch marks and shorten spaces
+const TOLERANCE_PERCENT: u32 = 30;
+
+const NEC_LEADER_MARK_US: u32 = 9000;
+const NEC_LEADER_SPACE_US: u32 = 4500;
+const NEC_REPEAT_SPACE_US: u32 = 2250;
+const NEC_BIT_MARK_US: u32 = 560;
+const NEC_ZERO_SPACE_US: u32 = 560;
+const NEC_ONE_SPACE_US: u32 = 1690;
+const NEC_BITS: usize = 32;
+
+const RC5_HALF_BIT_US: u32 = 889;
+const RC5_BITS: usize = 14;
+
+const SIRC_START_MARK_US: u32 = 2400;
+const SIRC_SPACE_US: u32 = 600;
+const SIRC_ZERO_MARK_US: u32 = 600;
+const SIRC_ONE_MARK_US: u32 = 1200;
+const SIRC_BIT_COUNTS: [usize; 3] = [12, 15, 20];
+
+/// One level period of the demodulated receiver output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrPulse {
+    /// Carrier present
+    pub mark: bool,
+    pub us: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IrProtocol {
+    Nec,
+    Rc5,
+    Sirc,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrCode {
+    pub protocol: IrProtocol,
+    pub address: u16,
+    pub command: u16,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IrFrame {
+    Code(IrCode),
+    /// NEC repeat frame, the key of the previous code is still held
+    Repeat,
+}
+
+/// Decodes one frame, the pulses start with the first mark and end at the idle gap
+pub fn decode(pulses: &[IrPulse]) -> Option<IrFrame> {
+    decode_nec(pulses)
+        .or_else(|| decode_rc5(pulses).map(IrFrame::Code))
+        .or_else(|| decode_sirc(pulses).map(IrFrame::Code))
+}
+
+fn near(us: u32, nominal_us: u32) -> bool {
+    us.abs_diff(nominal_us) * 100 <= nominal_us * TOLERANCE_PERCENT
+}
+
+fn is_mark(pulse: &IrPulse, nominal_us: u32) -> bool {
+    pulse.mark && near(pulse.us, nominal_us)
+}
+
+fn is_space(pulse: &IrPulse, nominal_us: u32) -> bool {
+    !pulse.mark && near(pulse.us, nominal_us)
+}
+
+/// NEC: 9 ms leader, 32 bits LSB first as address, inverted address, command, inverted command.
+/// Extended NEC uses the second byte as the high address byte.
+fn decode_nec(pulses: &[IrPulse]) -> Option<IrFrame> {
+    let [leader, gap, bits @ ..] = pulses else {
+        return None;
+    };
+    if !is_mark(leader, NEC_LEADER_MARK_US) {
+        return None;
+    }
+    if is_space(gap, NEC_REPEAT_SPACE_US) {
+        return Some(IrFrame::Repeat);
+    }
+    if !is_space(gap, NEC_LEADER_SPACE_US) {
+        return None;
+    }
+
+    let mut value: u32 = 0;
+    for bit in 0..NEC_BITS {
+        let mark = bits.get(bit * 2)?;
+        let space = bits.get(bit * 2 + 1)?;
+        if !is_mark(mark, NEC_BIT_MARK_US) {
+            return None;
+        }
+        if is_space(space, NEC_ONE_SPACE_US) {
+            value |= 1 << bit;
+        } else if !is_space(space, NEC_ZERO_SPACE_US) {
+            return None;
+        }
+    }
+
+    let [address_low, address_high, command, command_inverted] = value.to_le_bytes();
+    if command != !command_inverted {
+        return None;
+    }
+    let address = if address_high == !address_low {
+        address_low as u16
+    } else {
+        u16::from_le_bytes([address_low, address_high])
+    };
+    Some(IrFrame::Code(IrCode {
+        protocol: IrProtocol::Nec,
+        address,
+        command: command as u16,
+    }))
+}
+
+/// RC5: 14 Manchester coded bits MSB first, start, field, toggle, 5 address and 6 command bits.
+/// A cleared field bit is the seventh command bit of RC5X. The toggle bit is left out of the code.
+fn decode_rc5(pulses: &[IrPulse]) -> Option<IrCode> {
+    // The first half of the start bit is a space that merges into the idle level
+    let mut halves = vec![false];
+    for pulse in pulses {
+        let count = if near(pulse.us, RC5_HALF_BIT_US) {
+            1
+        } else if near(pulse.us, RC5_HALF_BIT_US * 2) {
+            2
+        } else {
+            return None;
+        };
+        halves.extend(std::iter::repeat(pulse.mark).take(count));
+    }
+    // A trailing zero ends in a space half that merges into the idle level
+    if halves.len() == RC5_BITS * 2 - 1 {
+        halves.push(false);
+    }
+    if halves.len() != RC5_BITS * 2 {
+        return None;
+    }
+
+    let mut value: u16 = 0;
+    for half in halves.chunks(2) {
+        let bit = match half {
+            [false, true] => 1,
+            [true, false] => 0,
+            _ => return None,
+        };
+        value = (value << 1) | bit;
+    }
+
+    let field = (value >> 12) & 1;
+    Some(IrCode {
+        protocol: IrProtocol::Rc5,
+        address: (value >> 6) & 0x1F,
+        command: (value & 0x3F) | ((field ^ 1) << 6),
+    })
+}
+
+/// SIRC: 2.4 ms start mark, then bits LSB first coded in the mark length.
+/// 7 command bits followed by 5, 8 or 13 address bits.
+fn decode_sirc(pulses: &[IrPulse]) -> Option<IrCode> {
+    let [start, bits @ ..] = pulses else {
+        return None;
+    };
+    if !is_mark(start, SIRC_START_MARK_US) {
+        return None;
+    }
+
+    let mut value: u32 = 0;
+    let mut count = 0;
+    for pair in bits.chunks_exact(2) {
+        let [space, mark] = pair else {
+            return None;
+        };
+        if !is_space(space, SIRC_SPACE_US) {
+            return None;
+        }
+        if is_mark(mark, SIRC_ONE_MARK_US) {
+            value |= 1 << count;
+        } else if !is_mark(mark, SIRC_ZERO_MARK_US) {
+            return None;
+        }
+        count += 1;
+    }
+    if !SIRC_BIT_COUNTS.contains(&count) {
+        return None;
+    }
+
+    Some(IrCode {
+        protocol: IrProtocol::Sirc,
+        address: (value >> 7) as u16,
+        command: (value & 0x7F) as u16,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Receivers stretch marks and shorten spaces, the frames below carry that skew
+
+    fn mark(us: u32) -> IrPulse {
+        IrPulse { mark: true, us }
+    }
+
+    fn space(us: u32) -> IrPulse {
+        IrPulse { mark: false, us }
+    }
+
+    fn nec_frame(bytes: [u8; 4]) -> Vec<IrPulse> {
+        let mut pulses = vec![mark(9080), space(4420)];
+        for bit in 0..NEC_BITS {
+            let one = u32::from_le_bytes(bytes) & (1 << bit) != 0;
+            pulses.push(mark(620));
+            pulses.push(space(if one { 1630 } else { 500 }));
+        }
+        pulses.push(mark(620));
+        pulses
+    }
+
+    fn sirc_frame(command: u32, address: u32, bits: usize) -> Vec<IrPulse> {
+        let value = command | (address << 7);
+        let mut pulses = vec![mark(2460)];
+        for bit in 0..bits {
+            pulses.push(space(550));
+            pulses.push(mark(if value & (1 << bit) != 0 { 1260 } else { 650 }));
+        }
+        pulses
+    }
+
+    #[test]
+    fn nec() {
+        assert_eq!(
+            decode(&nec_frame([0x00, 0xFF, 0x45, 0xBA])),
+            Some(IrFrame::Code(IrCode {
+                protocol: IrProtocol::Nec,
+                address: 0x00,
+                command: 0x45,
+            }))
+        );
+    }
+
+    #[test]
+    fn extended_nec() {
+        assert_eq!(
+            decode(&nec_frame([0x34, 0x12, 0x07, 0xF8])),
+            Some(IrFrame::Code(IrCode {
+                protocol: IrProtocol::Nec,
+                address: 0x1234,
+                command: 0x07,
+            }))
+        );
+    }
+
+    #[test]
+    fn nec_repeat() {
+        assert_eq!(
+            decode(&[mark(9050), space(2200), mark(610)]),
+            Some(IrFrame::Repeat)
+        );
+    }
+
+    #[test]
+    fn nec_with_broken_command_check() {
+        assert_eq!(decode(&nec_frame([0x00, 0xFF, 0x45, 0xBB])), None);
+    }
+
+    #[test]
+    fn truncated_nec() {
+        assert_eq!(decode(&nec_frame([0x00, 0xFF, 0x45, 0xBA])[..40]), None);
+    }
+
+    #[test]
+    fn rc5() {
+        // Start, field 1, toggle 0, address 5, command 0x35, the last bit ends in a mark
+        let pulses = [
+            mark(920),
+            space(860),
+            mark(1810),
+            space(860),
+            mark(920),
+            space(860),
+            mark(920),
+            space(1750),
+            mark(1810),
+            space(1750),
+            mark(920),
+            space(860),
+            mark(920),
+            space(860),
+            mark(1810),
+            space(1750),
+            mark(1810),
+            space(1750),
+            mark(920),
+        ];
+        assert_eq!(
+            decode(&pulses),
+            Some(IrFrame::Code(IrCode {
+                protocol: IrProtocol::Rc5,
+                address: 5,
+                command: 0x35,
+            }))
+        );
+    }
+
+    #[test]
+    fn rc5x() {
+        // Start, field 0, toggle 1, address 0x10, command 2, the trailing space merges into idle
+        let pulses = [
+            mark(1810),
+            space(1750),
+            mark(920),
+            space(860),
+            mark(1810),
+            space(860),
+            mark(920),
+            space(860),
+            mark(920),
+            space(860),
+            mark(920),
+            space(860),
+            mark(920),
+            space(860),
+            mark(920),
+            space(860),
+            mark(920),
+            space(860),
+            mark(920),
+            space(1750),
+            mark(1810),
+        ];
+        assert_eq!(
+            decode(&pulses),
+            Some(IrFrame::Code(IrCode {
+                protocol: IrProtocol::Rc5,
+                address: 0x10,
+                command: 0x42,
+            }))
+        );
+    }
+
+    #[test]
+    fn sirc() {
+        for (bits, address, command) in [(12, 0x01, 0x12), (15, 0x97, 0x2A), (20, 0x1A3A, 0x39)] {
+            assert_eq!(
+                decode(&sirc_frame(command, address, bits)),
+                Some(IrFrame::Code(IrCode {
+                    protocol: IrProtocol::Sirc,
+                    address: address as u16,
+                    command: command as u16,
+                })),
+                "SIRC-{}",
+                bits
+            );
+        }
+    }
+
+    #[test]
+    fn sirc_with_unknown_length() {
+        assert_eq!(decode(&sirc_frame(0x12, 0x01, 13)), None);
+    }
+
+    #[test]
+    fn noise() {
+        assert_eq!(decode(&[mark(300), space(5000), mark(150)]), None);
+        assert_eq!(decode(&[]), None);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use esp_idf_svc::{
+    hal::{
+        gpio::InputPin,
+        peripheral::Peripheral,
+        rmt::{config::ReceiveConfig, PinState, Pulse, Receive, RmtChannel, RxRmtDriver},
+    },
+    nvs::{EspDefaultNvs, EspDefaultNvsPartition},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    buttons::{self, ButtonAction},
+    hardware_context::HardwareContext,
+    ir_decoder::{self, IrCode, IrFrame, IrPulse},
+    lock::LockExt,
+    notifications::Notification,
+};
+
+const NVS_NAMESPACE: &str = "ir";
+const NVS_KEY_BINDINGS: &str = "bindings";
+const MAX_BINDINGS: usize = 32;
+
+/// 80 MHz APB clock divided down to 1 µs per tick
+const RMT_CLOCK_DIVIDER: u8 = 80;
+/// A space this long ends a frame, longer than any space inside NEC, RC5 or SIRC frames
+const IDLE_THRESHOLD_US: u16 = 12000;
+/// Glitches shorter than this are filtered out, in APB clock ticks
+const FILTER_TICKS: u8 = 100;
+const RING_BUFFER_SIZE: usize = 1000;
+const RECEIVE_TIMEOUT_TICKS: u32 = 100;
+/// The same code arriving again within this time counts as the key being held.
+/// Covers the NEC repeat interval of 108 ms and SIRC and RC5 frames repeating every 45 ms and 114 ms.
+const REPEAT_WINDOW_MS: u64 = 200;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IrBinding {
+    pub code: IrCode,
+    pub action: ButtonAction,
+}
+
+/// Bindings of remote control codes to actions, persisted in NVS
+pub struct IrRemote {
+    nvs: Mutex<EspDefaultNvs>,
+    bindings: Mutex<Vec<IrBinding>>,
+    /// Action the next received code gets bound to
+    learning: Mutex<Option<ButtonAction>>,
+}
+
+impl IrRemote {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+
+        let mut buffer = vec![0u8; MAX_BINDINGS * 128];
+        let bindings = match nvs.get_str(NVS_KEY_BINDINGS, &mut buffer)? {
+            Some(json) => serde_json::from_str(json).unwrap_or_else(|e| {
+                log::warn!("Discarding stored IR bindings: {}", e);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        log::info!("Restored {} IR bindings", bindings.len());
+
+        Ok(IrRemote {
+            nvs: Mutex::new(nvs),
+            bindings: Mutex::new(bindings),
+            learning: Mutex::new(None),
+        })
+    }
+
+    pub fn bindings(&self) -> Vec<IrBinding> {
+        self.bindings.lock_value().clone()
+    }
+
+    /// Binds the next received code to `action`, None cancels learning
+    pub fn learn(&self, action: Option<ButtonAction>) {
+        log::info!("IR learn mode: {:?}", action);
+        *self.learning.lock_value() = action;
+    }
+
+    pub fn learning(&self) -> Option<ButtonAction> {
+        *self.learning.lock_value()
+    }
+
+    /// Binds `code` to `action`, replacing an earlier binding of the same code
+    pub fn bind(&self, code: IrCode, action: ButtonAction) -> anyhow::Result<()> {
+        let mut bindings = self.bindings.lock_or_err("Could not lock IR bindings")?;
+        bindings.retain(|binding| binding.code != code);
+        if bindings.len() >= MAX_BINDINGS {
+            return Err(anyhow::Error::msg(format!(
+                "At most {} IR codes can be bound",
+                MAX_BINDINGS
+            )));
+        }
+        bindings.push(IrBinding { code, action });
+        self.save(&bindings)
+    }
+
+    /// Returns false if the code was not bound
+    pub fn unbind(&self, code: IrCode) -> anyhow::Result<bool> {
+        let mut bindings = self.bindings.lock_or_err("Could not lock IR bindings")?;
+        let count = bindings.len();
+        bindings.retain(|binding| binding.code != code);
+        if bindings.len() == count {
+            return Ok(false);
+        }
+        self.save(&bindings)?;
+        Ok(true)
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        let mut bindings = self.bindings.lock_or_err("Could not lock IR bindings")?;
+        bindings.clear();
+        self.save(&bindings)
+    }
+
+    fn action(&self, code: IrCode) -> Option<ButtonAction> {
+        self.bindings
+            .lock_value()
+            .iter()
+            .find(|binding| binding.code == code)
+            .map(|binding| binding.action)
+    }
+
+    fn save(&self, bindings: &[IrBinding]) -> anyhow::Result<()> {
+        let json = serde_json::to_string(bindings)?;
+        self.nvs
+            .lock_or_err("Could not lock NVS")?
+            .set_str(NVS_KEY_BINDINGS, &json)?;
+        Ok(())
+    }
+}
+
+/// Receives frames from a demodulating IR receiver (TSOP style, active low) on an RMT channel
+/// and runs the bound actions. In learn mode the next code is bound instead.
+pub fn ir_receiver<'d, C: RmtChannel>(
+    channel: impl Peripheral<P = C> + 'd,
+    pin: impl Peripheral<P = impl InputPin> + 'd,
+    hardware_context: Arc<HardwareContext<'static>>,
+) -> anyhow::Result<()> {
+    log::info!("IR receiver thread started");
+
+    let config = ReceiveConfig::new()
+        .clock_divider(RMT_CLOCK_DIVIDER)
+        .idle_threshold(IDLE_THRESHOLD_US)
+        .filter_ticks_thresh(FILTER_TICKS);
+    let mut rx = RxRmtDriver::new(channel, pin, &config, RING_BUFFER_SIZE)?;
+    rx.start()?;
+
+    let boot = Instant::now();
+    let mut buffer = [(Pulse::zero(), Pulse::zero()); 128];
+    // Last code and when it was received, for telling held keys from new presses
+    let mut last: Option<(IrCode, u64)> = None;
+
+    loop {
+        let length = match rx.receive(&mut buffer, RECEIVE_TIMEOUT_TICKS)? {
+            Receive::Read(length) => length,
+            Receive::Overflow(_) => {
+                log::warn!("IR frame too long for the receive buffer");
+                continue;
+            }
+            Receive::Timeout => continue,
+        };
+
+        let pulses = ir_pulses(&buffer[..length]);
+        let Some(frame) = ir_decoder::decode(&pulses) else {
+            // Marks positive, spaces negative in µs, the usual notation of raw IR captures
+            let durations: Vec<i64> = pulses
+                .iter()
+                .map(|pulse| if pulse.mark { 1 } else { -1 } * pulse.us as i64)
+                .collect();
+            log::debug!(
+                "Unknown IR frame of {} pulses: {:?}",
+                pulses.len(),
+                durations
+            );
+            continue;
+        };
+
+        let now_ms = boot.elapsed().as_millis() as u64;
+        let held = |code: IrCode| {
+            last.is_some_and(|(last_code, at_ms)| {
+                last_code == code && now_ms - at_ms < REPEAT_WINDOW_MS
+            })
+        };
+        let (code, repeat) = match frame {
+            IrFrame::Code(code) => (code, held(code)),
+            IrFrame::Repeat => match last {
+                Some((code, _)) if held(code) => (code, true),
+                _ => continue,
+            },
+        };
+        last = Some((code, now_ms));
+
+        if let Err(e) = handle_code(&hardware_context, code, repeat) {
+            log::error!("IR code {:?} failed: {:?}", code, e);
+        }
+    }
+}
+
+fn handle_code(
+    hardware_context: &HardwareContext,
+    code: IrCode,
+    repeat: bool,
+) -> anyhow::Result<()> {
+    let remote = &hardware_context.ir_remote;
+
+    if let Some(action) = remote.learning() {
+        if repeat {
+            return Ok(());
+        }
+        remote.learn(None);
+        remote.bind(code, action)?;
+        log::info!("Bound IR code {:?} to {:?}", code, action);
+        hardware_context
+            .notifier
+            .publish(Notification::IrLearned { code, action });
+        return Ok(());
+    }
+
+    let Some(action) = remote.action(code) else {
+        if !repeat {
+            log::info!("Unbound IR code {:?}", code);
+        }
+        return Ok(());
+    };
+    // Only the volume keeps changing while a key is held
+    if repeat && !matches!(action, ButtonAction::VolumeUp | ButtonAction::VolumeDown) {
+        return Ok(());
+    }
+    buttons::run_action(hardware_context, action);
+    Ok(())
+}
+
+/// Converts RMT items to marks and spaces, a zero length ends the frame
+fn ir_pulses(items: &[(Pulse, Pulse)]) -> Vec<IrPulse> {
+    let mut pulses: Vec<IrPulse> = Vec::with_capacity(items.len() * 2);
+    for pulse in items.iter().flat_map(|(first, second)| [first, second]) {
+        let us = pulse.ticks.ticks() as u32;
+        if us == 0 {
+            break;
+        }
+        let mark = pulse.pin_state == PinState::Low;
+        match pulses.last_mut() {
+            Some(last) if last.mark == mark => last.us += us,
+            _ => pulses.push(IrPulse { mark, us }),
+        }
+    }
+    pulses
+}
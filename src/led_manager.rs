@@ -0,0 +1,262 @@
+use std::sync::mpsc::Receiver;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_fault_monitor, hardware_context::HardwareContext, input_selector::InputSource,
+    lock::LockExt, notifications::Notification, power::PowerState, thermal_monitor::ThermalState,
+    web::wifi::WifiState,
+};
+
+const FAULT_BLINK_MS: u64 = 250;
+const WIFI_BLINK_MS: u64 = 500;
+/// How long a failed WiFi connection is shown before the normal pattern returns
+const WIFI_FAILED_SHOW_MS: u64 = 5000;
+const MUTE_PULSE_MS: u64 = 2000;
+const BOOT_PULSE_MS: u64 = 600;
+const CLIP_FLASH_MS: u64 = 120;
+/// A failed self-test replaces the input colour with orange for one of four periods
+const DEGRADED_BLINK_MS: u64 = 750;
+const VOLUME_FLASH_MS: u64 = 100;
+/// Lowest brightness of a pulse, relative to the full pattern brightness
+const PULSE_FLOOR: f32 = 0.15;
+/// Brightness of the standby glow, relative to the configured brightness
+const STANDBY_LEVEL: f32 = 0.1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LedConfig {
+    /// Brightness in percent
+    pub brightness: u8,
+    /// Dims the LED to `night_brightness` and leaves out the volume flashes
+    pub night_mode: bool,
+    pub night_brightness: u8,
+    /// Time the LED takes to follow a colour change
+    pub fade_ms: u32,
+}
+
+impl Default for LedConfig {
+    fn default() -> Self {
+        LedConfig {
+            brightness: 80,
+            night_mode: false,
+            night_brightness: 10,
+            fade_ms: 80,
+        }
+    }
+}
+
+impl LedConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.brightness > 100 || self.night_brightness > 100 {
+            return Err(anyhow::Error::msg("Brightness must be between 0 and 100"));
+        }
+        if self.fade_ms > 2000 {
+            return Err(anyhow::Error::msg("Fade time must be at most 2 s"));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const OFF: Rgb = Rgb::new(0, 0, 0);
+    pub const RED: Rgb = Rgb::new(255, 0, 0);
+    pub const ORANGE: Rgb = Rgb::new(255, 80, 0);
+    pub const AMBER: Rgb = Rgb::new(255, 140, 0);
+    pub const BLUE: Rgb = Rgb::new(0, 0, 255);
+    pub const WHITE: Rgb = Rgb::new(150, 255, 200);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Rgb { r, g, b }
+    }
+
+    pub fn scaled(&self, factor: f32) -> Self {
+        let scale = |value: u8| (value as f32 * factor.clamp(0.0, 1.0)).round() as u8;
+        Rgb::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+}
+
+/// Colour that identifies an input source
+pub fn source_color(source: InputSource) -> Rgb {
+    match source {
+        InputSource::Analog1 => Rgb::new(0, 255, 0),
+        InputSource::Analog2 => Rgb::new(0, 255, 255),
+        InputSource::Analog3 => Rgb::new(0, 0, 255),
+        InputSource::Analog4 => Rgb::new(255, 0, 255),
+        InputSource::Balanced1 => Rgb::new(255, 255, 0),
+        InputSource::Balanced2 => Rgb::new(120, 0, 255),
+        InputSource::Mix => Rgb::WHITE,
+    }
+}
+
+/// Everything the LED pattern depends on
+#[derive(Clone, Copy, Debug)]
+pub struct LedInputs {
+    pub power: PowerState,
+    pub source: InputSource,
+    pub fault: bool,
+    /// A chip failed the self-test, the amplifier runs without it
+    pub degraded: bool,
+    pub wifi: Option<WifiState>,
+}
+
+/// Picks the LED colour from the amplifier state and fades towards it.
+/// State changes arrive as notifications, the manager is polled from the control loop.
+pub struct LedManager {
+    notifications: Receiver<Notification>,
+    wifi: Option<WifiState>,
+    wifi_failed_ms: Option<u64>,
+    /// Overlay colour and the time it ends
+    flash: Option<(Rgb, u64)>,
+    /// Flash the current pattern at full brightness instead of a fixed colour
+    volume_flash_until_ms: Option<u64>,
+    current: [f32; 3],
+    last_update_ms: u64,
+}
+
+impl LedManager {
+    pub fn new(hardware_context: &HardwareContext) -> Self {
+        LedManager {
+            notifications: hardware_context.notifier.subscribe(),
+            wifi: None,
+            wifi_failed_ms: None,
+            flash: None,
+            volume_flash_until_ms: None,
+            current: [0.0; 3],
+            last_update_ms: 0,
+        }
+    }
+
+    /// Returns the colour to show now
+    pub fn update(&mut self, hardware_context: &HardwareContext, now_ms: u64) -> Rgb {
+        while let Ok(notification) = self.notifications.try_recv() {
+            self.handle(notification, now_ms);
+        }
+
+        let config = *hardware_context.led_config.lock_value();
+        let inputs = LedInputs {
+            power: hardware_context.power.state(),
+            source: hardware_context.input_selector.selection().source,
+            fault: amp_fault_monitor::is_latched(hardware_context)
+                || hardware_context.thermal_status.lock_value().state == ThermalState::Critical,
+            degraded: hardware_context.self_test.lock_value().degraded(),
+            wifi: self.wifi,
+        };
+
+        let target = self.target(&inputs, &config, now_ms);
+        self.fade_to(target, &config, now_ms)
+    }
+
+    fn handle(&mut self, notification: Notification, now_ms: u64) {
+        match notification {
+            Notification::Clipping { .. } => {
+                self.flash = Some((Rgb::RED, now_ms + CLIP_FLASH_MS));
+            }
+            Notification::VolumeChanged { .. } => {
+                self.volume_flash_until_ms = Some(now_ms + VOLUME_FLASH_MS);
+            }
+            Notification::Wifi { state } => {
+                self.wifi = Some(state);
+                self.wifi_failed_ms = (state == WifiState::Failed).then_some(now_ms);
+            }
+            _ => {}
+        }
+    }
+
+    fn target(&mut self, inputs: &LedInputs, config: &LedConfig, now_ms: u64) -> Rgb {
+        let brightness = if config.night_mode {
+            config.night_brightness
+        } else {
+            config.brightness
+        } as f32
+            / 100.0;
+
+        if let Some((color, until_ms)) = self.flash {
+            if now_ms < until_ms {
+                return color.scaled(brightness);
+            }
+            self.flash = None;
+        }
+
+        let volume_flash = self
+            .volume_flash_until_ms
+            .is_some_and(|until_ms| now_ms < until_ms && !config.night_mode);
+        let pattern = pattern(inputs, self.wifi_failed_ms, now_ms);
+        if volume_flash {
+            pattern
+        } else {
+            pattern.scaled(brightness)
+        }
+    }
+
+    /// Moves the output a step towards `target` so colour changes are smooth
+    fn fade_to(&mut self, target: Rgb, config: &LedConfig, now_ms: u64) -> Rgb {
+        let elapsed_ms = now_ms.saturating_sub(self.last_update_ms);
+        self.last_update_ms = now_ms;
+        let step = if config.fade_ms == 0 {
+            1.0
+        } else {
+            (elapsed_ms as f32 / config.fade_ms as f32).min(1.0)
+        };
+
+        for (current, target) in self.current.iter_mut().zip([target.r, target.g, target.b]) {
+            *current += (target as f32 - *current) * step;
+        }
+        let [r, g, b] = self.current.map(|value| value.round() as u8);
+        Rgb::new(r, g, b)
+    }
+}
+
+/// Base pattern at full brightness, free of hardware access
+pub fn pattern(inputs: &LedInputs, wifi_failed_ms: Option<u64>, now_ms: u64) -> Rgb {
+    if inputs.fault || inputs.power == PowerState::Fault {
+        return blink(Rgb::RED, FAULT_BLINK_MS, now_ms);
+    }
+    match inputs.power {
+        PowerState::Off => return Rgb::OFF,
+        PowerState::Standby => return Rgb::AMBER.scaled(STANDBY_LEVEL),
+        PowerState::Booting => return pulse(Rgb::WHITE, BOOT_PULSE_MS, now_ms),
+        _ => {}
+    }
+
+    match inputs.wifi {
+        Some(WifiState::Connecting) => return blink(Rgb::BLUE, WIFI_BLINK_MS, now_ms),
+        Some(WifiState::Failed)
+            if wifi_failed_ms.is_some_and(|failed| now_ms - failed < WIFI_FAILED_SHOW_MS) =>
+        {
+            return blink(Rgb::ORANGE, WIFI_BLINK_MS, now_ms)
+        }
+        _ => {}
+    }
+
+    let color = if inputs.degraded && (now_ms / DEGRADED_BLINK_MS) % 4 == 0 {
+        Rgb::ORANGE
+    } else {
+        source_color(inputs.source)
+    };
+    match inputs.power {
+        PowerState::Muted | PowerState::NoSignal => pulse(color, MUTE_PULSE_MS, now_ms),
+        _ => color,
+    }
+}
+
+fn blink(color: Rgb, period_ms: u64, now_ms: u64) -> Rgb {
+    if (now_ms / period_ms) % 2 == 0 {
+        color
+    } else {
+        Rgb::OFF
+    }
+}
+
+/// Triangle wave between `PULSE_FLOOR` and full brightness
+fn pulse(color: Rgb, period_ms: u64, now_ms: u64) -> Rgb {
+    let phase = (now_ms % period_ms) as f32 / period_ms as f32;
+    let level = 1.0 - (2.0 * phase - 1.0).abs();
+    color.scaled(PULSE_FLOOR + (1.0 - PULSE_FLOOR) * level)
+}
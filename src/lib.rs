@@ -0,0 +1,9 @@
+//! Hardware independent parts of the firmware. They build on the host, so their tests run with
+//! `cargo test --lib --target x86_64-unknown-linux-gnu` (or the host triple of the machine).
+
+pub mod clock_dividers;
+pub mod encoder_steps;
+pub mod gestures;
+pub mod ir_decoder;
+pub mod power_state;
+pub mod settings_schema;
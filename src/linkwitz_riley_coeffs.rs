@@ -23,9 +23,9 @@ impl SecondOrderCoeffs {
     /// The order is: [b2, b1, b0, a2, a1]
     pub fn to_fixed(&self) -> [u32; 5] {
         [
-            to_fixed_unsigned(self.b[0]),
-            to_fixed_unsigned(self.b[1]),
             to_fixed_unsigned(self.b[2]),
+            to_fixed_unsigned(self.b[1]),
+            to_fixed_unsigned(self.b[0]),
             to_fixed_unsigned(self.a[1]),
             to_fixed_unsigned(self.a[0]),
         ]
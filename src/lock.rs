@@ -0,0 +1,22 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Locking without panicking, so supervised tasks report errors instead of aborting the
+/// firmware
+pub trait LockExt<T> {
+    /// Reports a poisoned mutex as an error
+    fn lock_or_err(&self, message: &'static str) -> anyhow::Result<MutexGuard<'_, T>>;
+
+    /// Ignores poisoning, for mutexes that hold a plain value a panic cannot leave half
+    /// written
+    fn lock_value(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> LockExt<T> for Mutex<T> {
+    fn lock_or_err(&self, message: &'static str) -> anyhow::Result<MutexGuard<'_, T>> {
+        self.lock().map_err(|_| anyhow::Error::msg(message))
+    }
+
+    fn lock_value(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -1,26 +1,56 @@
 use std::sync::{Arc, Mutex};
 
 use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::gpio::{AnyIOPin, AnyInputPin, AnyOutputPin};
 use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
 use esp_idf_svc::hal::prelude::Peripherals;
 use esp_idf_svc::hal::prelude::*;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 
+use board::{BoardStore, Chip};
+use endstufe_esp32::{
+    clock_dividers, encoder_steps, gestures, ir_decoder, power_state, settings_schema,
+};
+use hardware_context::HardwareContext;
+use notifications::Notification;
+use web::wifi::WifiState;
+
+mod amp_fault_monitor;
+mod amp_state;
 mod api;
+mod asrc_monitor;
+mod board;
+mod buttons;
+mod clip_monitor;
+mod clock_plan;
 mod drivers;
+mod dsp_config;
 mod encoder;
 mod hardware_context;
 mod hardware_control;
 mod hardware_init;
 mod i2c_helper;
+mod input_selector;
+mod ir_remote;
+mod led_manager;
 mod linkwitz_riley_coeffs;
+mod lock;
+mod notifications;
+mod power;
+mod presets;
+mod self_test;
+mod settings;
 mod sigmastudio;
+mod standby;
+mod supervisor;
+mod thermal_monitor;
+mod volume_ramp;
 mod web;
-mod sticky_limiter;
 
 
-const HARDWARE_CONNECTED: bool = true;
-const ENABLE_WEB: bool = true;
+const EXECUTOR_STACK_SIZE: usize = 8192;
+const CONTROL_STACK_SIZE: usize = 6144;
+const WIFI_STACK_SIZE: usize = 8192;
 
 fn main() -> anyhow::Result<()> {
     // It is necessary to call this function once. Otherwise some patches to the runtime
@@ -35,57 +65,173 @@ fn main() -> anyhow::Result<()> {
     let peripherals = Peripherals::take()?;
     let sys_loop = EspSystemEventLoop::take()?;
     let nvs = EspDefaultNvsPartition::take()?;
-    let i2c = peripherals.i2c0;
-    let sda = peripherals.pins.gpio6;
-    let scl = peripherals.pins.gpio7;
 
-    let config = I2cConfig::new().baudrate(100.kHz().into());
-    let i2c = I2cDriver::new(i2c, sda, scl, &config)?;
+    let board_store = BoardStore::new(nvs.clone())?;
+    let board = board_store.load();
 
-    let shared_i2c = Arc::new(Mutex::new(i2c));
+    // SAFETY: the pins of the profile are validated to exist and to be distinct, and
+    // nothing else takes them from `peripherals`
+    let (sda, scl) = unsafe { (AnyIOPin::new(board.i2c.sda), AnyIOPin::new(board.i2c.scl)) };
+    let config = I2cConfig::new().baudrate(board.i2c.baudrate_hz.Hz());
+    let i2c = I2cDriver::new(peripherals.i2c0, sda, scl, &config)?;
 
-    let hardware_context = Arc::new(hardware_context::HardwareContext::new(shared_i2c));
+    let shared_i2c = Arc::new(Mutex::new(i2c));
 
-    let mut handle: Option<std::thread::JoinHandle<Result<(), anyhow::Error>>> = None;
+    let settings = settings::SettingsStore::new(nvs.clone())?;
+    let input_selector = input_selector::InputSelector::new(settings.stored().input);
+    let ir_remote = ir_remote::IrRemote::new(nvs.clone())?;
+    let presets = presets::PresetStore::new(nvs.clone())?;
+
+    let hardware_context = Arc::new(HardwareContext::new(
+        board,
+        board_store,
+        shared_i2c,
+        input_selector,
+        ir_remote,
+        settings,
+        presets,
+    ));
+
+    // Hardware init runs on the executor, it needs more stack than the default thread size
+    supervisor::spawn(
+        &hardware_context,
+        "amp_executor",
+        EXECUTOR_STACK_SIZE,
+        amp_state::amp_executor,
+    )?;
+
+    supervisor::spawn(
+        &hardware_context,
+        "settings_writer",
+        supervisor::DEFAULT_STACK_SIZE,
+        settings::settings_writer,
+    )?;
+
+    if hardware_context.board.hardware_connected {
+        // Chips that fail are left out from here on, the monitors below included
+        self_test::run(&hardware_context);
+
+        // Without a working chain the tasks still run, so the API and the LED can report it
+        if let Err(e) = hardware_context
+            .amp
+            .execute(amp_state::AmpCommand::Power(power::PowerRequest::PowerOn))
+        {
+            log::error!("Power-up failed: {:?}", e);
+        }
 
-    if HARDWARE_CONNECTED {
-        hardware_init::hardware_init(hardware_context.clone())?;
+        // A monitor polls one chip and is left out if the board does not have it
+        let monitors: [(&str, Chip, supervisor::Task); 6] = [
+            ("asrc_monitor", Chip::Adau1467, asrc_monitor::asrc_monitor),
+            ("standby_monitor", Chip::Pcm1865, standby::standby_monitor),
+            (
+                "input_monitor",
+                Chip::Pcm1865,
+                input_selector::input_monitor,
+            ),
+            ("clip_monitor", Chip::Pcm1865, clip_monitor::clip_monitor),
+            (
+                "thermal_monitor",
+                Chip::Adau1962a,
+                thermal_monitor::thermal_monitor,
+            ),
+            (
+                "amp_fault_monitor",
+                Chip::Expander,
+                amp_fault_monitor::amp_fault_monitor,
+            ),
+        ];
+        for (name, chip, monitor) in monitors {
+            if !hardware_context.has(chip) {
+                log::warn!("{} is not fitted, {} does not run", chip.name(), name);
+                continue;
+            }
+            supervisor::spawn(
+                &hardware_context,
+                name,
+                supervisor::DEFAULT_STACK_SIZE,
+                monitor,
+            )?;
+        }
 
-        let hardware_context_clone = hardware_context.clone();
+        if let Some(pin) = hardware_context.board.ir_receiver {
+            // The C6 receives on RMT channels 2 and 3 only, 0 and 1 are transmit channels
+            let mut ir_channel = peripherals.rmt.channel2;
+            // SAFETY: validated by the board profile, no other driver uses the pin
+            let mut ir_pin = unsafe { AnyInputPin::new(pin) };
+            supervisor::spawn(
+                &hardware_context,
+                "ir_receiver",
+                supervisor::DEFAULT_STACK_SIZE,
+                move |hardware_context| {
+                    ir_remote::ir_receiver(&mut ir_channel, &mut ir_pin, hardware_context)
+                },
+            )?;
+        }
 
-        /*handle = Some(std::thread::spawn(move || {
-            hardware_control::hardware_control(
-                peripherals.pins.gpio35.into(),
-                peripherals.pins.gpio36.into(),
-                peripherals.pins.gpio37.into(),
-                peripherals.pins.gpio38.into(),
-                peripherals.pins.gpio39.into(),
-                peripherals.pins.gpio40.into(),
-                peripherals.pins.gpio41.into(),
-                peripherals.pins.gpio42.into(),
-                peripherals.ledc.into(),
-                peripherals.pcnt0,
-                hardware_context_clone,
-            )
-        }));*/
+        if let Some(pins) = hardware_context.board.controls {
+            // SAFETY: validated by the board profile, no other driver uses the pins
+            let mut control_peripherals = unsafe {
+                hardware_control::ControlPeripherals {
+                    encoder_a: AnyIOPin::new(pins.encoder_a),
+                    encoder_b: AnyIOPin::new(pins.encoder_b),
+                    button_mute: AnyIOPin::new(pins.button_mute),
+                    button_bassboost: AnyIOPin::new(pins.button_bassboost),
+                    button_standby: AnyIOPin::new(pins.button_standby),
+                    led_red: AnyOutputPin::new(pins.led_red),
+                    led_green: AnyOutputPin::new(pins.led_green),
+                    led_blue: AnyOutputPin::new(pins.led_blue),
+                    ledc: peripherals.ledc,
+                    pcnt: peripherals.pcnt0,
+                }
+            };
+            supervisor::spawn(
+                &hardware_context,
+                "hardware_control",
+                CONTROL_STACK_SIZE,
+                move |hardware_context| {
+                    hardware_control::hardware_control(&mut control_peripherals, hardware_context)
+                },
+            )?;
+        }
 
         log::info!("Hardware init complete");
     }
 
-    if ENABLE_WEB {
-        let _wifi = web::wifi::setup_wifi(peripherals.modem, sys_loop, nvs)?;
-
-        let _web_server = web::server::start_server(hardware_context)?;
-
+    if hardware_context.board.enable_web {
+        // Subscribed before the WiFi task starts so its first result cannot be missed
+        let notifications = hardware_context.notifier.subscribe();
+
+        let mut modem = peripherals.modem;
+        supervisor::spawn(
+            &hardware_context,
+            "wifi",
+            WIFI_STACK_SIZE,
+            move |hardware_context| {
+                web::wifi::wifi_task(&mut modem, sys_loop.clone(), nvs.clone(), hardware_context)
+            },
+        )?;
+
+        // The HTTP server needs the network stack the WiFi task brings up
         loop {
-            std::thread::sleep(std::time::Duration::from_secs(60));
+            if let Notification::Wifi {
+                state: WifiState::Connected | WifiState::Failed,
+            } = notifications.recv()?
+            {
+                break;
+            }
         }
+        drop(notifications);
+
+        supervisor::spawn(
+            &hardware_context,
+            "http_server",
+            supervisor::DEFAULT_STACK_SIZE,
+            web::server::server_task,
+        )?;
     }
 
-    if handle.is_some() {
-        handle.unwrap().join().expect("Hardware control thread panicked")?;
+    // Everything runs in supervised tasks from here on
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(60));
     }
-    
-
-    Ok(())
 }
@@ -0,0 +1,165 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+
+use crate::{
+    amp_state::AmpState, buttons::ButtonAction, input_selector::InputSource, ir_decoder::IrCode,
+    lock::LockExt, power::PowerState, thermal_monitor::ThermalState, web::wifi::WifiState,
+};
+
+/// Notifications kept for clients polling /api/events, older ones are dropped
+const EVENT_LOG_SIZE: usize = 32;
+
+/// Events that are pushed to every subscriber and kept for the /api/events poll
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Notification {
+    InputLocked { asrc: u8, sample_rate: Option<u32> },
+    InputLockLost { asrc: u8 },
+    Standby,
+    Wake,
+    InputSelected { source: InputSource },
+    Clipping {
+        count: u32,
+        timestamp_ms: u64,
+        gain_db: Option<f32>,
+    },
+    Thermal {
+        state: ThermalState,
+        temperature_c: i16,
+    },
+    AmpFault {
+        amps: u8,
+        count: u32,
+    },
+    AmpRecovered,
+    AmpLatchedOff {
+        count: u32,
+    },
+    PowerState {
+        state: PowerState,
+    },
+    VolumeChanged {
+        db: f32,
+    },
+    Wifi {
+        state: WifiState,
+    },
+    IrLearned {
+        code: IrCode,
+        action: ButtonAction,
+    },
+    AmpState {
+        state: AmpState,
+    },
+    TaskRestarted {
+        task: &'static str,
+        restarts: u32,
+    },
+    PresetRecalled {
+        name: String,
+    },
+    /// The self-test finished or its result changed
+    SelfTest {
+        degraded: bool,
+    },
+}
+
+/// A notification with its sequence number, which polling clients use as their cursor
+#[derive(Serialize, Clone, Debug)]
+pub struct LoggedEvent {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub notification: Notification,
+}
+
+/// Answer to an /api/events poll
+#[derive(Serialize, Debug)]
+pub struct EventBatch {
+    /// Sequence number of the next event, the `since` of the following poll
+    pub cursor: u64,
+    /// Events after `since` were dropped from the log before this poll
+    pub missed: bool,
+    pub events: Vec<LoggedEvent>,
+}
+
+struct EventLog {
+    events: VecDeque<LoggedEvent>,
+    next_seq: u64,
+}
+
+pub struct Notifier {
+    subscribers: Mutex<Vec<Sender<Notification>>>,
+    log: Mutex<EventLog>,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Notifier {
+            subscribers: Mutex::new(Vec::new()),
+            log: Mutex::new(EventLog {
+                events: VecDeque::with_capacity(EVENT_LOG_SIZE),
+                next_seq: 0,
+            }),
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Notification> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock_value().push(sender);
+        receiver
+    }
+
+    /// Sends the notification to all subscribers, dropped receivers are removed
+    pub fn publish(&self, notification: Notification) {
+        log::info!("Notification: {:?}", notification);
+        {
+            let mut log = self.log.lock_value();
+            if log.events.len() == EVENT_LOG_SIZE {
+                log.events.pop_front();
+            }
+            let seq = log.next_seq;
+            log.events.push_back(LoggedEvent {
+                seq,
+                notification: notification.clone(),
+            });
+            log.next_seq += 1;
+        }
+        self.subscribers
+            .lock_value()
+            .retain(|subscriber| subscriber.send(notification.clone()).is_ok());
+    }
+
+    /// Logged events from `since` on. Without a cursor the client only gets the current one,
+    /// a cursor from before a reboot returns everything that is still logged.
+    pub fn events_since(&self, since: Option<u64>) -> EventBatch {
+        let log = self.log.lock_value();
+        let cursor = log.next_seq;
+        let Some(since) = since else {
+            return EventBatch {
+                cursor,
+                missed: false,
+                events: Vec::new(),
+            };
+        };
+
+        let since = if since > cursor { 0 } else { since };
+        let oldest = log.events.front().map_or(cursor, |event| event.seq);
+        EventBatch {
+            cursor,
+            missed: since < oldest,
+            events: log
+                .events
+                .iter()
+                .filter(|event| event.seq >= since)
+                .cloned()
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::sync::Mutex;
+
+pub use crate::power_state::{PowerRequest, PowerState};
+use crate::{
+    board::Chip,
+    hardware_context::HardwareContext,
+    hardware_init,
+    lock::LockExt,
+    notifications::Notification,
+    power_state::{self, PowerActions},
+    volume_ramp,
+};
+
+/// State the machine is in. `resume` is where Standby and Fault return to, Muted or Playing.
+#[derive(Clone, Copy, Debug)]
+struct PowerStatus {
+    state: PowerState,
+    resume: PowerState,
+}
+
+pub struct PowerManager {
+    status: Mutex<PowerStatus>,
+    /// Held for the duration of a transition so requests from buttons, API and monitors queue up
+    transition: Mutex<()>,
+}
+
+impl PowerManager {
+    /// `muted` is where the first power-up ends, restored from the settings
+    pub fn new(muted: bool) -> Self {
+        PowerManager {
+            status: Mutex::new(PowerStatus {
+                state: PowerState::Off,
+                resume: if muted {
+                    PowerState::Muted
+                } else {
+                    PowerState::Playing
+                },
+            }),
+            transition: Mutex::new(()),
+        }
+    }
+
+    pub fn state(&self) -> PowerState {
+        self.status().state
+    }
+
+    fn status(&self) -> PowerStatus {
+        *self.status.lock_value()
+    }
+
+    fn set_status(&self, status: PowerStatus) {
+        *self.status.lock_value() = status;
+    }
+}
+
+/// Runs the transition for `request` and returns the new state,
+/// or None if the request does not apply in the current state
+pub fn request(
+    hardware_context: &HardwareContext,
+    request: PowerRequest,
+) -> anyhow::Result<Option<PowerState>> {
+    let power = &hardware_context.power;
+    let _transition = power
+        .transition
+        .lock_or_err("Could not lock power transition")?;
+
+    let PowerStatus { state, resume } = power.status();
+    let Some(target) = power_state::next_state(state, resume, request) else {
+        log::info!("Ignoring {:?} in power state {:?}", request, state);
+        return Ok(None);
+    };
+    log::info!("Power state {:?} -> {:?}", state, target);
+
+    // Standby, Fault and Off remember whether the amp was muted before. Without a signal
+    // it was playing, the ASRC monitor mutes again if the input is still unlocked.
+    let resume = match state {
+        PowerState::Muted | PowerState::Playing => state,
+        PowerState::NoSignal => PowerState::Playing,
+        _ => resume,
+    };
+
+    if target == PowerState::Booting {
+        power.set_status(PowerStatus {
+            state: PowerState::Booting,
+            resume,
+        });
+    }
+    if let Err(e) = power_state::run_transition(
+        &mut ChainActions(hardware_context),
+        state,
+        request,
+        target,
+        resume,
+    ) {
+        if target == PowerState::Booting {
+            power.set_status(PowerStatus {
+                state: PowerState::Off,
+                resume,
+            });
+        }
+        return Err(e);
+    }
+
+    // Booting ends with the chain running, muted if it was muted before
+    let state = match target {
+        PowerState::Booting => resume,
+        _ => target,
+    };
+    power.set_status(PowerStatus { state, resume });
+    hardware_context
+        .notifier
+        .publish(Notification::PowerState { state });
+
+    Ok(Some(state))
+}
+
+/// Runs the transition steps on the chips the board has
+struct ChainActions<'a, 'b>(&'a HardwareContext<'b>);
+
+impl PowerActions for ChainActions<'_, '_> {
+    fn power_up(&mut self, muted: bool) -> anyhow::Result<()> {
+        hardware_init::hardware_init(self.0, muted)
+    }
+
+    fn fade(&mut self, muted: bool) -> anyhow::Result<()> {
+        volume_ramp::set_muted(self.0, muted)
+    }
+
+    fn mute_amplifier(&mut self) -> anyhow::Result<()> {
+        if !self.0.has(Chip::Expander) {
+            return Ok(());
+        }
+        self.0
+            .tpa3116d2
+            .lock_or_err("Could not lock TPA3116d2 driver")?
+            .mute_speaker_outputs(true)
+    }
+
+    fn enable_amplifier(&mut self, enabled: bool) -> anyhow::Result<()> {
+        if !self.0.has(Chip::Expander) {
+            return Ok(());
+        }
+        self.0
+            .tpa3116d2
+            .lock_or_err("Could not lock TPA3116d2 driver")?
+            .enable_speaker_outputs(enabled)
+    }
+
+    fn power_dac(&mut self, powered: bool) -> anyhow::Result<()> {
+        if !self.0.has(Chip::Adau1962a) {
+            return Ok(());
+        }
+        self.0
+            .adau1962a
+            .lock_or_err("Could not lock ADAU1962a driver")?
+            .master_power_up(powered)
+    }
+
+    /// Reverse order of `hardware_init`
+    fn shut_down(&mut self) -> anyhow::Result<()> {
+        let hardware_context = self.0;
+        if hardware_context.has(Chip::Adau1962a) {
+            let mut adau1962a = hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?;
+            adau1962a.master_power_up(false)?;
+            adau1962a.set_reset(false)?;
+        }
+        if hardware_context.has(Chip::Adau1467) {
+            hardware_context
+                .adau1467
+                .lock_or_err("Could not lock ADAU1467 driver")?
+                .set_reset(false)?;
+        }
+        if hardware_context.has(Chip::Pcm1865) {
+            hardware_context
+                .pcm1865
+                .lock_or_err("Could not lock PCM1865 driver")?
+                .set_power_down(true)?;
+        }
+        Ok(())
+    }
+
+    fn announce_standby(&mut self, standby: bool) {
+        self.0.notifier.publish(if standby {
+            Notification::Standby
+        } else {
+            Notification::Wake
+        });
+    }
+}
@@ -0,0 +1,505 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerState {
+    /// All chips powered down or held in reset
+    Off,
+    /// Power-up sequence running
+    Booting,
+    /// Chain running, amplifier outputs muted
+    Muted,
+    Playing,
+    /// Chain running, amplifier outputs muted because the selected input lost its lock.
+    /// Playing resumes once it locks again.
+    NoSignal,
+    /// Amplifier disabled and DAC powered down, the ADC keeps listening for a signal.
+    /// A thermal standby keeps the DAC powered, its sensor reports when it cooled down.
+    Standby,
+    /// Amplifier outputs disabled after a fault
+    Fault,
+}
+
+impl PowerState {
+    pub const ALL: [PowerState; 7] = [
+        PowerState::Off,
+        PowerState::Booting,
+        PowerState::Muted,
+        PowerState::Playing,
+        PowerState::NoSignal,
+        PowerState::Standby,
+        PowerState::Fault,
+    ];
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerRequest {
+    PowerOn,
+    PowerOff,
+    Mute,
+    Unmute,
+    ToggleMute,
+    Standby,
+    /// Standby of the thermal monitor, only the amplifier outputs are disabled
+    ThermalStandby,
+    Wake,
+    Fault,
+    ClearFault,
+    /// The selected input lost its lock, reported by the ASRC monitor
+    SignalLost,
+    SignalRestored,
+}
+
+impl PowerRequest {
+    pub const ALL: [PowerRequest; 12] = [
+        PowerRequest::PowerOn,
+        PowerRequest::PowerOff,
+        PowerRequest::Mute,
+        PowerRequest::Unmute,
+        PowerRequest::ToggleMute,
+        PowerRequest::Standby,
+        PowerRequest::ThermalStandby,
+        PowerRequest::Wake,
+        PowerRequest::Fault,
+        PowerRequest::ClearFault,
+        PowerRequest::SignalLost,
+        PowerRequest::SignalRestored,
+    ];
+}
+
+/// The hardware steps the transitions are made of. The power manager runs them on the chips,
+/// the tests record their order.
+pub trait PowerActions {
+    /// Runs the power-up sequence, ending muted or at the volume
+    fn power_up(&mut self, muted: bool) -> anyhow::Result<()>;
+    /// Fades the DAC to mute or back to the volume and switches the amplifier mute with it.
+    /// Only called when the outputs change between audible and silent.
+    fn fade(&mut self, muted: bool) -> anyhow::Result<()>;
+    /// Mutes the amplifier at once, without a fade
+    fn mute_amplifier(&mut self) -> anyhow::Result<()>;
+    fn enable_amplifier(&mut self, enabled: bool) -> anyhow::Result<()>;
+    fn power_dac(&mut self, powered: bool) -> anyhow::Result<()>;
+    /// Powers the DAC, DSP and ADC down in the reverse order of the power-up
+    fn shut_down(&mut self) -> anyhow::Result<()>;
+    /// Tells the rest of the firmware that standby was entered or left
+    fn announce_standby(&mut self, standby: bool);
+}
+
+/// Target of `request` in `state`, None if the request does not apply there.
+/// Free of hardware access so the transition table can be checked on its own.
+pub fn next_state(
+    state: PowerState,
+    resume: PowerState,
+    request: PowerRequest,
+) -> Option<PowerState> {
+    match (state, request) {
+        (PowerState::Off, PowerRequest::PowerOn) => Some(PowerState::Booting),
+        (PowerState::Off | PowerState::Booting, _) => None,
+        (_, PowerRequest::PowerOff) => Some(PowerState::Off),
+        (PowerState::Fault, PowerRequest::ClearFault) => Some(resume),
+        (PowerState::Fault, _) => None,
+        (_, PowerRequest::Fault) => Some(PowerState::Fault),
+        (PowerState::Standby, PowerRequest::Wake) => Some(resume),
+        (PowerState::Standby, _) => None,
+        (_, PowerRequest::Standby | PowerRequest::ThermalStandby) => Some(PowerState::Standby),
+        (PowerState::Playing, PowerRequest::Mute | PowerRequest::ToggleMute) => {
+            Some(PowerState::Muted)
+        }
+        (PowerState::Muted, PowerRequest::Unmute | PowerRequest::ToggleMute) => {
+            Some(PowerState::Playing)
+        }
+        (PowerState::Playing, PowerRequest::SignalLost) => Some(PowerState::NoSignal),
+        (PowerState::NoSignal, PowerRequest::SignalRestored) => Some(PowerState::Playing),
+        // Unmuting has to wait for the input to lock again
+        (PowerState::NoSignal, PowerRequest::Mute | PowerRequest::ToggleMute) => {
+            Some(PowerState::Muted)
+        }
+        _ => None,
+    }
+}
+
+/// Runs the steps from `state` to `target` for `request`. The amplifier is only enabled
+/// while the DAC runs and is silent, and only disabled once the DAC faded out, so neither
+/// end pops. The outputs are only audible in Playing, every other state keeps the amplifier
+/// muted, so a fade only runs when Playing is entered or left. `resume` is where Booting
+/// ends, muted or playing.
+pub fn run_transition(
+    actions: &mut impl PowerActions,
+    state: PowerState,
+    request: PowerRequest,
+    target: PowerState,
+    resume: PowerState,
+) -> anyhow::Result<()> {
+    match target {
+        PowerState::Booting => actions.power_up(resume == PowerState::Muted),
+        PowerState::Off => {
+            disable_outputs(actions, state)?;
+            actions.shut_down()
+        }
+        PowerState::Standby => {
+            disable_outputs(actions, state)?;
+            // The thermal monitor reads the DAC sensor to find out when to wake up again
+            if request != PowerRequest::ThermalStandby {
+                actions.power_dac(false)?;
+            }
+            actions.announce_standby(true);
+            Ok(())
+        }
+        // A fade would keep driving into the fault
+        PowerState::Fault => {
+            actions.mute_amplifier()?;
+            actions.enable_amplifier(false)
+        }
+        PowerState::NoSignal => actions.fade(true),
+        PowerState::Muted | PowerState::Playing => match state {
+            PowerState::Standby => {
+                actions.power_dac(true)?;
+                enable_outputs(actions, target)?;
+                actions.announce_standby(false);
+                Ok(())
+            }
+            PowerState::Fault => enable_outputs(actions, target),
+            _ if audible(state) != audible(target) => actions.fade(!audible(target)),
+            _ => Ok(()),
+        },
+    }
+}
+
+fn audible(state: PowerState) -> bool {
+    state == PowerState::Playing
+}
+
+/// Fades out if playing, then disables the amplifier
+fn disable_outputs(actions: &mut impl PowerActions, state: PowerState) -> anyhow::Result<()> {
+    if audible(state) {
+        actions.fade(true)?;
+    }
+    actions.enable_amplifier(false)
+}
+
+/// Enables the amplifier again and fades in if `target` is Playing
+fn enable_outputs(actions: &mut impl PowerActions, target: PowerState) -> anyhow::Result<()> {
+    actions.enable_amplifier(true)?;
+    if audible(target) {
+        actions.fade(false)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Step {
+        PowerUp { muted: bool },
+        Fade { muted: bool },
+        MuteAmplifier,
+        EnableAmplifier(bool),
+        PowerDac(bool),
+        ShutDown,
+        AnnounceStandby(bool),
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        steps: Vec<Step>,
+        fail_power_up: bool,
+    }
+
+    impl PowerActions for Recorder {
+        fn power_up(&mut self, muted: bool) -> anyhow::Result<()> {
+            self.steps.push(Step::PowerUp { muted });
+            if self.fail_power_up {
+                return Err(anyhow::anyhow!("DAC does not answer"));
+            }
+            Ok(())
+        }
+
+        fn fade(&mut self, muted: bool) -> anyhow::Result<()> {
+            self.steps.push(Step::Fade { muted });
+            Ok(())
+        }
+
+        fn mute_amplifier(&mut self) -> anyhow::Result<()> {
+            self.steps.push(Step::MuteAmplifier);
+            Ok(())
+        }
+
+        fn enable_amplifier(&mut self, enabled: bool) -> anyhow::Result<()> {
+            self.steps.push(Step::EnableAmplifier(enabled));
+            Ok(())
+        }
+
+        fn power_dac(&mut self, powered: bool) -> anyhow::Result<()> {
+            self.steps.push(Step::PowerDac(powered));
+            Ok(())
+        }
+
+        fn shut_down(&mut self) -> anyhow::Result<()> {
+            self.steps.push(Step::ShutDown);
+            Ok(())
+        }
+
+        fn announce_standby(&mut self, standby: bool) {
+            self.steps.push(Step::AnnounceStandby(standby));
+        }
+    }
+
+    fn steps(state: PowerState, request: PowerRequest, resume: PowerState) -> Vec<Step> {
+        let target = next_state(state, resume, request).unwrap();
+        let mut recorder = Recorder::default();
+        run_transition(&mut recorder, state, request, target, resume).unwrap();
+        recorder.steps
+    }
+
+    /// Every transition that applies, all other combinations must be ignored
+    fn expected(
+        state: PowerState,
+        resume: PowerState,
+        request: PowerRequest,
+    ) -> Option<PowerState> {
+        use PowerRequest as R;
+        use PowerState as S;
+
+        let table = [
+            (S::Off, R::PowerOn, S::Booting),
+            (S::Muted, R::PowerOff, S::Off),
+            (S::Muted, R::Unmute, S::Playing),
+            (S::Muted, R::ToggleMute, S::Playing),
+            (S::Muted, R::Standby, S::Standby),
+            (S::Muted, R::ThermalStandby, S::Standby),
+            (S::Muted, R::Fault, S::Fault),
+            (S::Playing, R::PowerOff, S::Off),
+            (S::Playing, R::Mute, S::Muted),
+            (S::Playing, R::ToggleMute, S::Muted),
+            (S::Playing, R::Standby, S::Standby),
+            (S::Playing, R::ThermalStandby, S::Standby),
+            (S::Playing, R::Fault, S::Fault),
+            (S::Playing, R::SignalLost, S::NoSignal),
+            (S::NoSignal, R::PowerOff, S::Off),
+            (S::NoSignal, R::Mute, S::Muted),
+            (S::NoSignal, R::ToggleMute, S::Muted),
+            (S::NoSignal, R::Standby, S::Standby),
+            (S::NoSignal, R::ThermalStandby, S::Standby),
+            (S::NoSignal, R::Fault, S::Fault),
+            (S::NoSignal, R::SignalRestored, S::Playing),
+            (S::Standby, R::PowerOff, S::Off),
+            (S::Standby, R::Wake, resume),
+            (S::Standby, R::Fault, S::Fault),
+            (S::Fault, R::PowerOff, S::Off),
+            (S::Fault, R::ClearFault, resume),
+        ];
+        table
+            .iter()
+            .find(|(from, on, _)| *from == state && *on == request)
+            .map(|(_, _, to)| *to)
+    }
+
+    #[test]
+    fn transition_table() {
+        for resume in [PowerState::Muted, PowerState::Playing] {
+            for state in PowerState::ALL {
+                for request in PowerRequest::ALL {
+                    assert_eq!(
+                        next_state(state, resume, request),
+                        expected(state, resume, request),
+                        "{:?} in {:?}, resuming to {:?}",
+                        request,
+                        state,
+                        resume
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn power_up_ends_where_it_resumes() {
+        assert_eq!(
+            steps(PowerState::Off, PowerRequest::PowerOn, PowerState::Muted),
+            [Step::PowerUp { muted: true }]
+        );
+        assert_eq!(
+            steps(PowerState::Off, PowerRequest::PowerOn, PowerState::Playing),
+            [Step::PowerUp { muted: false }]
+        );
+    }
+
+    #[test]
+    fn failed_power_up_is_reported() {
+        let mut recorder = Recorder {
+            fail_power_up: true,
+            ..Default::default()
+        };
+        assert!(run_transition(
+            &mut recorder,
+            PowerState::Off,
+            PowerRequest::PowerOn,
+            PowerState::Booting,
+            PowerState::Playing
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn mute_and_unmute_only_fade() {
+        assert_eq!(
+            steps(PowerState::Playing, PowerRequest::Mute, PowerState::Playing),
+            [Step::Fade { muted: true }]
+        );
+        assert_eq!(
+            steps(PowerState::Muted, PowerRequest::Unmute, PowerState::Muted),
+            [Step::Fade { muted: false }]
+        );
+    }
+
+    #[test]
+    fn lost_signal_fades_out_until_it_returns() {
+        assert_eq!(
+            steps(
+                PowerState::Playing,
+                PowerRequest::SignalLost,
+                PowerState::Playing
+            ),
+            [Step::Fade { muted: true }]
+        );
+        assert_eq!(
+            steps(
+                PowerState::NoSignal,
+                PowerRequest::SignalRestored,
+                PowerState::Playing
+            ),
+            [Step::Fade { muted: false }]
+        );
+        assert!(steps(
+            PowerState::NoSignal,
+            PowerRequest::Mute,
+            PowerState::Playing
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn power_off_fades_before_the_amplifier_and_chain_go_down() {
+        assert_eq!(
+            steps(
+                PowerState::Playing,
+                PowerRequest::PowerOff,
+                PowerState::Playing
+            ),
+            [
+                Step::Fade { muted: true },
+                Step::EnableAmplifier(false),
+                Step::ShutDown,
+            ]
+        );
+    }
+
+    #[test]
+    fn silent_states_need_no_fade() {
+        assert_eq!(
+            steps(PowerState::Muted, PowerRequest::PowerOff, PowerState::Muted),
+            [Step::EnableAmplifier(false), Step::ShutDown]
+        );
+        assert_eq!(
+            steps(
+                PowerState::NoSignal,
+                PowerRequest::Standby,
+                PowerState::Playing
+            ),
+            [
+                Step::EnableAmplifier(false),
+                Step::PowerDac(false),
+                Step::AnnounceStandby(true),
+            ]
+        );
+        assert_eq!(
+            steps(
+                PowerState::Fault,
+                PowerRequest::ClearFault,
+                PowerState::Muted
+            ),
+            [Step::EnableAmplifier(true)]
+        );
+    }
+
+    #[test]
+    fn standby_disables_the_amplifier_before_the_dac() {
+        assert_eq!(
+            steps(
+                PowerState::Playing,
+                PowerRequest::Standby,
+                PowerState::Playing
+            ),
+            [
+                Step::Fade { muted: true },
+                Step::EnableAmplifier(false),
+                Step::PowerDac(false),
+                Step::AnnounceStandby(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn thermal_standby_keeps_the_dac_powered() {
+        assert_eq!(
+            steps(
+                PowerState::Playing,
+                PowerRequest::ThermalStandby,
+                PowerState::Playing
+            ),
+            [
+                Step::Fade { muted: true },
+                Step::EnableAmplifier(false),
+                Step::AnnounceStandby(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn wake_powers_the_dac_before_the_amplifier() {
+        assert_eq!(
+            steps(PowerState::Standby, PowerRequest::Wake, PowerState::Playing),
+            [
+                Step::PowerDac(true),
+                Step::EnableAmplifier(true),
+                Step::Fade { muted: false },
+                Step::AnnounceStandby(false),
+            ]
+        );
+        assert_eq!(
+            steps(PowerState::Standby, PowerRequest::Wake, PowerState::Muted),
+            [
+                Step::PowerDac(true),
+                Step::EnableAmplifier(true),
+                Step::AnnounceStandby(false),
+            ]
+        );
+    }
+
+    #[test]
+    fn fault_cuts_the_outputs_without_a_fade() {
+        assert_eq!(
+            steps(
+                PowerState::Playing,
+                PowerRequest::Fault,
+                PowerState::Playing
+            ),
+            [Step::MuteAmplifier, Step::EnableAmplifier(false)]
+        );
+    }
+
+    #[test]
+    fn clearing_a_fault_fades_back_in() {
+        assert_eq!(
+            steps(
+                PowerState::Fault,
+                PowerRequest::ClearFault,
+                PowerState::Playing
+            ),
+            [Step::EnableAmplifier(true), Step::Fade { muted: false }]
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_state::AmpCommand, drivers::adau1962a::Volume, dsp_config::DspConfig,
+    hardware_context::HardwareContext, lock::LockExt, notifications::Notification,
+    settings::ChannelTrims,
+};
+
+const NVS_NAMESPACE: &str = "presets";
+const NVS_KEY_PRESETS: &str = "presets";
+const MAX_PRESETS: usize = 8;
+const MAX_NAME_LEN: usize = 24;
+/// Version of the export file format, files of other versions are rejected
+const FILE_VERSION: u32 = 1;
+/// Largest preset file accepted for import
+pub const MAX_FILE_LEN: usize = 8192;
+
+/// A named sound setup. Unknown fields are rejected so a file made for other
+/// hardware does not import half of its settings silently.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Preset {
+    pub name: String,
+    pub dsp: DspConfig,
+    pub trims: ChannelTrims,
+}
+
+impl Preset {
+    /// Captures the running DSP configuration and channel trims
+    pub fn capture(hardware_context: &HardwareContext, name: String) -> anyhow::Result<Self> {
+        Ok(Preset {
+            name,
+            dsp: *hardware_context
+                .dsp_config
+                .lock_or_err("Could not lock DSP config")?,
+            trims: *hardware_context
+                .channel_trims
+                .lock_or_err("Could not lock channel trims")?,
+        })
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate_name(&self.name)?;
+        self.dsp
+            .validate()
+            .map_err(|e| anyhow::anyhow!("Preset {}: {}", self.name, e))?;
+        if let Some(trim) = self
+            .trims
+            .iter()
+            .find(|trim| !(Volume::MIN_DB..=0.0).contains(*trim))
+        {
+            return Err(anyhow::anyhow!(
+                "Preset {}: trim {} dB is outside {} to 0 dB",
+                self.name,
+                trim,
+                Volume::MIN_DB
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The JSON file presets are exported to and imported from
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct PresetFile {
+    pub version: u32,
+    pub presets: Vec<Preset>,
+}
+
+impl PresetFile {
+    /// Parses and validates an uploaded file
+    pub fn parse(json: &[u8]) -> anyhow::Result<Self> {
+        let file: PresetFile = serde_json::from_slice(json)
+            .map_err(|e| anyhow::anyhow!("Invalid preset file: {}", e))?;
+        if file.version != FILE_VERSION {
+            return Err(anyhow::anyhow!(
+                "Preset file version {} is not supported, expected {}",
+                file.version,
+                FILE_VERSION
+            ));
+        }
+        for (index, preset) in file.presets.iter().enumerate() {
+            preset.validate()?;
+            if file.presets[..index]
+                .iter()
+                .any(|other| other.name == preset.name)
+            {
+                return Err(anyhow::anyhow!("Preset {} appears twice", preset.name));
+            }
+        }
+        Ok(file)
+    }
+}
+
+fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.trim().is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return Err(anyhow::anyhow!(
+            "Preset names must have 1 to {} characters",
+            MAX_NAME_LEN
+        ));
+    }
+    Ok(())
+}
+
+/// Named presets, persisted in NVS
+pub struct PresetStore {
+    nvs: Mutex<EspDefaultNvs>,
+    presets: Mutex<Vec<Preset>>,
+}
+
+impl PresetStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let nvs = EspDefaultNvs::new(partition, NVS_NAMESPACE, true)?;
+
+        let presets = match nvs.blob_len(NVS_KEY_PRESETS)? {
+            Some(len) => {
+                let mut buffer = vec![0u8; len];
+                match nvs.get_blob(NVS_KEY_PRESETS, &mut buffer)? {
+                    Some(json) => serde_json::from_slice(json).unwrap_or_else(|e| {
+                        log::warn!("Discarding stored presets: {}", e);
+                        Vec::new()
+                    }),
+                    None => Vec::new(),
+                }
+            }
+            None => Vec::new(),
+        };
+        log::info!("Restored {} presets", presets.len());
+
+        Ok(PresetStore {
+            nvs: Mutex::new(nvs),
+            presets: Mutex::new(presets),
+        })
+    }
+
+    pub fn presets(&self) -> anyhow::Result<Vec<Preset>> {
+        Ok(self.presets.lock_or_err("Could not lock presets")?.clone())
+    }
+
+    pub fn get(&self, name: &str) -> anyhow::Result<Option<Preset>> {
+        Ok(self
+            .presets
+            .lock_or_err("Could not lock presets")?
+            .iter()
+            .find(|preset| preset.name == name)
+            .cloned())
+    }
+
+    /// Stores the preset, replacing one of the same name
+    pub fn save(&self, preset: Preset) -> anyhow::Result<()> {
+        preset.validate()?;
+        self.merge(vec![preset])
+    }
+
+    /// Stores validated presets, replacing those of the same names
+    pub fn import(&self, file: PresetFile) -> anyhow::Result<()> {
+        self.merge(file.presets)
+    }
+
+    pub fn export(&self) -> anyhow::Result<PresetFile> {
+        Ok(PresetFile {
+            version: FILE_VERSION,
+            presets: self.presets()?,
+        })
+    }
+
+    pub fn rename(&self, name: &str, new_name: String) -> anyhow::Result<()> {
+        validate_name(&new_name)?;
+        let mut presets = self.presets.lock_or_err("Could not lock presets")?;
+        if name != new_name && presets.iter().any(|preset| preset.name == new_name) {
+            return Err(anyhow::anyhow!("Preset {} already exists", new_name));
+        }
+        let preset = presets
+            .iter_mut()
+            .find(|preset| preset.name == name)
+            .ok_or_else(|| anyhow::anyhow!("Preset {} does not exist", name))?;
+        preset.name = new_name;
+        self.persist(&presets)
+    }
+
+    pub fn delete(&self, name: &str) -> anyhow::Result<()> {
+        let mut presets = self.presets.lock_or_err("Could not lock presets")?;
+        let count = presets.len();
+        presets.retain(|preset| preset.name != name);
+        if presets.len() == count {
+            return Err(anyhow::anyhow!("Preset {} does not exist", name));
+        }
+        self.persist(&presets)
+    }
+
+    pub fn clear(&self) -> anyhow::Result<()> {
+        let mut presets = self.presets.lock_or_err("Could not lock presets")?;
+        presets.clear();
+        self.persist(&presets)
+    }
+
+    fn merge(&self, new_presets: Vec<Preset>) -> anyhow::Result<()> {
+        let mut presets = self.presets.lock_or_err("Could not lock presets")?;
+        let mut merged = presets.clone();
+        for preset in new_presets {
+            merged.retain(|other| other.name != preset.name);
+            merged.push(preset);
+        }
+        if merged.len() > MAX_PRESETS {
+            return Err(anyhow::anyhow!(
+                "At most {} presets can be stored",
+                MAX_PRESETS
+            ));
+        }
+        self.persist(&merged)?;
+        *presets = merged;
+        Ok(())
+    }
+
+    fn persist(&self, presets: &[Preset]) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(presets)?;
+        self.nvs
+            .lock_or_err("Could not lock NVS")?
+            .set_blob(NVS_KEY_PRESETS, &json)?;
+        Ok(())
+    }
+}
+
+/// Switches to a preset, the amplifier executor fades around the switch
+pub fn recall(hardware_context: &HardwareContext, preset: &Preset) -> anyhow::Result<()> {
+    log::info!("Recalling preset {}", preset.name);
+    hardware_context.amp.execute(AmpCommand::RecallPreset {
+        dsp: preset.dsp,
+        trims: preset.trims,
+    })?;
+
+    hardware_context
+        .notifier
+        .publish(Notification::PresetRecalled {
+            name: preset.name.clone(),
+        });
+    Ok(())
+}
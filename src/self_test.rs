@@ -0,0 +1,362 @@
+use std::time::Duration;
+
+use esp_idf_svc::hal::delay::BLOCK;
+use serde::Serialize;
+
+use crate::{
+    board::Chip, drivers::adau1467_registers::CoreStatus, hardware_context::HardwareContext,
+    lock::LockExt, notifications::Notification,
+};
+
+/// First and last address a bus scan probes, the others are reserved by the I2C spec
+const SCAN_FIRST_ADDRESS: u8 = 0x08;
+const SCAN_LAST_ADDRESS: u8 = 0x77;
+/// Time a chip needs after its reset line was released before it answers on the bus
+const RESET_SETTLE_MS: u64 = 50;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceStatus {
+    Passed,
+    Failed,
+    /// The board profile lists the chip as not fitted, it was not tested
+    NotFitted,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ProgramCheck {
+    pub running: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FeatureCheck {
+    pub supported: bool,
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct DeviceReport {
+    pub chip: Chip,
+    pub address: u8,
+    pub status: DeviceStatus,
+    /// Why the chip failed
+    pub reason: Option<String>,
+}
+
+/// Result of the boot-time self-test. Chips that failed are left out of the power
+/// sequence and the monitors, the rest of the amplifier runs without them.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SelfTestReport {
+    pub devices: Vec<DeviceReport>,
+    /// Addresses that answered the bus scan but belong to no chip of the board profile
+    pub unknown_addresses: Vec<u8>,
+    /// DSP core state after the last program download, None until the DSP was set up
+    pub dsp_program: Option<ProgramCheck>,
+    /// Whether the expander firmware has the amplifier fault register, None if the expander
+    /// was not tested. Without it amplifier faults go unnoticed.
+    pub amp_fault_readback: Option<FeatureCheck>,
+}
+
+impl SelfTestReport {
+    /// Whether `chip` answered and was identified. True before the self-test ran.
+    pub fn passed(&self, chip: Chip) -> bool {
+        !self
+            .devices
+            .iter()
+            .any(|device| device.chip == chip && device.status == DeviceStatus::Failed)
+    }
+
+    pub fn amp_faults_supported(&self) -> bool {
+        self.amp_fault_readback
+            .as_ref()
+            .is_some_and(|check| check.supported)
+    }
+
+    /// True if a chip failed or the DSP program does not run
+    pub fn degraded(&self) -> bool {
+        self.devices
+            .iter()
+            .any(|device| device.status == DeviceStatus::Failed)
+            || self
+                .dsp_program
+                .as_ref()
+                .is_some_and(|program| !program.running)
+    }
+
+    fn fail(&mut self, chip: Chip, reason: String) {
+        if let Some(device) = self.devices.iter_mut().find(|device| device.chip == chip) {
+            device.status = DeviceStatus::Failed;
+            device.reason = Some(reason);
+        }
+    }
+
+    fn log(&self) {
+        for device in &self.devices {
+            match device.status {
+                DeviceStatus::Passed => log::info!(
+                    "Self-test: {} at 0x{:02X} passed",
+                    device.chip.name(),
+                    device.address
+                ),
+                DeviceStatus::Failed => log::error!(
+                    "Self-test: {} at 0x{:02X} failed: {}",
+                    device.chip.name(),
+                    device.address,
+                    device.reason.as_deref().unwrap_or("unknown reason")
+                ),
+                DeviceStatus::NotFitted => {
+                    log::info!("Self-test: {} not fitted", device.chip.name())
+                }
+            }
+        }
+        if let Some(FeatureCheck {
+            supported: false,
+            reason,
+        }) = &self.amp_fault_readback
+        {
+            log::warn!(
+                "Self-test: amplifier fault readback unsupported: {}",
+                reason.as_deref().unwrap_or("unknown reason")
+            );
+        }
+        if !self.unknown_addresses.is_empty() {
+            log::warn!(
+                "Self-test: unknown devices at {:02X?}",
+                self.unknown_addresses
+            );
+        }
+    }
+}
+
+/// Tests the chips of the board profile before the first power-up: the expander, the
+/// reset lines it drives, a bus scan and an identification of every fitted chip.
+/// Must run while the chain is off, it pulses the DAC and DSP reset lines.
+pub fn run(hardware_context: &HardwareContext) {
+    log::info!("Running self-test");
+
+    let devices = hardware_context.board.devices;
+    let mut report = SelfTestReport {
+        devices: Chip::ALL
+            .iter()
+            .map(|&chip| {
+                let device = devices.get(chip);
+                DeviceReport {
+                    chip,
+                    address: device.address,
+                    status: if device.fitted {
+                        DeviceStatus::Passed
+                    } else {
+                        DeviceStatus::NotFitted
+                    },
+                    reason: None,
+                }
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    // The DAC and DSP only answer once the expander released their reset lines
+    let expander_ok = devices.expander.fitted && {
+        let result = check_expander(hardware_context);
+        if let Err(reason) = &result {
+            report.fail(Chip::Expander, reason.clone());
+        }
+        result.is_ok()
+    };
+    if expander_ok {
+        report.amp_fault_readback = Some(check_amp_fault_readback(hardware_context));
+        if let Err(e) = release_resets(hardware_context) {
+            report.fail(
+                Chip::Expander,
+                format!("Releasing the reset lines failed: {}", e),
+            );
+        }
+    }
+
+    let found = scan(hardware_context);
+    report.unknown_addresses = found
+        .iter()
+        .copied()
+        .filter(|address| {
+            !Chip::ALL.iter().any(|&chip| {
+                let device = devices.get(chip);
+                device.fitted && device.address == *address
+            })
+        })
+        .collect();
+
+    for chip in [Chip::Pcm1865, Chip::Adau1962a, Chip::Adau1467] {
+        let device = devices.get(chip);
+        if !device.fitted {
+            continue;
+        }
+        let result = if !found.contains(&device.address) {
+            Err(format!("No answer at 0x{:02X}", device.address))
+        } else {
+            identify(hardware_context, chip).and_then(|_| {
+                if expander_ok && chip != Chip::Pcm1865 {
+                    check_reset_line(hardware_context, chip)
+                } else {
+                    Ok(())
+                }
+            })
+        };
+        if let Err(reason) = result {
+            report.fail(chip, reason);
+        }
+    }
+
+    report.log();
+    let degraded = report.degraded();
+    *hardware_context.self_test.lock_value() = report;
+    hardware_context
+        .notifier
+        .publish(Notification::SelfTest { degraded });
+}
+
+/// Checks that the DSP core runs the downloaded program, called after every download
+pub fn check_dsp_program(hardware_context: &HardwareContext) {
+    let result = {
+        let adau1467 = hardware_context.adau1467.lock_value();
+        match (adau1467.core_status(), adau1467.panic_code()) {
+            (Ok(CoreStatus::Running), Ok(None)) => Ok(()),
+            (Ok(CoreStatus::Running), Ok(Some(code))) => {
+                Err(format!("DSP core panicked with code 0x{:04X}", code))
+            }
+            (Ok(CoreStatus::Running), Err(e)) | (Err(e), _) => {
+                Err(format!("DSP status unreadable: {}", e))
+            }
+            (Ok(status), _) => Err(format!("DSP core is {:?} after the download", status)),
+        }
+    };
+    match &result {
+        Ok(()) => log::info!("Self-test: DSP program running"),
+        Err(reason) => log::error!("Self-test: {}", reason),
+    }
+
+    let mut report = hardware_context.self_test.lock_value();
+    let was_degraded = report.degraded();
+    report.dsp_program = Some(ProgramCheck {
+        running: result.is_ok(),
+        reason: result.err(),
+    });
+    let degraded = report.degraded();
+    drop(report);
+    if degraded != was_degraded {
+        hardware_context
+            .notifier
+            .publish(Notification::SelfTest { degraded });
+    }
+}
+
+pub fn report(hardware_context: &HardwareContext) -> SelfTestReport {
+    hardware_context.self_test.lock_value().clone()
+}
+
+/// Addresses that acknowledge a one byte read
+fn scan(hardware_context: &HardwareContext) -> Vec<u8> {
+    let mut i2c = hardware_context.i2c.lock_value();
+    let found: Vec<u8> = (SCAN_FIRST_ADDRESS..=SCAN_LAST_ADDRESS)
+        .filter(|&address| i2c.read(address, &mut [0u8; 1], BLOCK).is_ok())
+        .collect();
+    log::info!("I2C scan found {:02X?}", found);
+    found
+}
+
+fn probe(hardware_context: &HardwareContext, address: u8) -> bool {
+    hardware_context
+        .i2c
+        .lock_value()
+        .read(address, &mut [0u8; 1], BLOCK)
+        .is_ok()
+}
+
+/// The expander has no ID register. It is identified by the mute register reading back,
+/// the amplifiers are muted anyway until the chain is up.
+fn check_expander(hardware_context: &HardwareContext) -> Result<(), String> {
+    let address = hardware_context.board.devices.expander.address;
+    if !probe(hardware_context, address) {
+        return Err(format!("No answer at 0x{:02X}", address));
+    }
+    let tpa3116d2 = hardware_context.tpa3116d2.lock_value();
+    tpa3116d2
+        .mute_speaker_outputs(true)
+        .map_err(|e| format!("Mute register not writable: {}", e))?;
+    match tpa3116d2.speakers_muted() {
+        Ok(true) => Ok(()),
+        Ok(false) => Err("Mute register does not read back".to_string()),
+        Err(e) => Err(format!("Mute register not readable: {}", e)),
+    }
+}
+
+/// The fault register is only trusted if it reads back as a fault mask. With the outputs
+/// disabled the amplifiers report no fault, so any set bit is suspicious too.
+fn check_amp_fault_readback(hardware_context: &HardwareContext) -> FeatureCheck {
+    let result = hardware_context.tpa3116d2.lock_value().fault_status();
+    match result {
+        Ok(0) => FeatureCheck {
+            supported: true,
+            reason: None,
+        },
+        Ok(faults) => FeatureCheck {
+            supported: false,
+            reason: Some(format!("Fault register reads 0x{:02X} at boot", faults)),
+        },
+        Err(e) => FeatureCheck {
+            supported: false,
+            reason: Some(e.to_string()),
+        },
+    }
+}
+
+fn release_resets(hardware_context: &HardwareContext) -> anyhow::Result<()> {
+    hardware_context
+        .adau1962a
+        .lock_or_err("Could not lock ADAU1962a driver")?
+        .set_reset(true)?;
+    hardware_context
+        .adau1467
+        .lock_or_err("Could not lock ADAU1467 driver")?
+        .set_reset(true)?;
+    Ok(())
+}
+
+fn identify(hardware_context: &HardwareContext, chip: Chip) -> Result<(), String> {
+    let result = match chip {
+        Chip::Pcm1865 => hardware_context.pcm1865.lock_value().identify(),
+        Chip::Adau1962a => hardware_context.adau1962a.lock_value().identify(),
+        Chip::Adau1467 => hardware_context.adau1467.lock_value().identify(),
+        Chip::Expander => return check_expander(hardware_context),
+    };
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!(
+            "Device at the address does not behave like a {}",
+            chip.name()
+        )),
+        Err(e) => Err(format!("Identification failed: {}", e)),
+    }
+}
+
+/// A chip held in reset stops answering on the bus and comes back once released
+fn check_reset_line(hardware_context: &HardwareContext, chip: Chip) -> Result<(), String> {
+    let address = hardware_context.board.devices.get(chip).address;
+    let set_reset = |reset: bool| match chip {
+        Chip::Adau1962a => hardware_context.adau1962a.lock_value().set_reset(reset),
+        Chip::Adau1467 => hardware_context.adau1467.lock_value().set_reset(reset),
+        Chip::Pcm1865 | Chip::Expander => Ok(()),
+    };
+
+    set_reset(false).map_err(|e| format!("Reset line not writable: {}", e))?;
+    let answered_in_reset = probe(hardware_context, address);
+    set_reset(true).map_err(|e| format!("Reset line not writable: {}", e))?;
+    std::thread::sleep(Duration::from_millis(RESET_SETTLE_MS));
+    if answered_in_reset {
+        return Err("Still answers while held in reset".to_string());
+    }
+    if !probe(hardware_context, address) {
+        return Err("No answer after the reset was released".to_string());
+    }
+    Ok(())
+}
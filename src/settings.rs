@@ -0,0 +1,316 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::nvs::{EspDefaultNvs, EspDefaultNvsPartition};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_state::AmpCommand,
+    board::Chip,
+    drivers::adau1962a::{Taper, TdmConfig, Volume, DAC_CHANNEL_COUNT},
+    dsp_config::{self, DspConfig},
+    hardware_context::HardwareContext,
+    input_selector::{InputSelection, InputSource},
+    led_manager::LedConfig,
+    lock::LockExt,
+    power::{PowerRequest, PowerState},
+    settings_schema::{self, SCHEMA_VERSION},
+};
+
+const NVS_NAMESPACE: &str = "settings";
+const NVS_KEY_SETTINGS: &str = "settings";
+/// Namespace the input selection was kept in before the settings store
+const LEGACY_INPUT_NAMESPACE: &str = "input";
+/// Volume level after a factory reset
+pub const DEFAULT_LEVEL: u8 = 66;
+const POLL_INTERVAL_MS: u64 = 500;
+/// Settings are written once they did not change for this long, a turn of the knob is one write
+const WRITE_DELAY_MS: u64 = 5000;
+
+/// Attenuation of each DAC channel in dB, channel 1 first
+pub type ChannelTrims = [f32; DAC_CHANNEL_COUNT as usize];
+
+/// Everything the user can set that survives a reboot
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    pub volume_db: f32,
+    pub muted: bool,
+    pub input: InputSelection,
+    pub dsp: DspConfig,
+    pub trims: ChannelTrims,
+    pub led: LedConfig,
+    pub tdm: TdmConfig,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            version: SCHEMA_VERSION,
+            volume_db: Taper::default().to_volume(DEFAULT_LEVEL).db(),
+            muted: false,
+            input: InputSelection::default(),
+            dsp: DspConfig::default(),
+            trims: [0.0; DAC_CHANNEL_COUNT as usize],
+            led: LedConfig::default(),
+            tdm: TdmConfig::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Collects the current settings from the running system. Mute is only known while
+    /// the chain runs, in standby or off the stored value is kept.
+    fn capture(hardware_context: &HardwareContext, stored: &Settings) -> Settings {
+        let state = hardware_context.amp.state();
+        Settings {
+            version: SCHEMA_VERSION,
+            volume_db: state.volume_db,
+            muted: match state.power {
+                PowerState::Muted => true,
+                PowerState::Playing | PowerState::NoSignal => false,
+                _ => stored.muted,
+            },
+            input: state.input,
+            dsp: *hardware_context.dsp_config.lock_value(),
+            trims: *hardware_context.channel_trims.lock_value(),
+            led: *hardware_context.led_config.lock_value(),
+            tdm: *hardware_context.tdm_config.lock_value(),
+        }
+    }
+
+    /// Drops values a config change of this firmware would reject, like a corrupted entry
+    fn sanitize(mut self) -> Self {
+        let defaults = Settings::default();
+        if self.dsp.validate().is_err() {
+            log::warn!("Stored DSP config is invalid, using the default");
+            self.dsp = defaults.dsp;
+        }
+        if self.led.validate().is_err() {
+            log::warn!("Stored LED config is invalid, using the default");
+            self.led = defaults.led;
+        }
+        if self.tdm.validate().is_err() {
+            log::warn!("Stored TDM config is invalid, using the default");
+            self.tdm = defaults.tdm;
+        }
+        self.volume_db = self.volume_db.clamp(Volume::MIN_DB, 0.0);
+        for trim in self.trims.iter_mut() {
+            *trim = trim.clamp(Volume::MIN_DB, 0.0);
+        }
+        self
+    }
+}
+
+/// Versioned settings in NVS and the copy last written
+pub struct SettingsStore {
+    nvs: Mutex<EspDefaultNvs>,
+    stored: Mutex<Settings>,
+}
+
+impl SettingsStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> anyhow::Result<Self> {
+        let mut nvs = EspDefaultNvs::new(partition.clone(), NVS_NAMESPACE, true)?;
+
+        // Unreadable settings must not keep the amplifier from booting
+        let settings = match read(&nvs) {
+            Ok(Some(json)) => parse(&json),
+            Ok(None) => match legacy_settings(partition) {
+                Ok(settings) => {
+                    // Written at once so the old keys are read only this one time
+                    nvs.set_str(NVS_KEY_SETTINGS, &serde_json::to_string(&settings)?)?;
+                    settings
+                }
+                Err(e) => {
+                    log::error!(
+                        "Could not read the legacy settings, using defaults: {:?}",
+                        e
+                    );
+                    Settings::default()
+                }
+            },
+            Err(e) => {
+                log::error!(
+                    "Could not read the stored settings, using defaults: {:?}",
+                    e
+                );
+                Settings::default()
+            }
+        };
+        log::info!("Restored settings: {:?}", settings);
+
+        Ok(SettingsStore {
+            nvs: Mutex::new(nvs),
+            stored: Mutex::new(settings),
+        })
+    }
+
+    /// Settings as last written to flash
+    pub fn stored(&self) -> Settings {
+        *self.stored.lock_value()
+    }
+
+    fn save(&self, settings: &Settings) -> anyhow::Result<()> {
+        let json = serde_json::to_string(settings)?;
+        self.nvs
+            .lock_or_err("Could not lock NVS")?
+            .set_str(NVS_KEY_SETTINGS, &json)?;
+        *self.stored.lock_or_err("Could not lock settings")? = *settings;
+        log::info!("Settings saved");
+        Ok(())
+    }
+}
+
+/// The stored settings JSON, None if nothing was stored yet
+fn read(nvs: &EspDefaultNvs) -> anyhow::Result<Option<String>> {
+    let Some(len) = nvs.str_len(NVS_KEY_SETTINGS)? else {
+        return Ok(None);
+    };
+    let mut buffer = vec![0u8; len];
+    Ok(nvs
+        .get_str(NVS_KEY_SETTINGS, &mut buffer)?
+        .map(str::to_string))
+}
+
+/// Reads the stored settings, anything unreadable falls back to the defaults
+fn parse(json: &str) -> Settings {
+    match settings_schema::parse::<Settings>(json) {
+        Ok(settings) => Settings {
+            version: SCHEMA_VERSION,
+            ..settings.sanitize()
+        },
+        Err(e) => {
+            log::warn!("Discarding stored settings: {}", e);
+            Settings::default()
+        }
+    }
+}
+
+/// Settings of firmware before the settings store, only the input selection was kept
+fn legacy_settings(partition: EspDefaultNvsPartition) -> anyhow::Result<Settings> {
+    let mut settings = Settings::default();
+    let nvs = EspDefaultNvs::new(partition, LEGACY_INPUT_NAMESPACE, true)?;
+    if let Some(source) = nvs.get_u8("source")?.and_then(InputSource::from_value) {
+        settings.input.source = source;
+    }
+    if let Some(auto) = nvs.get_u8("auto")? {
+        settings.input.auto = auto != 0;
+    }
+    Ok(settings)
+}
+
+/// Writes the settings to NVS once they stopped changing, to spare the flash
+pub fn settings_writer(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    log::info!("Settings writer thread started");
+
+    let store = &hardware_context.settings;
+    let mut last = store.stored();
+    let mut changed_at = Instant::now();
+
+    loop {
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+        let current = Settings::capture(&hardware_context, &store.stored());
+        if current != last {
+            last = current;
+            changed_at = Instant::now();
+            continue;
+        }
+        if current != store.stored()
+            && changed_at.elapsed() >= Duration::from_millis(WRITE_DELAY_MS)
+        {
+            store.save(&current)?;
+        }
+    }
+}
+
+/// Erases the IR bindings and presets, applies the default settings right away and
+/// stores them. They are stored last, the settings writer would otherwise save the old
+/// settings again while the defaults are being applied.
+pub fn factory_reset(hardware_context: &HardwareContext) -> anyhow::Result<()> {
+    log::warn!("Factory reset");
+    hardware_context.ir_remote.clear()?;
+    hardware_context.presets.clear()?;
+
+    let defaults = Settings::default();
+    hardware_context
+        .amp
+        .execute(AmpCommand::SetLevel(DEFAULT_LEVEL))?;
+    hardware_context
+        .amp
+        .execute(AmpCommand::Power(PowerRequest::Unmute))?;
+    hardware_context
+        .amp
+        .execute(AmpCommand::SelectInput(defaults.input))?;
+    dsp_config::apply(hardware_context, defaults.dsp)?;
+    apply_trims(hardware_context, defaults.trims)?;
+    apply_tdm_config(hardware_context, defaults.tdm)?;
+    *hardware_context
+        .led_config
+        .lock_or_err("Could not lock LED config")? = defaults.led;
+    hardware_context.settings.save(&defaults)
+}
+
+/// Stores the channel trims and writes them to the DAC. While the chain is off they are
+/// written by the next power-up.
+pub fn apply_trims(hardware_context: &HardwareContext, trims: ChannelTrims) -> anyhow::Result<()> {
+    *hardware_context
+        .channel_trims
+        .lock_or_err("Could not lock channel trims")? = trims;
+
+    if matches!(
+        hardware_context.power.state(),
+        PowerState::Off | PowerState::Booting
+    ) || !hardware_context.has(Chip::Adau1962a)
+    {
+        return Ok(());
+    }
+    let mut adau1962a = hardware_context
+        .adau1962a
+        .lock_or_err("Could not lock ADAU1962a driver")?;
+    for (channel, db) in (1..=DAC_CHANNEL_COUNT).zip(trims) {
+        adau1962a.set_channel_volume(channel, Volume::from_db(db))?;
+    }
+    Ok(())
+}
+
+/// Switches the DAC to the TDM setup and stores it. Both ends have to agree, on a mismatch
+/// with the DSP output ports the DAC keeps its old setup and nothing is stored. While the
+/// chain is off it is only stored, the next power-up writes it.
+pub fn apply_tdm_config(
+    hardware_context: &HardwareContext,
+    config: TdmConfig,
+) -> anyhow::Result<()> {
+    config.validate()?;
+
+    if !matches!(
+        hardware_context.power.state(),
+        PowerState::Off | PowerState::Booting
+    ) && hardware_context.has(Chip::Adau1962a)
+    {
+        let mut adau1962a = hardware_context
+            .adau1962a
+            .lock_or_err("Could not lock ADAU1962a driver")?;
+        adau1962a.set_master_mute(true)?;
+        let result = if hardware_context.has(Chip::Adau1467) {
+            adau1962a.set_tdm_config(
+                config,
+                &*hardware_context
+                    .adau1467
+                    .lock_or_err("Could not lock ADAU1467 driver")?,
+            )
+        } else {
+            adau1962a.write_tdm_config(config)
+        };
+        adau1962a.set_master_mute(false)?;
+        result?;
+    }
+
+    *hardware_context
+        .tdm_config
+        .lock_or_err("Could not lock TDM config")? = config;
+    Ok(())
+}
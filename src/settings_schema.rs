@@ -0,0 +1,113 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+/// Bump when a field changes meaning or is renamed and add the conversion to `MIGRATIONS`.
+/// New fields do not need a bump, they take their default when missing.
+pub const SCHEMA_VERSION: u32 = 1;
+/// Conversions of the stored JSON object, entry n turns schema n into schema n + 1
+const MIGRATIONS: [fn(&mut Map<String, Value>); SCHEMA_VERSION as usize] = [migrate_0_to_1];
+
+/// Parses stored settings of any known schema into the current one
+pub fn parse<T: DeserializeOwned>(json: &str) -> anyhow::Result<T> {
+    let value = serde_json::from_str(json)?;
+    Ok(serde_json::from_value(migrate(value)?)?)
+}
+
+/// Converts settings written with an older schema, one version step at a time. Settings
+/// of newer firmware are rejected, their fields may mean something else by now.
+pub fn migrate(mut value: Value) -> anyhow::Result<Value> {
+    let settings = value
+        .as_object_mut()
+        .ok_or_else(|| anyhow::Error::msg("Settings are not a JSON object"))?;
+    let version = match settings.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| anyhow::anyhow!("Invalid schema version {}", version))?,
+    };
+    if version > SCHEMA_VERSION as u64 {
+        return Err(anyhow::anyhow!(
+            "Settings were written by newer firmware (schema {}, this one knows {})",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+
+    for (from, step) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        log::info!("Migrating settings from schema {} to {}", from, from + 1);
+        step(settings);
+        settings.insert("version".to_string(), (from + 1).into());
+    }
+    Ok(value)
+}
+
+/// Schema 0 is the store before the version field was added, its fields are those of
+/// schema 1
+fn migrate_0_to_1(_settings: &mut Map<String, Value>) {}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    /// Stands in for the firmware settings, which need the hardware types
+    #[derive(Deserialize, Debug, Default, PartialEq)]
+    #[serde(default)]
+    struct Stored {
+        version: u32,
+        volume_db: f32,
+        muted: bool,
+    }
+
+    #[test]
+    fn schema_0_is_migrated() {
+        let stored: Stored = parse(r#"{"volume_db":-20.0,"muted":true}"#).unwrap();
+        assert_eq!(
+            stored,
+            Stored {
+                version: SCHEMA_VERSION,
+                volume_db: -20.0,
+                muted: true,
+            }
+        );
+    }
+
+    #[test]
+    fn current_schema_is_kept() {
+        let stored: Stored = parse(r#"{"version":1,"volume_db":-6.5,"muted":false}"#).unwrap();
+        assert_eq!(stored.version, 1);
+        assert_eq!(stored.volume_db, -6.5);
+    }
+
+    #[test]
+    fn missing_fields_take_their_default() {
+        let stored: Stored = parse(r#"{"version":1}"#).unwrap();
+        assert_eq!(
+            stored,
+            Stored {
+                version: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn newer_schema_is_rejected() {
+        let error = parse::<Stored>(r#"{"version":2,"volume_db":-20.0}"#).unwrap_err();
+        assert!(error.to_string().contains("newer firmware"));
+    }
+
+    #[test]
+    fn corrupt_blobs_are_rejected() {
+        for json in [
+            r#"{"version":1,"volume_db":-20"#,
+            "\u{0}\u{0}\u{0}",
+            r#"[1,2,3]"#,
+            r#"{"version":"one"}"#,
+            r#"{"version":1,"muted":"yes"}"#,
+        ] {
+            assert!(parse::<Stored>(json).is_err(), "{:?} was accepted", json);
+        }
+    }
+}
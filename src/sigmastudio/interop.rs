@@ -7,12 +7,22 @@ use std::{
 
 use esp_idf_svc::hal::i2c::I2cDriver;
 
+use crate::lock::LockExt;
+
 extern "C" {
     fn load_sigmastudio_program_adau1467();
 }
 static mut I2C: OnceLock<Arc<Mutex<I2cDriver>>> = OnceLock::new();
+/// Serialises downloads, the C code reaches the bus through the static above
+static DOWNLOAD_LOCK: Mutex<()> = Mutex::new(());
+/// First failed write of the running download, the writes after it are skipped
+static DOWNLOAD_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Downloads the SigmaStudio default program. The C code cannot handle a failed write, so the
+/// first error is kept, the remaining writes are skipped and the error is returned at the end.
+pub fn load_sigmastudio_dsp_program(local_i2c: &Arc<Mutex<I2cDriver>>) -> anyhow::Result<()> {
+    let _download = DOWNLOAD_LOCK.lock_or_err("Could not lock DSP download")?;
 
-pub fn load_sigmastudio_dsp_program(local_i2c: &Arc<Mutex<I2cDriver>>) {
     // This is safe as we call load_sigmastudio_program_adau1467 which then uses the i2c before we return here.
     let static_i2c = unsafe {
         std::mem::transmute::<&Arc<Mutex<I2cDriver<'_>>>, &Arc<Mutex<I2cDriver<'static>>>>(
@@ -21,9 +31,11 @@ pub fn load_sigmastudio_dsp_program(local_i2c: &Arc<Mutex<I2cDriver>>) {
     };
 
     unsafe {
-        I2C.set(static_i2c.clone())
-            .unwrap_or_else(|_| panic!("I2C already initialized!"));
+        if I2C.set(static_i2c.clone()).is_err() {
+            anyhow::bail!("I2C already initialized");
+        }
     }
+    *DOWNLOAD_ERROR.lock_or_err("Could not lock DSP download error")? = None;
 
     log::info!("Executing C Code");
 
@@ -34,6 +46,14 @@ pub fn load_sigmastudio_dsp_program(local_i2c: &Arc<Mutex<I2cDriver>>) {
     // Make sure the I2C in here gets invalidated so it can't be used after the function returns
     // when returning, the I2C may get invalid.
     unsafe { I2C.take() };
+
+    match DOWNLOAD_ERROR
+        .lock_or_err("Could not lock DSP download error")?
+        .take()
+    {
+        Some(error) => Err(anyhow::anyhow!("DSP program download failed: {}", error)),
+        None => Ok(()),
+    }
 }
 
 #[no_mangle]
@@ -43,18 +63,31 @@ pub extern "C" fn sleep_ms(microseconds: u64) {
 
 #[no_mangle]
 pub extern "C" fn i2c_write(i2c_address: u8, buffer: *const u8, length: i32) -> i32 {
-    let mut i2c = unsafe { I2C.get().expect("I2C not initialized").lock().unwrap() };
+    let mut error = DOWNLOAD_ERROR.lock_value();
+    if error.is_some() {
+        return -1;
+    }
+    let Some(mut i2c) = (unsafe { I2C.get() }).map(|i2c| i2c.lock_value()) else {
+        *error = Some("I2C not initialized".to_string());
+        return -1;
+    };
 
     // Safety: You need to ensure that `buffer` is valid and points to `length` bytes.
     if !buffer.is_null() && length > 0 {
         unsafe {
             let slice = std::slice::from_raw_parts(buffer, length as usize);
             log::debug!("Sending to address {:02X}{:02X}", slice[0], slice[1]);
-            i2c.write(i2c_address, slice, esp_idf_svc::hal::delay::BLOCK)
-                .unwrap();
+            if let Err(e) = i2c.write(i2c_address, slice, esp_idf_svc::hal::delay::BLOCK) {
+                *error = Some(format!(
+                    "Write to register 0x{:02X}{:02X} failed: {}",
+                    slice[0], slice[1], e
+                ));
+                return -1;
+            }
         }
     } else {
-        eprintln!("Invalid buffer or length!");
+        *error = Some(format!("Invalid buffer of length {}", length));
+        return -1;
     }
 
     // Sending the data was successful, so we can return the number of bytes sent safely
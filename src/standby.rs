@@ -0,0 +1,178 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_state::AmpCommand,
+    hardware_context::HardwareContext,
+    lock::LockExt,
+    power::{PowerRequest, PowerState},
+    thermal_monitor::ThermalState,
+};
+
+const POLL_INTERVAL_MS: u64 = 250;
+/// All eight analog inputs are watched by the PCM1865 signal detector
+const SIGNAL_DETECT_MASK: u8 = 0xFF;
+const SIGNAL_DETECT_INPUTS: u8 = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StandbyConfig {
+    pub enabled: bool,
+    /// Seconds without signal before the amp goes into standby
+    pub silence_timeout_s: u32,
+    /// Signal has to be present for this long before the amp wakes up
+    pub wake_hold_ms: u32,
+    /// Detection threshold while running, a signal dropping below it counts as silence
+    pub loss_threshold: u8,
+    /// Detection threshold while in standby, higher than `loss_threshold` so noise does not wake the amp
+    pub wake_threshold: u8,
+}
+
+impl Default for StandbyConfig {
+    fn default() -> Self {
+        StandbyConfig {
+            enabled: true,
+            silence_timeout_s: 15 * 60,
+            wake_hold_ms: 500,
+            loss_threshold: 0x08,
+            wake_threshold: 0x10,
+        }
+    }
+}
+
+impl StandbyConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.silence_timeout_s == 0 {
+            return Err(anyhow::Error::msg("Silence timeout must be at least 1 s"));
+        }
+        if self.wake_threshold < self.loss_threshold {
+            return Err(anyhow::Error::msg(
+                "Wake threshold must not be below the loss threshold",
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn standby_monitor(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    log::info!("Standby monitor thread started");
+
+    let mut config = current_config(&hardware_context);
+    {
+        let pcm1865 = hardware_context
+            .pcm1865
+            .lock_or_err("Could not lock PCM1865 driver")?;
+        pcm1865.set_signal_detect_mask(SIGNAL_DETECT_MASK)?;
+    }
+    set_threshold(&hardware_context, config.loss_threshold)?;
+
+    let mut last_signal = Instant::now();
+    let mut signal_since: Option<Instant> = None;
+    // Only a standby entered by this monitor is left on a signal, not one from the button or API
+    let mut auto_standby = false;
+
+    loop {
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+        let new_config = current_config(&hardware_context);
+        let state = hardware_context.power.state();
+        if new_config != config {
+            config = new_config;
+            set_threshold(
+                &hardware_context,
+                if auto_standby {
+                    config.wake_threshold
+                } else {
+                    config.loss_threshold
+                },
+            )?;
+        }
+
+        let signal = hardware_context
+            .pcm1865
+            .lock_or_err("Could not lock PCM1865 driver")?
+            .signal_detect_status()?
+            != 0;
+        let now = Instant::now();
+
+        if auto_standby && state != PowerState::Standby {
+            // Woken up or switched off by someone else
+            auto_standby = false;
+            set_threshold(&hardware_context, config.loss_threshold)?;
+        }
+
+        if matches!(
+            state,
+            PowerState::Playing | PowerState::Muted | PowerState::NoSignal
+        ) {
+            if signal || !config.enabled {
+                last_signal = now;
+            } else if now.duration_since(last_signal)
+                >= Duration::from_secs(config.silence_timeout_s as u64)
+            {
+                log::info!("No signal, entering standby");
+                if hardware_context
+                    .amp
+                    .execute(AmpCommand::Power(PowerRequest::Standby))?
+                {
+                    auto_standby = true;
+                    set_threshold(&hardware_context, config.wake_threshold)?;
+                    signal_since = None;
+                }
+            }
+            continue;
+        }
+
+        // A standby from the button, API or thermal monitor is left by whoever requested it
+        if !auto_standby || thermal_state(&hardware_context) == ThermalState::Critical {
+            last_signal = now;
+            continue;
+        }
+        if !config.enabled {
+            wake(&hardware_context, &config)?;
+            auto_standby = false;
+            last_signal = now;
+            continue;
+        }
+        if !signal {
+            signal_since = None;
+            continue;
+        }
+        let since = *signal_since.get_or_insert(now);
+        if now.duration_since(since) >= Duration::from_millis(config.wake_hold_ms as u64) {
+            log::info!("Signal detected, waking up");
+            wake(&hardware_context, &config)?;
+            auto_standby = false;
+            last_signal = now;
+        }
+    }
+}
+
+fn wake(hardware_context: &HardwareContext, config: &StandbyConfig) -> anyhow::Result<()> {
+    set_threshold(hardware_context, config.loss_threshold)?;
+    hardware_context
+        .amp
+        .execute(AmpCommand::Power(PowerRequest::Wake))?;
+    Ok(())
+}
+
+fn current_config(hardware_context: &HardwareContext) -> StandbyConfig {
+    *hardware_context.standby_config.lock_value()
+}
+
+fn thermal_state(hardware_context: &HardwareContext) -> ThermalState {
+    hardware_context.thermal_status.lock_value().state
+}
+
+fn set_threshold(hardware_context: &HardwareContext, threshold: u8) -> anyhow::Result<()> {
+    let pcm1865 = hardware_context
+        .pcm1865
+        .lock_or_err("Could not lock PCM1865 driver")?;
+    for input in 0..SIGNAL_DETECT_INPUTS {
+        pcm1865.set_signal_detect_threshold(input, threshold)?;
+    }
+    Ok(())
+}
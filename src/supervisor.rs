@@ -0,0 +1,113 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{hardware_context::HardwareContext, lock::LockExt, notifications::Notification};
+
+/// Stack of tasks that only poll and talk I2C
+pub const DEFAULT_STACK_SIZE: usize = 4096;
+const FIRST_RESTART_DELAY_MS: u64 = 1000;
+const MAX_RESTART_DELAY_MS: u64 = 60_000;
+/// A task that ran this long before failing restarts with the short delay again
+const HEALTHY_AFTER_S: u64 = 60;
+
+pub type Task = fn(Arc<HardwareContext<'static>>) -> anyhow::Result<()>;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    pub running: bool,
+    /// Restarts since boot
+    pub restarts: u32,
+    pub last_error: Option<String>,
+}
+
+/// Runs `task` on its own thread and runs it again when it returns an error.
+/// The restart delay doubles with every failure in a row. A task that returns Ok is done.
+/// Only `Err` returns are restarted: the firmware is built with `panic_abort`, so a panic
+/// resets the whole board. Tasks report failures as errors instead of panicking.
+pub fn spawn<F>(
+    hardware_context: &Arc<HardwareContext<'static>>,
+    name: &'static str,
+    stack_size: usize,
+    mut task: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(Arc<HardwareContext<'static>>) -> anyhow::Result<()> + Send + 'static,
+{
+    hardware_context
+        .tasks
+        .lock_or_err("Could not lock task list")?
+        .push(TaskStatus {
+            name,
+            running: true,
+            restarts: 0,
+            last_error: None,
+        });
+
+    let hardware_context = hardware_context.clone();
+    std::thread::Builder::new()
+        .name(name.to_string())
+        .stack_size(stack_size)
+        .spawn(move || {
+            let mut delay_ms = FIRST_RESTART_DELAY_MS;
+            loop {
+                let started = Instant::now();
+                let error = match task(hardware_context.clone()) {
+                    Ok(()) => {
+                        log::info!("Task {} finished", name);
+                        update(&hardware_context, name, |status| status.running = false);
+                        return;
+                    }
+                    Err(e) => format!("{:?}", e),
+                };
+
+                if started.elapsed() >= Duration::from_secs(HEALTHY_AFTER_S) {
+                    delay_ms = FIRST_RESTART_DELAY_MS;
+                }
+                log::error!(
+                    "Task {} stopped: {}, restarting in {} ms",
+                    name,
+                    error,
+                    delay_ms
+                );
+                let restarts = update(&hardware_context, name, |status| {
+                    status.restarts += 1;
+                    status.last_error = Some(error);
+                    status.restarts
+                })
+                .unwrap_or_default();
+                hardware_context
+                    .notifier
+                    .publish(Notification::TaskRestarted {
+                        task: name,
+                        restarts,
+                    });
+
+                std::thread::sleep(Duration::from_millis(delay_ms));
+                delay_ms = (delay_ms * 2).min(MAX_RESTART_DELAY_MS);
+            }
+        })?;
+    Ok(())
+}
+
+pub fn tasks(hardware_context: &HardwareContext) -> Vec<TaskStatus> {
+    hardware_context.tasks.lock_value().clone()
+}
+
+/// Runs `f` on the status of task `name`, None if no such task was spawned
+fn update<T>(
+    hardware_context: &HardwareContext,
+    name: &'static str,
+    f: impl FnOnce(&mut TaskStatus) -> T,
+) -> Option<T> {
+    hardware_context
+        .tasks
+        .lock_value()
+        .iter_mut()
+        .find(|status| status.name == name)
+        .map(f)
+}
@@ -0,0 +1,186 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    amp_state::AmpCommand,
+    drivers::adau1962a::Volume,
+    hardware_context::HardwareContext,
+    lock::LockExt,
+    notifications::Notification,
+    power::{PowerRequest, PowerState},
+};
+
+const POLL_INTERVAL_MS: u64 = 2000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ThermalConfig {
+    /// Above this the master volume is limited to `warning_max_db`
+    pub warning_c: i16,
+    /// Above this the amp goes into protective standby if `critical_standby` is set
+    pub critical_c: i16,
+    /// The temperature has to fall this far below a threshold before its state is left
+    pub hysteresis_c: i16,
+    /// Loudest master volume while the warning or critical threshold is exceeded
+    pub warning_max_db: f32,
+    pub critical_standby: bool,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        ThermalConfig {
+            warning_c: 70,
+            critical_c: 85,
+            hysteresis_c: 5,
+            warning_max_db: -20.0,
+            critical_standby: true,
+        }
+    }
+}
+
+impl ThermalConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0..=125).contains(&self.warning_c) || !(0..=125).contains(&self.critical_c) {
+            return Err(anyhow::Error::msg(
+                "Thresholds must be between 0 °C and 125 °C",
+            ));
+        }
+        if self.critical_c <= self.warning_c {
+            return Err(anyhow::Error::msg(
+                "Critical threshold must be above the warning threshold",
+            ));
+        }
+        if !(1..=20).contains(&self.hysteresis_c) {
+            return Err(anyhow::Error::msg(
+                "Hysteresis must be between 1 °C and 20 °C",
+            ));
+        }
+        if !(Volume::MIN_DB..=0.0).contains(&self.warning_max_db) {
+            return Err(anyhow::Error::msg(
+                "Maximum volume must be between -95.625 dB and 0 dB",
+            ));
+        }
+        Ok(())
+    }
+
+    /// State for `temperature_c` without looking at the previous state
+    fn classify(&self, temperature_c: i16) -> ThermalState {
+        if temperature_c >= self.critical_c {
+            ThermalState::Critical
+        } else if temperature_c >= self.warning_c {
+            ThermalState::Warning
+        } else {
+            ThermalState::Normal
+        }
+    }
+
+    /// Rising temperatures switch at the thresholds, falling ones only `hysteresis_c` below them
+    fn next_state(&self, state: ThermalState, temperature_c: i16) -> ThermalState {
+        let rising = self.classify(temperature_c);
+        if rising >= state {
+            return rising;
+        }
+        self.classify(temperature_c + self.hysteresis_c).min(state)
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ThermalState {
+    #[default]
+    Normal,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct ThermalStatus {
+    pub state: ThermalState,
+    /// Last ADAU1962A die temperature, None until the first conversion was read
+    pub temperature_c: Option<i16>,
+    /// Highest temperature seen since boot
+    pub max_temperature_c: Option<i16>,
+}
+
+/// Reads the ADAU1962A temperature sensor and limits the volume or goes to standby when it gets hot
+pub fn thermal_monitor(hardware_context: Arc<HardwareContext<'static>>) -> anyhow::Result<()> {
+    log::info!("Thermal monitor thread started");
+
+    // Set while this monitor holds the amp in standby
+    let mut thermal_standby = false;
+
+    loop {
+        std::thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+        // The DAC is held in reset, the power-up enables the sensor again
+        if matches!(
+            hardware_context.power.state(),
+            PowerState::Off | PowerState::Booting
+        ) {
+            continue;
+        }
+
+        let temperature_c = hardware_context
+            .adau1962a
+            .lock_or_err("Could not lock ADAU1962a driver")?
+            .temperature()?;
+        let config = *hardware_context
+            .thermal_config
+            .lock_or_err("Could not lock thermal config")?;
+
+        let (previous, state) = {
+            let mut status = hardware_context
+                .thermal_status
+                .lock_or_err("Could not lock thermal status")?;
+            let previous = status.state;
+            status.state = config.next_state(previous, temperature_c);
+            status.temperature_c = Some(temperature_c);
+            status.max_temperature_c = Some(
+                status
+                    .max_temperature_c
+                    .map_or(temperature_c, |max| max.max(temperature_c)),
+            );
+            (previous, status.state)
+        };
+        if state == previous {
+            continue;
+        }
+
+        match state {
+            ThermalState::Normal => {
+                log::info!("Temperature back to normal at {} °C", temperature_c)
+            }
+            ThermalState::Warning => log::warn!(
+                "Temperature warning at {} °C, limiting volume to {} dB",
+                temperature_c,
+                config.warning_max_db
+            ),
+            ThermalState::Critical => log::error!("Critical temperature of {} °C", temperature_c),
+        }
+
+        hardware_context
+            .amp
+            .execute(AmpCommand::SetVolumeLimit(match state {
+                ThermalState::Normal => None,
+                _ => Some(Volume::from_db(config.warning_max_db)),
+            }))?;
+
+        if state == ThermalState::Critical {
+            if config.critical_standby {
+                thermal_standby = hardware_context
+                    .amp
+                    .execute(AmpCommand::Power(PowerRequest::ThermalStandby))?;
+            }
+        } else if thermal_standby {
+            hardware_context
+                .amp
+                .execute(AmpCommand::Power(PowerRequest::Wake))?;
+            thermal_standby = false;
+        }
+
+        hardware_context.notifier.publish(Notification::Thermal {
+            state,
+            temperature_c,
+        });
+    }
+}
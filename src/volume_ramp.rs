@@ -0,0 +1,228 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::hal::delay::TICK_RATE_HZ;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    board::Chip, drivers::adau1962a::Volume, hardware_context::HardwareContext, lock::LockExt,
+    notifications::Notification,
+};
+
+/// Shortest sleep of a task, one FreeRTOS tick (CONFIG_FREERTOS_HZ in sdkconfig.defaults)
+const KERNEL_TICK_MS: u32 = 1000 / TICK_RATE_HZ;
+/// Time between two attenuator steps of a ramp, a sleep never ends before the next kernel tick
+const TICK_MS: u32 = if KERNEL_TICK_MS > 2 {
+    KERNEL_TICK_MS
+} else {
+    2
+};
+/// Attenuation used as silence at the end of a fade-out
+const SILENCE: Volume = Volume::MUTE;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RampConfig {
+    /// Duration of a ramp between two volume settings (encoder, API)
+    pub volume_ms: u32,
+    /// Duration of a fade to or from silence (mute, standby, program reload)
+    pub fade_ms: u32,
+}
+
+impl Default for RampConfig {
+    fn default() -> Self {
+        RampConfig {
+            volume_ms: 50,
+            fade_ms: 300,
+        }
+    }
+}
+
+impl RampConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.volume_ms > 2000 || self.fade_ms > 5000 {
+            return Err(anyhow::Error::msg(
+                "Ramps are limited to 2 s for volume changes and 5 s for fades",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Moves the ADAU1962A master attenuator in small steps instead of jumping.
+/// The DAC has no volume ramp to configure: DAC_CTRL0 (0x06) holds clock, format and
+/// master mute, DAC_CTRL1 (0x07) the serial clocks and DAC_CTRL2 (0x08) de-emphasis,
+/// oversampling, auto-mute and polarity. The steps are written from here instead.
+pub struct VolumeRamp {
+    config: Mutex<RampConfig>,
+    /// Held for the duration of a ramp so two ramps never fight over the attenuator
+    running: Mutex<()>,
+    /// Loudest attenuation a ramp may end at, set by the thermal protection
+    limit: Mutex<Option<Volume>>,
+}
+
+impl VolumeRamp {
+    pub fn new() -> Self {
+        VolumeRamp {
+            config: Mutex::new(RampConfig::default()),
+            running: Mutex::new(()),
+            limit: Mutex::new(None),
+        }
+    }
+
+    pub fn config(&self) -> RampConfig {
+        *self.config.lock_value()
+    }
+
+    pub fn set_config(&self, config: RampConfig) {
+        *self.config.lock_value() = config;
+    }
+
+    pub fn limit(&self) -> Option<Volume> {
+        *self.limit.lock_value()
+    }
+
+    pub fn set_limit(&self, limit: Option<Volume>) {
+        *self.limit.lock_value() = limit;
+    }
+
+    /// `volume`, or the limit if that is quieter
+    fn limited(&self, volume: Volume) -> Volume {
+        match self.limit() {
+            Some(limit) if limit.db() < volume.db() => limit,
+            _ => volume,
+        }
+    }
+}
+
+/// Ramps the master volume to an attenuator setting
+pub fn ramp_to_volume(hardware_context: &HardwareContext, target: Volume) -> anyhow::Result<()> {
+    let duration_ms = hardware_context.volume_ramp.config().volume_ms;
+    ramp(hardware_context, target, duration_ms)?;
+    hardware_context
+        .notifier
+        .publish(Notification::VolumeChanged {
+            db: hardware_context.volume_ramp.limited(target).db(),
+        });
+    Ok(())
+}
+
+/// Fades the master volume to silence and returns the volume it started from
+pub fn fade_out(hardware_context: &HardwareContext) -> anyhow::Result<Volume> {
+    if !hardware_context.has(Chip::Adau1962a) {
+        return Ok(hardware_context.amp.state().volume());
+    }
+    let current = hardware_context
+        .adau1962a
+        .lock_or_err("Could not lock ADAU1962a driver")?
+        .master_attenuation()?;
+    let duration_ms = hardware_context.volume_ramp.config().fade_ms;
+    ramp(hardware_context, SILENCE, duration_ms)?;
+    Ok(current)
+}
+
+/// Fades the master volume from silence up to `volume`
+pub fn fade_in(hardware_context: &HardwareContext, volume: Volume) -> anyhow::Result<()> {
+    if !hardware_context.has(Chip::Adau1962a) {
+        return Ok(());
+    }
+    let duration_ms = hardware_context.volume_ramp.config().fade_ms;
+    hardware_context
+        .adau1962a
+        .lock_or_err("Could not lock ADAU1962a driver")?
+        .step_master_attenuation(SILENCE)?;
+    ramp(hardware_context, volume, duration_ms)
+}
+
+/// Mutes or unmutes the amplifier with a fade, the volume setting is kept.
+/// Without a DAC the amplifier is switched without the fade. The power state machine only
+/// calls it when the outputs become audible or silent.
+pub fn set_muted(hardware_context: &HardwareContext, muted: bool) -> anyhow::Result<()> {
+    if !hardware_context.has(Chip::Expander) {
+        return Ok(());
+    }
+    let has_dac = hardware_context.has(Chip::Adau1962a);
+
+    if muted {
+        let volume = fade_out(hardware_context)?;
+        hardware_context
+            .tpa3116d2
+            .lock_or_err("Could not lock TPA3116d2 driver")?
+            .mute_speaker_outputs(true)?;
+        // Silent behind the muted amp, restore the setting for the next unmute
+        if has_dac {
+            hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?
+                .step_master_attenuation(volume)?;
+        }
+    } else if !has_dac {
+        hardware_context
+            .tpa3116d2
+            .lock_or_err("Could not lock TPA3116d2 driver")?
+            .mute_speaker_outputs(false)?;
+    } else {
+        let volume = {
+            let mut adau1962a = hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?;
+            let volume = adau1962a.master_attenuation()?;
+            adau1962a.step_master_attenuation(SILENCE)?;
+            volume
+        };
+        hardware_context
+            .tpa3116d2
+            .lock_or_err("Could not lock TPA3116d2 driver")?
+            .mute_speaker_outputs(false)?;
+        fade_in(hardware_context, volume)?;
+    }
+    Ok(())
+}
+
+/// Steps linearly in dB from the current attenuation to `target` over `duration_ms`.
+/// The step is taken from the elapsed time, so a late wake-up does not stretch the ramp.
+/// A target louder than the volume limit ends at the limit instead.
+fn ramp(
+    hardware_context: &HardwareContext,
+    target: Volume,
+    duration_ms: u32,
+) -> anyhow::Result<()> {
+    if !hardware_context.has(Chip::Adau1962a) {
+        return Ok(());
+    }
+    let _running = hardware_context
+        .volume_ramp
+        .running
+        .lock_or_err("Could not lock volume ramp")?;
+
+    let start = hardware_context
+        .adau1962a
+        .lock_or_err("Could not lock ADAU1962a driver")?
+        .master_attenuation()?
+        .register() as i32;
+    let end = hardware_context.volume_ramp.limited(target).register() as i32;
+    let duration = Duration::from_millis(duration_ms as u64);
+
+    let began = Instant::now();
+    let mut last = start;
+    loop {
+        let elapsed = began.elapsed().min(duration);
+        let value = if duration_ms == 0 {
+            end
+        } else {
+            start + (end - start) * elapsed.as_millis() as i32 / duration_ms as i32
+        };
+        if value != last {
+            hardware_context
+                .adau1962a
+                .lock_or_err("Could not lock ADAU1962a driver")?
+                .step_master_attenuation(Volume::from_register(value as u8))?;
+            last = value;
+        }
+        if elapsed >= duration {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(TICK_MS as u64));
+    }
+}
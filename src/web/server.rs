@@ -1,18 +1,34 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use anyhow::Ok;
-use embedded_svc::http::{Headers, Method};
+use embedded_svc::http::{Headers, Method, Query};
 use esp_idf_svc::{
     http::server::EspHttpServer,
     io::{Read, Write},
 };
 use log::*;
 
-use crate::{api::commands::Command, hardware_context::HardwareContext};
+use crate::{
+    api::commands::Command,
+    board::{self, BoardProfile},
+    hardware_context::HardwareContext,
+    lock::LockExt,
+    presets::{self, PresetFile},
+};
 
-const MAX_LEN: usize = 128;
+/// Largest command body. A full button config is about 340 bytes, the TDM config with its
+/// channel assignment about 300.
+const MAX_LEN: usize = 1024;
 const STACK_SIZE: usize = 10240;
 
+/// Runs the HTTP server until the firmware stops, the server handles requests on its own task
+pub fn server_task(hardware_context: Arc<HardwareContext<'static>>) -> Result<(), anyhow::Error> {
+    let _server = start_server(hardware_context)?;
+    loop {
+        std::thread::sleep(Duration::from_secs(60));
+    }
+}
+
 pub fn start_server(
     hardware_context: Arc<HardwareContext<'static>>,
 ) -> Result<EspHttpServer<'static>, anyhow::Error> {
@@ -76,5 +92,126 @@ pub fn mount_routes(
         }
     })?;
 
+    let hardware_context_clone = Arc::clone(&hardware_context);
+
+    // Short poll for notifications. GET /api/events?since=<cursor> answers at once with the
+    // events since the cursor of the previous answer, so the single HTTP worker never blocks
+    server.fn_handler("/api/events", Method::Get, move |req| {
+        let since = query_param(req.uri(), "since").and_then(|since| since.parse().ok());
+        let batch = hardware_context_clone.notifier.events_since(since);
+        let body = serde_json::to_vec(&batch)?;
+        let mut response = req.into_ok_response()?;
+        response.write_all(&body)?;
+        Ok(())
+    })?;
+
+    let hardware_context_clone = Arc::clone(&hardware_context);
+
+    // All presets as a JSON file, the format the import below takes
+    server.fn_handler("/api/presets.json", Method::Get, move |req| {
+        let body = serde_json::to_vec_pretty(&hardware_context_clone.presets.export()?)?;
+        let mut response = req.into_response(
+            200,
+            None,
+            &[
+                ("Content-Type", "application/json"),
+                (
+                    "Content-Disposition",
+                    "attachment; filename=\"presets.json\"",
+                ),
+            ],
+        )?;
+        response.write_all(&body)?;
+        Ok(())
+    })?;
+
+    let hardware_context_clone = Arc::clone(&hardware_context);
+
+    // Imports a preset file, presets with the names of stored ones replace them
+    server.fn_handler("/api/presets.json", Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > presets::MAX_FILE_LEN {
+            req.into_status_response(413)?
+                .write_all("Preset file too big".as_bytes())?;
+            return Ok(());
+        }
+
+        let mut buf = vec![0; len];
+        req.read_exact(&mut buf)?;
+
+        let result =
+            PresetFile::parse(&buf).and_then(|file| hardware_context_clone.presets.import(file));
+        match result {
+            std::result::Result::Ok(()) => {
+                req.into_ok_response()?;
+            }
+            Err(e) => {
+                req.into_status_response(400)?
+                    .write_all(e.to_string().as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
+    let hardware_context_clone = Arc::clone(&hardware_context);
+
+    // Profile the firmware booted with, a starting point for the profile of another board
+    server.fn_handler("/api/board.json", Method::Get, move |req| {
+        let body = serde_json::to_vec_pretty(&hardware_context_clone.board)?;
+        let mut response = req.into_response(
+            200,
+            None,
+            &[
+                ("Content-Type", "application/json"),
+                ("Content-Disposition", "attachment; filename=\"board.json\""),
+            ],
+        )?;
+        response.write_all(&body)?;
+        Ok(())
+    })?;
+
+    let hardware_context_clone = Arc::clone(&hardware_context);
+
+    // Stores a board profile, it takes effect with the next boot
+    server.fn_handler("/api/board.json", Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > board::MAX_PROFILE_LEN {
+            req.into_status_response(413)?
+                .write_all("Board profile too big".as_bytes())?;
+            return Ok(());
+        }
+
+        let mut buf = vec![0; len];
+        req.read_exact(&mut buf)?;
+
+        let result = BoardProfile::parse(&buf).and_then(|profile| {
+            hardware_context_clone
+                .board_store
+                .lock_or_err("Could not lock board store")?
+                .save(&profile)
+        });
+        match result {
+            std::result::Result::Ok(()) => {
+                req.into_ok_response()?;
+            }
+            Err(e) => {
+                req.into_status_response(400)?
+                    .write_all(e.to_string().as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+
     Ok(())
 }
+
+/// Value of `name` in the query string of `uri`
+fn query_param<'a>(uri: &'a str, name: &str) -> Option<&'a str> {
+    let (_, query) = uri.split_once('?')?;
+    query
+        .split('&')
+        .find_map(|pair| match pair.split_once('=') {
+            Some((key, value)) if key == name => Some(value),
+            _ => None,
+        })
+}
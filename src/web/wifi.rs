@@ -1,18 +1,70 @@
+use std::{sync::Arc, time::Duration};
+
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    hal::modem::Modem,
+    hal::{modem::Modem, peripheral::Peripheral},
     nvs::EspDefaultNvsPartition,
     wifi::{BlockingWifi, EspWifi, PmfConfiguration, ScanMethod, ScanSortMethod},
 };
+use serde::Serialize;
+
+use crate::{hardware_context::HardwareContext, notifications::Notification};
 
 const SSID: &str = "Wollersberger";
 const PASSWORD: &str = env!("WIFI_PASSWORD");
+const CONNECTION_CHECK_INTERVAL_S: u64 = 10;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WifiState {
+    Connecting,
+    Connected,
+    Failed,
+}
+
+/// Connects and watches the connection. Returns an error when the connection is lost
+/// so the supervisor sets the WiFi up again.
+pub fn wifi_task(
+    modem: &mut Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    hardware_context: Arc<HardwareContext<'static>>,
+) -> anyhow::Result<()> {
+    hardware_context.notifier.publish(Notification::Wifi {
+        state: WifiState::Connecting,
+    });
+    let wifi = setup_wifi(modem, sys_loop, nvs);
+    let connected = wifi
+        .as_ref()
+        .is_ok_and(|wifi| wifi.is_connected().unwrap_or(false));
+    hardware_context.notifier.publish(Notification::Wifi {
+        state: if connected {
+            WifiState::Connected
+        } else {
+            WifiState::Failed
+        },
+    });
+    let wifi = wifi?;
+    if !connected {
+        return Err(anyhow::Error::msg("Could not connect to WiFi"));
+    }
+
+    loop {
+        std::thread::sleep(Duration::from_secs(CONNECTION_CHECK_INTERVAL_S));
+        if !wifi.is_connected()? {
+            hardware_context.notifier.publish(Notification::Wifi {
+                state: WifiState::Failed,
+            });
+            return Err(anyhow::Error::msg("WiFi connection lost"));
+        }
+    }
+}
 
-pub fn setup_wifi(
-    modem: Modem,
+pub fn setup_wifi<'d>(
+    modem: impl Peripheral<P = Modem> + 'd,
     sys_loop: EspSystemEventLoop,
     nvs: EspDefaultNvsPartition,
-) -> anyhow::Result<BlockingWifi<EspWifi<'static>>> {
+) -> anyhow::Result<BlockingWifi<EspWifi<'d>>> {
     // Create and configure WiFi (using blocking APIs)
     let mut wifi = BlockingWifi::wrap(
         EspWifi::new(modem, sys_loop.clone(), Some(nvs))?,